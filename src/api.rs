@@ -0,0 +1,143 @@
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+use crate::playback::{PlaybackCommand, PlaybackMessage};
+use crate::presets::{PresetMessage, PresetRequest};
+
+/// Serves the local API on a Unix socket at `path`. The API is deliberately about as simple as it gets, so that a stage
+/// manager's laptop, a cron job, or someone with `socat` over ssh can drive it: one command per line, one answer per
+/// command. Answers start with `ok` or `error`. The commands are:
+///
+///   - `recall <preset>`: hold the windmill on a preset, overriding the console.
+///   - `release`: hand the windmill back to the console.
+///   - `capture <preset>`: save what the windmill is doing right now as a preset.
+///   - `list`: list every preset, numbered by their preset channel value.
+///   - `play`, `pause` and `stop`: control timeline playback, if there's a timeline.
+///
+/// Preset requests are passed along to the windmill task over `presets`, and playback commands to the playback task
+/// over `playback`, which do the actual work.
+pub async fn serve(
+  path: PathBuf,
+  presets: UnboundedSender<PresetMessage>,
+  playback: Option<UnboundedSender<PlaybackMessage>>
+) -> Result<(), &'static str> {
+  // A socket left behind by a previous run would stop us from binding, so that goes. Anything else at our path is
+  // somebody's mistake, and not ours to delete.
+  if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+    if !metadata.file_type().is_socket() {
+      return Err("there's something other than a socket at the api socket path");
+    }
+
+    std::fs::remove_file(&path).map_err(|_| "failed to remove the old api socket")?;
+  }
+
+  let listener = UnixListener::bind(&path).map_err(|_| "failed to bind the api socket")?;
+
+  loop {
+    let (stream, _) = listener.accept().await.map_err(|_| "failed to accept an api connection")?;
+    let (presets, playback) = (presets.clone(), playback.clone());
+
+    // Each connection gets its own task, so one slow client can't hold up anyone else. A connection going wrong only
+    // takes out that connection.
+    tokio::spawn(async move {
+      if let Err(why) = connection(stream, presets, playback).await {
+        eprintln!("api: {why}");
+      }
+    });
+  }
+}
+
+/// Something asked of the API, and who it's for.
+#[derive(Clone, Debug, PartialEq)]
+enum Request {
+  /// For the windmill task's presets.
+  Preset(PresetRequest),
+
+  /// For the playback task.
+  Playback(PlaybackCommand)
+}
+
+/// Answers commands on a single connection until it closes.
+async fn connection(
+  stream: UnixStream,
+  presets: UnboundedSender<PresetMessage>,
+  playback: Option<UnboundedSender<PlaybackMessage>>
+) -> Result<(), &'static str> {
+  let (reader, mut writer) = stream.into_split();
+  let mut lines = BufReader::new(reader).lines();
+
+  while let Some(line) = lines.next_line().await.map_err(|_| "failed to read from an api connection")? {
+    let answer = match (parse(&line), &playback) {
+      (Ok(Request::Preset(request)), _) => ask(&presets, request).await,
+      (Ok(Request::Playback(command)), Some(playback)) => ask(playback, command).await,
+      (Ok(Request::Playback(_)), None) => Err("there's no timeline to play"),
+      (Err(why), _) => Err(why)
+    };
+
+    let answer = match answer {
+      Ok(message) => format!("ok {message}\n"),
+      Err(why) => format!("error {why}\n")
+    };
+
+    writer.write_all(answer.as_bytes()).await.map_err(|_| "failed to write to an api connection")?;
+  }
+
+  Ok(())
+}
+
+/// Parses a line of the API into a request.
+fn parse(line: &str) -> Result<Request, &'static str> {
+  let mut words = line.split_whitespace();
+
+  match (words.next(), words.next(), words.next()) {
+    (Some("recall"), Some(name), None) => Ok(Request::Preset(PresetRequest::Recall(name.to_string()))),
+    (Some("release"), None, None) => Ok(Request::Preset(PresetRequest::Release)),
+    (Some("capture"), Some(name), None) => Ok(Request::Preset(PresetRequest::Capture(name.to_string()))),
+    (Some("list"), None, None) => Ok(Request::Preset(PresetRequest::List)),
+    (Some("play"), None, None) => Ok(Request::Playback(PlaybackCommand::Play)),
+    (Some("pause"), None, None) => Ok(Request::Playback(PlaybackCommand::Pause)),
+    (Some("stop"), None, None) => Ok(Request::Playback(PlaybackCommand::Stop)),
+    _ => Err("expected recall <preset>, release, capture <preset>, list, play, pause, or stop")
+  }
+}
+
+/// Hands a request to another task and waits for its answer.
+pub async fn ask<T>(
+  sender: &UnboundedSender<(T, oneshot::Sender<Result<String, &'static str>>)>,
+  request: T
+) -> Result<String, &'static str> {
+  let (reply, answer) = oneshot::channel();
+  sender.send((request, reply)).map_err(|_| "nobody is listening")?;
+  answer.await.map_err(|_| "nobody answered")?
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_commands() {
+    assert_eq!(Ok(Request::Preset(PresetRequest::Recall(String::from("storm")))), parse("recall storm"));
+    assert_eq!(Ok(Request::Preset(PresetRequest::Release)), parse("  release "));
+    assert_eq!(Ok(Request::Preset(PresetRequest::Capture(String::from("calm")))), parse("capture calm"));
+    assert_eq!(Ok(Request::Preset(PresetRequest::List)), parse("list"));
+    assert_eq!(Ok(Request::Playback(PlaybackCommand::Pause)), parse("pause"));
+    assert!(parse("recall").is_err());
+    assert!(parse("recall storm now").is_err());
+    assert!(parse("spin faster").is_err());
+  }
+
+  #[tokio::test]
+  async fn leaves_anything_but_an_old_socket_alone() {
+    let path = std::env::temp_dir().join(format!("windmill-api-{}", std::process::id()));
+    std::fs::write(&path, "important").unwrap();
+
+    assert!(serve(path.clone(), tokio::sync::mpsc::unbounded_channel().0, None).await.is_err());
+    assert_eq!("important", std::fs::read_to_string(&path).unwrap());
+
+    std::fs::remove_file(&path).ok();
+  }
+}
@@ -1,4 +1,17 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::conditioning::{ConditioningProfile, Smoothing};
+use crate::curve::ResponseCurve;
+use crate::fixture::{MotionChannels, Personality};
+use crate::motion::{CooldownCurve, CooldownModel, KickStart, MotionProfile, StopMode};
+use crate::output::DutyLimits;
+use crate::physics::RotorModel;
+use crate::pwm;
+use crate::ramp::RampProfile;
+use crate::scenario::Target;
+use crate::timecode::TimecodeSource;
 
 /// Arguments that can be passed to the windmill to control it! These settings are most convenient when needing to live
 /// alongside other hardware or dealing with unique console limitations.
@@ -9,11 +22,531 @@ pub struct Args {
   #[arg(short, long, default_value_t = 5)]
   pub universe: u32,
 
-  /// The channel to pick up speed signals from.
+  /// How the windmill's channels are laid out: a separate speed and direction channel, or a single centre-zero
+  /// (`bipolar`) fader on the speed channel.
+  #[arg(short, long, value_enum, default_value_t = PersonalityKind::SpeedDirection)]
+  pub personality: PersonalityKind,
+
+  /// The channel to pick up speed signals from. With the `bipolar` personality, this is the centre-zero fader.
   #[arg(short, long, default_value_t = 10)]
   pub speed_channel: u32,
 
-  /// The channel to pick up direction signals from.
+  /// The channel to pick up direction signals from. Unused by the `bipolar` personality.
   #[arg(short, long, default_value_t = 11)]
-  pub direction_channel: u32
+  pub direction_channel: u32,
+
+  /// With the `bipolar` personality, how many values either side of the centre of the fader count as stopped.
+  #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u8).range(0..=Personality::MAX_CENTRE_BAND as i64))]
+  pub centre_band: u8,
+
+  /// The pwmchip index driving the motor speed signal. Use `windmill pwm-chips` to find the right one for your board.
+  #[arg(long, default_value_t = 0)]
+  pub pwm_chip: u8,
+
+  /// The channel on the pwmchip driving the motor speed signal.
+  #[arg(long, default_value_t = 0)]
+  pub pwm_channel: u8,
+
+  /// The frequency, in Hz, to drive the motor speed signal at. It's up to you to pick one your hardware supports.
+  #[arg(long, default_value_t = 20000, value_parser = clap::value_parser!(u16).range(1..))]
+  pub pwm_frequency: u16,
+
+  /// How to get the motor speed signal behaving as active-high. `auto` falls back to inverting duty cycles in software
+  /// when the chip's polarity can't be changed.
+  #[arg(long, value_enum, default_value_t = pwm::PolarityMode::Auto)]
+  pub pwm_polarity: pwm::PolarityMode,
+
+  /// Drive the motor speed signal with software PWM on this WiringPi pin instead of a hardware PWM chip. This is far
+  /// less precise (100Hz, with scheduler jitter) and is only meant for small props on boards without hardware PWM.
+  #[arg(long)]
+  pub soft_pwm_pin: Option<i32>,
+
+  /// How many seconds it should take the windmill to speed up from stopped to full speed. The default is the windmill's
+  /// original ramp: one step of speed every 60ms.
+  #[arg(long, default_value = "15.3", value_parser = duration)]
+  pub acceleration_time: Duration,
+
+  /// How many seconds it should take the windmill to slow down from full speed to stopped.
+  #[arg(long, default_value = "15.3", value_parser = duration)]
+  pub deceleration_time: Duration,
+
+  /// Eases speed changes in and out as an S-curve, taking this many seconds for the rate of change to build up to its
+  /// maximum. Leave it off for plain linear ramps.
+  #[arg(long, value_parser = duration)]
+  pub s_curve_time: Option<Duration>,
+
+  /// How DMX speed values map onto motor duty cycles: `linear`, `square`, `exponential`, `s-curve`, or a lookup table
+  /// like `table:0=0,64=10,192=60,255=100` that is interpolated between points.
+  #[arg(long, global = true, default_value_t = ResponseCurve::Linear)]
+  pub response_curve: ResponseCurve,
+
+  /// The lowest duty cycle (in percent) that keeps the motor turning. The first DMX value above the dead band maps here.
+  #[arg(long, global = true, default_value_t = 0)]
+  pub minimum_duty: u8,
+
+  /// The highest duty cycle (in percent) the motor will ever be driven at, regardless of input. Full speed maps here.
+  #[arg(long, global = true, default_value_t = 100)]
+  pub maximum_duty: u8,
+
+  /// DMX speed values at or below this are treated as off.
+  #[arg(long, global = true, default_value_t = 0)]
+  pub dead_band: u8,
+
+  /// Kick-starts the motor at this duty cycle (in percent) when it starts from a standstill, to break it free of static
+  /// friction before the normal ramp takes over. Requires `--kick-start-time`.
+  #[arg(long, requires = "kick_start_time")]
+  pub kick_start_duty: Option<u8>,
+
+  /// How many seconds a kick-start lasts.
+  #[arg(long, requires = "kick_start_duty", value_parser = duration)]
+  pub kick_start_time: Option<Duration>,
+
+  /// How the windmill comes to a stop when it's turned off or has to change direction. Faults and shutdown always hard
+  /// brake regardless.
+  #[arg(long, value_enum, default_value_t = StopMode::HardBrake)]
+  pub stop_mode: StopMode,
+
+  /// The shortest cool down (in seconds) after braking, used when the windmill was barely turning.
+  #[arg(long, default_value = "2", value_parser = duration)]
+  pub brake_cooldown_minimum: Duration,
+
+  /// The cool down (in seconds) after braking from full speed, which is how long the windmill always used to wait.
+  /// Anything in between is scaled by `--cooldown-curve`.
+  #[arg(long, default_value = "6", value_parser = duration)]
+  pub brake_cooldown_full_speed: Duration,
+
+  /// The shortest cool down (in seconds) after coasting, used when the windmill was barely turning. The brake goes back
+  /// on when a coast's cool down ends, so without a sensor to say the rotor has stopped, this needs to be long enough
+  /// for it to spin down on its own.
+  #[arg(long, default_value = "5", value_parser = duration)]
+  pub coast_cooldown_minimum: Duration,
+
+  /// The cool down (in seconds) after coasting from full speed, which should be at least a full spin-down.
+  #[arg(long, default_value = "20", value_parser = duration)]
+  pub coast_cooldown_full_speed: Duration,
+
+  /// How cool downs scale between their minimum and full speed durations.
+  #[arg(long, value_enum, default_value_t = CooldownCurve::Linear)]
+  pub cooldown_curve: CooldownCurve,
+
+  /// A channel that lets the console pick the stop mode per cue: 0-63 uses `--stop-mode`, 64-127 ramps down, 128-191
+  /// coasts, and 192-255 hard brakes.
+  #[arg(long)]
+  pub stop_mode_channel: Option<u32>,
+
+  /// A channel that sets how long the next speed change takes, in tenths of a second (so 80 is eight seconds). Zero
+  /// leaves it to the configured ramp, and no fade is ever faster than `--acceleration-time`/`--deceleration-time`.
+  #[arg(long)]
+  pub fade_time_channel: Option<u32>,
+
+  /// A channel for maintenance commands, each of which has to be held for two seconds: 10-19 arms, 20-29 disarms, 30-39
+  /// resets faults, 40-49 runs a self-test, and 50-59/60-69/70-79 change the default stop mode to ramp down, coast or
+  /// hard brake.
+  #[arg(long)]
+  pub control_channel: Option<u32>,
+
+  /// A channel that picks a motion effect to lay over the speed: 10-59 gusts, 60-109 oscillates, 110-159 pulses, and
+  /// 160-209 breathes. Anything else is no effect.
+  #[arg(long)]
+  pub effect_channel: Option<u32>,
+
+  /// A channel that sets how quickly the effect cycles.
+  #[arg(long, requires = "effect_channel")]
+  pub effect_rate_channel: Option<u32>,
+
+  /// A channel that sets how far the effect pushes the speed away from where the console has it.
+  #[arg(long, requires = "effect_channel")]
+  pub effect_depth_channel: Option<u32>,
+
+  /// A channel that recalls an on-device preset: 1 is the first preset in the preset file, 2 the second, and so on.
+  /// Zero hands control back to the console.
+  #[arg(long)]
+  pub preset_channel: Option<u32>,
+
+  /// Where on-device presets are kept. Without one, presets can still be captured over the API but are forgotten on
+  /// restart.
+  #[arg(long)]
+  pub preset_file: Option<PathBuf>,
+
+  /// Serve the local API (for recalling and capturing presets) on a Unix socket at this path.
+  #[arg(long)]
+  pub api_socket: Option<PathBuf>,
+
+  /// Listen for OSC preset requests (`/windmill/recall <preset>`, `/windmill/release` and `/windmill/capture <preset>`)
+  /// on this address, e.g. `0.0.0.0:9000`, for show control software that would rather ask for presets by name.
+  #[arg(long)]
+  pub osc_address: Option<SocketAddr>,
+
+  /// Run a scripted timeline with no console attached. Live DMX still takes over whenever it asks for anything. Send
+  /// `SIGUSR1` to pause and resume, `SIGUSR2` to stop, or use `play`/`pause`/`stop` on the local API.
+  #[arg(long)]
+  pub timeline: Option<PathBuf>,
+
+  /// Load the timeline without starting it, so it waits for `play`.
+  #[arg(long, requires = "timeline")]
+  pub timeline_paused: bool,
+
+  /// Chase timecode through the timeline instead of running it on its own clock, so each keyframe fires at its time
+  /// in the show. This is separate from DMX, so it works even when every DMX channel is spoken for.
+  #[arg(long, value_enum, requires = "timeline", conflicts_with = "timeline_paused")]
+  pub timecode: Option<TimecodeKind>,
+
+  /// Where to listen for ArtTimeCode. Consoles broadcast it to Art-Net's port, which OLA's Art-Net plugin already has,
+  /// so the windmill shares the port with it rather than taking it over.
+  #[arg(long, default_value = "0.0.0.0:6454")]
+  pub timecode_address: SocketAddr,
+
+  /// The raw MIDI device to read MIDI timecode from.
+  #[arg(long, default_value = "/dev/snd/midiC1D0")]
+  pub timecode_device: PathBuf,
+
+  /// How long, in seconds, to keep running on our own clock when timecode goes missing before holding where we are.
+  #[arg(long, default_value_t = 2.0)]
+  pub timecode_freewheel: f64,
+
+  /// Record every DMX frame that arrives (just the channels the windmill listens to) to this file, so a show can be
+  /// replayed later with `--replay`.
+  #[arg(long, conflicts_with = "replay_source")]
+  pub record: Option<PathBuf>,
+
+  /// Replay a recording made with `--record` instead of listening to OLA. The windmill stops once the recording ends.
+  #[arg(long, group = "replay_source")]
+  pub replay: Option<PathBuf>,
+
+  /// Replay the Art-Net and sACN traffic for our universe from a Wireshark or tcpdump capture (pcap or pcapng) instead
+  /// of listening to OLA, printing what each packet was decoded into. The windmill stops once the capture ends.
+  #[arg(long, group = "replay_source")]
+  pub pcap: Option<PathBuf>,
+
+  /// Listen for Art-Net and sACN directly on this address (say `0.0.0.0:6454` for Art-Net or `0.0.0.0:5568` for sACN)
+  /// instead of going through OLA. Handy on a bench or a laptop where there's no `olad` running.
+  #[arg(long, conflicts_with = "replay_source")]
+  pub listen: Option<SocketAddr>,
+
+  /// How many times faster than real time to replay a recording or capture.
+  #[arg(long, default_value_t = 1.0, requires = "replay_source")]
+  pub replay_speed: f64,
+
+  /// Print every command sent to the hardware (brake, direction, safety relay and duty cycle), in order, along with
+  /// when it was sent. With `--simulate`, that's a dry run: everything the windmill would have done, without doing it.
+  #[arg(long)]
+  pub audit_outputs: bool,
+
+  /// Run without any hardware: GPIO writes are skipped and the PWM duty cycle is only logged. Mostly useful with
+  /// `--replay`, to re-run a rehearsal somewhere other than on the windmill.
+  #[arg(long, conflicts_with_all = ["soft_pwm_pin", "sensor_pin"])]
+  pub simulate: bool,
+
+  /// Use the simulated rotor as a rotation sensor, the same as `--sensor-pin` would on the real thing.
+  #[arg(long, requires = "simulate")]
+  pub simulated_sensor: bool,
+
+  /// Print every change the windmill makes to the simulated hardware, along with when it happened and how fast the
+  /// rotor was turning, for a complete timeline of what the windmill did.
+  #[arg(long, requires = "simulate")]
+  pub simulate_trace: bool,
+
+  /// The simulated rotor's moment of inertia, in kg·m².
+  #[arg(long, default_value_t = RotorModel::default().inertia, requires = "simulate")]
+  pub rotor_inertia: f64,
+
+  /// The simulated rotor's speed-dependent friction (bearings and air), in N·m per rad/s.
+  #[arg(long, default_value_t = RotorModel::default().viscous_friction, requires = "simulate")]
+  pub rotor_viscous_friction: f64,
+
+  /// The simulated rotor's constant friction, in N·m, which the motor also has to overcome to get going.
+  #[arg(long, default_value_t = RotorModel::default().static_friction, requires = "simulate")]
+  pub rotor_static_friction: f64,
+
+  /// The torque the simulated motor makes at a standstill on full duty, in N·m.
+  #[arg(long, default_value_t = RotorModel::default().stall_torque, requires = "simulate")]
+  pub motor_stall_torque: f64,
+
+  /// How fast the simulated motor would turn on full duty with nothing attached, in RPM.
+  #[arg(long, default_value_t = RotorModel::default().top_speed, requires = "simulate")]
+  pub motor_top_speed: f64,
+
+  /// How hard the simulated braking resistor holds the rotor back, in N·m per rad/s.
+  #[arg(long, default_value_t = RotorModel::default().brake_damping, requires = "simulate")]
+  pub brake_damping: f64,
+
+  /// Start disarmed, so the windmill won't move until it's armed from the control channel.
+  #[arg(long, requires = "control_channel")]
+  pub require_arm: bool,
+
+  /// A WiringPi pin with a rotation sensor attached, which pulses high as the rotor turns. When present, it's used to
+  /// skip kick-starts if the rotor is already moving, and to hold cool downs until the rotor has actually stopped.
+  #[arg(long)]
+  pub sensor_pin: Option<i32>,
+
+  /// How many times the rotation sensor pulses per revolution of the rotor.
+  #[arg(long, default_value_t = 1)]
+  pub sensor_pulses_per_revolution: u8,
+
+  /// How long (in seconds) the rotation sensor has to go without a pulse before the rotor counts as stopped. This wants
+  /// to be two or three times as long as the rotor takes to go round at its slowest.
+  #[arg(long, default_value = "6", value_parser = duration)]
+  pub sensor_stopped_after: Duration,
+
+  /// How to smooth the incoming speed: `none`, a `low-pass` filter, or the `median` of the last few frames.
+  #[arg(long, value_enum, default_value_t = SmoothingKind::None)]
+  pub smoothing: SmoothingKind,
+
+  /// The time constant (in seconds) of the `low-pass` smoothing filter. Bigger is smoother, and laggier.
+  #[arg(long, default_value = "0.1", value_parser = duration)]
+  pub smoothing_time: Duration,
+
+  /// How many frames the `median` smoothing filter looks at.
+  #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u16).range(1..))]
+  pub smoothing_frames: u16,
+
+  /// How many values either side of the direction channel's midpoint leave the direction as it was. Stops a direction
+  /// channel hovering around 128 from flipping back and forth.
+  #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=127))]
+  pub direction_hysteresis: u8,
+
+  /// How many frames in a row a change of direction has to be asked for before the windmill believes it.
+  #[arg(long, default_value_t = 0)]
+  pub direction_hold_frames: u32,
+
+  /// How many seconds a change of direction has to be asked for before the windmill believes it. When given alongside
+  /// `--direction-hold-frames`, whichever is satisfied first wins.
+  #[arg(long, default_value = "0", value_parser = duration)]
+  pub direction_hold_time: Duration,
+
+  /// Something to do other than running the windmill.
+  #[command(subcommand)]
+  pub command: Option<Command>
+}
+
+impl Args {
+  /// The `Personality` described by the channel arguments.
+  pub fn personality(&self) -> Personality {
+    let motion = match self.personality {
+      PersonalityKind::SpeedDirection => MotionChannels::SpeedDirection {
+        speed_channel: self.speed_channel,
+        direction_channel: self.direction_channel
+      },
+
+      PersonalityKind::Bipolar => MotionChannels::Bipolar {
+        channel: self.speed_channel,
+        centre_band: self.centre_band
+      }
+    };
+
+    Personality {
+      motion,
+      stop_mode_channel: self.stop_mode_channel,
+      fade_time_channel: self.fade_time_channel,
+      control_channel: self.control_channel,
+      effect_channel: self.effect_channel,
+      effect_rate_channel: self.effect_rate_channel,
+      effect_depth_channel: self.effect_depth_channel,
+      preset_channel: self.preset_channel
+    }
+  }
+
+  /// The `MotionProfile` described by the ramp, kick-start and cool down arguments. A ramp time of zero is to say "as
+  /// fast as the hardware can go".
+  pub fn motion_profile(&self) -> Result<MotionProfile, &'static str> {
+    let kick_start = match (self.kick_start_duty, self.kick_start_time) {
+      (Some(duty), _) if duty > 100 => return Err("the kick-start duty cycle can't be more than 100%"),
+      (Some(duty), Some(duration)) => Some(KickStart { duty, duration }),
+      _ => None
+    };
+
+    Ok(MotionProfile {
+      ramp: RampProfile {
+        acceleration: self.acceleration_time,
+        deceleration: self.deceleration_time,
+        s_curve: self.s_curve_time
+      },
+      kick_start,
+      brake_cooldown: CooldownModel {
+        minimum: self.brake_cooldown_minimum,
+        full_speed: self.brake_cooldown_full_speed,
+        curve: self.cooldown_curve
+      },
+      coast_cooldown: CooldownModel {
+        minimum: self.coast_cooldown_minimum,
+        full_speed: self.coast_cooldown_full_speed,
+        curve: self.cooldown_curve
+      }
+    })
+  }
+
+  /// The `ConditioningProfile` described by the smoothing and direction arguments.
+  pub fn conditioning_profile(&self) -> ConditioningProfile {
+    let smoothing = match self.smoothing {
+      SmoothingKind::None => Smoothing::None,
+      SmoothingKind::LowPass => Smoothing::LowPass(self.smoothing_time),
+      SmoothingKind::Median => Smoothing::Median(self.smoothing_frames as usize)
+    };
+
+    ConditioningProfile {
+      smoothing,
+      direction_hysteresis: self.direction_hysteresis,
+      direction_hold_frames: self.direction_hold_frames,
+      direction_hold_time: self.direction_hold_time
+    }
+  }
+
+  /// The simulated windmill described by the rotor, motor and brake arguments.
+  pub fn rotor_model(&self) -> Result<RotorModel, &'static str> {
+    if self.rotor_inertia <= 0.0 || self.motor_top_speed <= 0.0 {
+      return Err("the simulated rotor needs some inertia and the motor some top speed");
+    }
+
+    Ok(RotorModel {
+      inertia: self.rotor_inertia,
+      viscous_friction: self.rotor_viscous_friction,
+      static_friction: self.rotor_static_friction,
+      stall_torque: self.motor_stall_torque,
+      top_speed: self.motor_top_speed,
+      brake_damping: self.brake_damping
+    })
+  }
+
+  /// Where to chase timecode from, if anywhere.
+  pub fn timecode_source(&self) -> Option<TimecodeSource> {
+    match self.timecode? {
+      TimecodeKind::ArtNet => Some(TimecodeSource::ArtNet(self.timecode_address)),
+      TimecodeKind::Mtc => Some(TimecodeSource::Mtc(self.timecode_device.clone()))
+    }
+  }
+
+  /// The `DutyLimits` described by the duty cycle related arguments.
+  pub fn duty_limits(&self) -> Result<DutyLimits, &'static str> {
+    let maximum = match self.scenario() {
+      Some((_, Target::Hardware { maximum_duty })) => self.maximum_duty.min(maximum_duty),
+      _ => self.maximum_duty
+    };
+
+    DutyLimits::new(self.minimum_duty, maximum, self.dead_band)
+  }
+
+  /// The scenario to run and where to run it, if that's what we've been asked to do.
+  pub fn scenario(&self) -> Option<(&Path, Target)> {
+    match &self.command {
+      Some(Command::Scenario { file, hardware: false, .. }) => Some((file, Target::Simulated)),
+      Some(Command::Scenario { file, hardware: true, hardware_maximum_duty }) =>
+        Some((file, Target::Hardware { maximum_duty: *hardware_maximum_duty })),
+      _ => None
+    }
+  }
+
+  /// Whether to run against the simulation rather than the hardware, which a scenario does unless told otherwise.
+  pub fn simulating(&self) -> bool {
+    self.simulate || matches!(self.scenario(), Some((_, Target::Simulated)))
+  }
+}
+
+/// The channel layouts that can be picked from the command line. See `MotionChannels`.
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum PersonalityKind {
+  /// One channel for speed, another for direction.
+  SpeedDirection,
+
+  /// One centre-zero channel for both.
+  Bipolar
+}
+
+/// The smoothing filters that can be picked from the command line. See `Smoothing`.
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum SmoothingKind {
+  /// No smoothing.
+  None,
+
+  /// An exponential low-pass filter.
+  LowPass,
+
+  /// A running median.
+  Median
+}
+
+/// Where timecode comes from.
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum TimecodeKind {
+  /// ArtTimeCode packets over the network.
+  ArtNet,
+
+  /// MIDI timecode from a MIDI interface.
+  Mtc
+}
+
+/// One-off utilities that run instead of the windmill itself. With no command, the windmill just does its thing.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+  /// Lists the PWM chips the kernel knows about, along with their channels and backing devices.
+  PwmChips,
+
+  /// Prints the duty cycle the configured `--response-curve` produces for each DMX value, without touching any
+  /// hardware.
+  Curve {
+    /// Only print every this many DMX values.
+    #[arg(long, default_value_t = 5)]
+    step: u8
+  },
+
+  /// Runs a scenario file against the windmill: sends its DMX, checks the windmill does what it expects, and reports
+  /// what passed and what didn't. Runs against the simulation unless `--hardware` is given.
+  Scenario {
+    /// The scenario to run.
+    file: PathBuf,
+
+    /// Run on the real windmill rather than the simulation, in a supervised low-speed mode: it waits for the go-ahead
+    /// before anything moves, and stops at the first failure.
+    #[arg(long)]
+    hardware: bool,
+
+    /// On the real windmill, the highest duty cycle (in percent) the motor will be driven at, whatever the scenario asks.
+    #[arg(long, default_value_t = 25, requires = "hardware")]
+    hardware_maximum_duty: u8
+  }
+}
+
+/// Parses a number of seconds from the command line as a `Duration`, for clap.
+fn duration(seconds: &str) -> Result<Duration, &'static str> {
+  seconds.parse::<f64>()
+    .ok()
+    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+    .ok_or("expected a reasonable number of seconds, and not a negative one")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// The defaults are what the windmill did before any of this was configurable, and a show that never set them
+  /// shouldn't notice the difference: one step of speed every 60ms, and a cool down of 100 of those 60ms updates after
+  /// braking from full speed. Slower stops get shorter cool downs, since they have less to get rid of.
+  #[test]
+  fn defaults_keep_the_original_timing() {
+    let profile = Args::parse_from(["windmill"]).motion_profile().unwrap();
+
+    assert_eq!(Duration::from_millis(255 * 60), profile.ramp.acceleration);
+    assert_eq!(Duration::from_millis(255 * 60), profile.ramp.deceleration);
+    assert_eq!(None, profile.ramp.s_curve);
+    assert_eq!(Duration::from_secs(6), profile.brake_cooldown.duration(u8::MAX));
+    assert!(profile.brake_cooldown.duration(0) < profile.brake_cooldown.duration(u8::MAX));
+    assert!(profile.coast_cooldown.duration(0) < profile.coast_cooldown.duration(u8::MAX));
+  }
+
+  #[test]
+  fn rejects_times_that_arent_a_sensible_number_of_seconds() {
+    for time in ["-1", "inf", "NaN", "1e30", "soon"] {
+      assert!(Args::try_parse_from(["windmill", "--acceleration-time", time]).is_err(), "{time} was accepted");
+      assert!(Args::try_parse_from(["windmill", "--brake-cooldown-minimum", time]).is_err(), "{time} was accepted");
+    }
+
+    let args = Args::parse_from(["windmill", "--deceleration-time", "0", "--coast-cooldown-full-speed", "2.5"]);
+    assert_eq!(Duration::ZERO, args.deceleration_time);
+    assert_eq!(Duration::from_millis(2500), args.coast_cooldown_full_speed);
+  }
+
+  #[test]
+  fn rejects_a_pwm_frequency_of_zero() {
+    assert!(Args::try_parse_from(["windmill", "--pwm-frequency", "0"]).is_err());
+    assert_eq!(1, Args::parse_from(["windmill", "--pwm-frequency", "1"]).pwm_frequency);
+  }
 }
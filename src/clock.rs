@@ -0,0 +1,60 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+/// A `Clock` is where the control loop gets its idea of time from. In the real world, that's the real clock. In tests,
+/// it's a `VirtualClock`, so that ramps and cool downs can be stepped through to the exact instant without anyone
+/// having to wait for them.
+pub trait Clock {
+  /// The current time.
+  fn now(&self) -> Instant;
+
+  /// Waits for `duration` to pass.
+  fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send;
+}
+
+/// The real clock, by way of tokio (so a test running with tokio's time paused gets paused time here too).
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+
+  fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send {
+    tokio::time::sleep(duration)
+  }
+}
+
+/// `VirtualClock` only moves when it's told to. Sleeping on it moves it along by exactly as long as the sleep, right
+/// away, and then gives everything else on the runtime a chance to run, as if that much time really had passed. Clones
+/// share the same time, so one can be handed to the control loop and another kept to look at.
+#[derive(Clone, Debug)]
+pub struct VirtualClock {
+  /// The current time.
+  now: Arc<Mutex<Instant>>
+}
+
+impl VirtualClock {
+  /// Creates a new `VirtualClock` that starts at `start`.
+  pub fn new(start: Instant) -> Self {
+    VirtualClock { now: Arc::new(Mutex::new(start)) }
+  }
+
+  /// Moves the clock along by `duration`.
+  pub fn advance(&self, duration: Duration) {
+    *self.now.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) += duration;
+  }
+}
+
+impl Clock for VirtualClock {
+  fn now(&self) -> Instant {
+    *self.now.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send {
+    self.advance(duration);
+    tokio::task::yield_now()
+  }
+}
@@ -0,0 +1,312 @@
+use std::collections::VecDeque;
+use tokio::time::{Duration, Instant};
+use crate::control::ControlHold;
+use crate::fixture::{Cue, MotionChannels, Personality, Windmill};
+
+/// How (if at all) the speed coming in from the console should be smoothed before it's acted on.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Smoothing {
+  /// Take every frame at face value.
+  None,
+
+  /// An exponential low-pass filter with the given time constant. Good for taking the edge off a jittery fader, at the
+  /// cost of a little lag.
+  LowPass(Duration),
+
+  /// The median of the last however many frames. Good at throwing away the odd wild frame entirely, which a low-pass
+  /// filter would only smear out.
+  Median(usize)
+}
+
+/// Everything about how incoming frames are cleaned up before they reach the control loop.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ConditioningProfile {
+  /// How speed is smoothed.
+  pub smoothing: Smoothing,
+
+  /// How many values either side of the direction channel's threshold (`127`/`128`) leave the direction as it was,
+  /// rather than flipping it. Only applies to the speed/direction personality; a bipolar fader has its centre band.
+  pub direction_hysteresis: u8,
+
+  /// How many frames in a row a change of direction has to be asked for before it's believed. `0` doesn't count frames.
+  pub direction_hold_frames: u32,
+
+  /// How long a change of direction has to be asked for before it's believed. Zero doesn't time anything.
+  pub direction_hold_time: Duration
+}
+
+impl Default for ConditioningProfile {
+  fn default() -> Self {
+    ConditioningProfile {
+      smoothing: Smoothing::None,
+      direction_hysteresis: 0,
+      direction_hold_frames: 0,
+      direction_hold_time: Duration::ZERO
+    }
+  }
+}
+
+/// Which way the windmill is being asked to turn, without the speed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Direction {
+  Forward,
+  Reverse
+}
+
+/// A change of direction that has been asked for, but not yet believed.
+#[derive(Copy, Clone, Debug)]
+struct PendingDirection {
+  /// The direction being asked for.
+  direction: Direction,
+
+  /// When it was first asked for.
+  since: Instant,
+
+  /// How many frames in a row have asked for it.
+  frames: u32
+}
+
+/// `Conditioner` sits between decoding a DMX frame and handing the result to the control loop. Changing direction is
+/// the single most expensive thing the windmill can be asked to do (a full brake and a cool down), so it is very
+/// undesirable for a noisy fader, or a console crossfade that happens to swing through the middle of the direction
+/// channel, to trigger one. This stage smooths speed, adds hysteresis around the direction threshold, and holds off on
+/// changes of direction until they've been asked for consistently. It also debounces the control channel, for much the
+/// same reason.
+pub struct Conditioner {
+  /// Decodes the raw frames.
+  personality: Personality,
+
+  /// How to clean them up.
+  profile: ConditioningProfile,
+
+  /// The direction we last passed along.
+  direction: Option<Direction>,
+
+  /// A change of direction we're waiting on.
+  pending: Option<PendingDirection>,
+
+  /// The low-pass filter's current output.
+  filtered: f64,
+
+  /// Recent speeds, for the median filter.
+  history: VecDeque<u8>,
+
+  /// When the last frame arrived.
+  last_frame: Option<Instant>,
+
+  /// Debounces the control channel.
+  control: ControlHold
+}
+
+impl Conditioner {
+  /// Creates a new `Conditioner` for frames laid out according to `personality`.
+  pub fn new(personality: Personality, profile: ConditioningProfile) -> Self {
+    Conditioner {
+      personality,
+      profile,
+      direction: None,
+      pending: None,
+      filtered: 0.0,
+      history: VecDeque::new(),
+      last_frame: None,
+      control: ControlHold::default()
+    }
+  }
+
+  /// The personality frames are decoded with.
+  pub fn personality(&self) -> &Personality {
+    &self.personality
+  }
+
+  /// Decodes and conditions a frame that arrived at `now`, where `channel` looks up the value of a (one-indexed) DMX
+  /// channel.
+  pub fn decode(&mut self, channel: impl Fn(u32) -> u8, now: Instant) -> Cue {
+    let cue = self.personality.decode(&channel);
+
+    let (requested, speed) = match cue.windmill {
+      Windmill::Forward(speed) => (Some(Direction::Forward), speed),
+      Windmill::Reverse(speed) => (Some(Direction::Reverse), speed),
+      Windmill::Off | Windmill::Cooldown(_) => (None, 0)
+    };
+
+    let requested = self.apply_hysteresis(requested, &channel);
+    let speed = self.smooth(speed, now);
+    self.last_frame = Some(now);
+
+    // Once the speed has come all the way down, there's nothing to brake, so there's no reason to be suspicious of a
+    // change of direction. Otherwise, a change has to prove itself before we believe it.
+    let direction = match (self.direction, requested) {
+      (_, None) => self.direction,
+      (None, Some(requested)) => Some(requested),
+      (Some(current), Some(requested)) if current == requested || speed == 0 => Some(requested),
+      (Some(current), Some(requested)) => Some(if self.hold(requested, now) { requested } else { current })
+    };
+
+    if direction == requested || requested.is_none() {
+      self.pending = None;
+    }
+
+    self.direction = direction;
+
+    let windmill = match (direction, speed) {
+      (_, 0) | (None, _) => Windmill::Off,
+      (Some(Direction::Forward), speed) => Windmill::Forward(speed),
+      (Some(Direction::Reverse), speed) => Windmill::Reverse(speed)
+    };
+
+    let control = self.control.update(cue.control, now);
+
+    Cue { windmill, control, ..cue }
+  }
+
+  /// Within the hysteresis band around the direction channel's threshold, whatever direction we had sticks.
+  fn apply_hysteresis(&self, requested: Option<Direction>, channel: &impl Fn(u32) -> u8) -> Option<Direction> {
+    let MotionChannels::SpeedDirection { direction_channel, .. } = self.personality.motion else {
+      return requested;
+    };
+
+    let hysteresis = self.profile.direction_hysteresis;
+    let value = channel(direction_channel);
+    let in_band = value >= 128u8.saturating_sub(hysteresis) && value <= 127u8.saturating_add(hysteresis);
+
+    match (requested, self.direction) {
+      (Some(_), Some(current)) if in_band && hysteresis > 0 => Some(current),
+      (requested, _) => requested
+    }
+  }
+
+  /// Runs `speed` through whichever smoothing filter is configured.
+  fn smooth(&mut self, speed: u8, now: Instant) -> u8 {
+    match self.profile.smoothing {
+      Smoothing::None => speed,
+
+      Smoothing::LowPass(time_constant) => {
+        let elapsed = self.last_frame.map(|last_frame| now.saturating_duration_since(last_frame));
+
+        self.filtered = match elapsed {
+          // The very first frame has nothing to be smoothed against.
+          None => speed as f64,
+          Some(_) if time_constant.is_zero() => speed as f64,
+          Some(elapsed) => {
+            let alpha = 1.0 - (-elapsed.as_secs_f64() / time_constant.as_secs_f64()).exp();
+            self.filtered + alpha * (speed as f64 - self.filtered)
+          }
+        };
+
+        self.filtered.round() as u8
+      },
+
+      Smoothing::Median(frames) => {
+        self.history.push_back(speed);
+
+        while self.history.len() > std::cmp::max(frames, 1) {
+          self.history.pop_front();
+        }
+
+        let mut sorted = self.history.iter().copied().collect::<Vec<_>>();
+        sorted.sort_unstable();
+        sorted[sorted.len() / 2]
+      }
+    }
+  }
+
+  /// Tracks how long `requested` has been asked for, and returns whether it's been asked for long enough to believe.
+  fn hold(&mut self, requested: Direction, now: Instant) -> bool {
+    let pending = match self.pending {
+      Some(pending) if pending.direction == requested => PendingDirection { frames: pending.frames + 1, ..pending },
+      _ => PendingDirection { direction: requested, since: now, frames: 1 }
+    };
+
+    self.pending = Some(pending);
+
+    let frames = self.profile.direction_hold_frames;
+    let time = self.profile.direction_hold_time;
+
+    match (frames, time.is_zero()) {
+      (0, true) => true,
+      (0, false) => now.saturating_duration_since(pending.since) >= time,
+      (frames, true) => pending.frames >= frames,
+      (frames, false) => pending.frames >= frames || now.saturating_duration_since(pending.since) >= time
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn frame(speed: u8, direction: u8) -> impl Fn(u32) -> u8 {
+    move |channel| if channel == 1 { speed } else { direction }
+  }
+
+  #[test]
+  fn crossfade_through_the_middle_does_not_reverse() {
+    let profile = ConditioningProfile { direction_hold_frames: 3, ..ConditioningProfile::default() };
+    let mut conditioner = Conditioner::new(Personality::speed_direction(1, 2), profile);
+    let now = Instant::now();
+
+    assert_eq!(Windmill::Forward(100), conditioner.decode(frame(100, 0), now).windmill);
+    assert_eq!(Windmill::Forward(100), conditioner.decode(frame(100, 200), now).windmill);
+    assert_eq!(Windmill::Forward(100), conditioner.decode(frame(100, 0), now).windmill);
+
+    assert_eq!(Windmill::Forward(100), conditioner.decode(frame(100, 200), now).windmill);
+    assert_eq!(Windmill::Forward(100), conditioner.decode(frame(100, 200), now).windmill);
+    assert_eq!(Windmill::Reverse(100), conditioner.decode(frame(100, 200), now).windmill);
+  }
+
+  #[test]
+  fn direction_change_can_be_held_by_time() {
+    let profile = ConditioningProfile {
+      direction_hold_time: Duration::from_millis(250),
+      ..ConditioningProfile::default()
+    };
+
+    let mut conditioner = Conditioner::new(Personality::speed_direction(1, 2), profile);
+    let now = Instant::now();
+
+    conditioner.decode(frame(100, 0), now);
+    assert_eq!(Windmill::Forward(100), conditioner.decode(frame(100, 255), now).windmill);
+    assert_eq!(Windmill::Forward(100), conditioner.decode(frame(100, 255), now + Duration::from_millis(200)).windmill);
+    assert_eq!(Windmill::Reverse(100), conditioner.decode(frame(100, 255), now + Duration::from_millis(250)).windmill);
+  }
+
+  #[test]
+  fn hysteresis_holds_direction_near_the_threshold() {
+    let profile = ConditioningProfile { direction_hysteresis: 10, ..ConditioningProfile::default() };
+    let mut conditioner = Conditioner::new(Personality::speed_direction(1, 2), profile);
+    let now = Instant::now();
+
+    conditioner.decode(frame(100, 0), now);
+    assert_eq!(Windmill::Forward(100), conditioner.decode(frame(100, 137), now).windmill);
+    assert_eq!(Windmill::Reverse(100), conditioner.decode(frame(100, 138), now).windmill);
+    assert_eq!(Windmill::Reverse(100), conditioner.decode(frame(100, 118), now).windmill);
+    assert_eq!(Windmill::Forward(100), conditioner.decode(frame(100, 117), now).windmill);
+  }
+
+  #[test]
+  fn median_throws_away_glitches() {
+    let profile = ConditioningProfile { smoothing: Smoothing::Median(3), ..ConditioningProfile::default() };
+    let mut conditioner = Conditioner::new(Personality::speed_direction(1, 2), profile);
+    let now = Instant::now();
+
+    conditioner.decode(frame(100, 0), now);
+    conditioner.decode(frame(100, 0), now);
+    assert_eq!(Windmill::Forward(100), conditioner.decode(frame(0, 0), now).windmill);
+    assert_eq!(Windmill::Forward(100), conditioner.decode(frame(255, 0), now).windmill);
+  }
+
+  #[test]
+  fn low_pass_lags_behind_steps() {
+    let profile = ConditioningProfile {
+      smoothing: Smoothing::LowPass(Duration::from_millis(100)),
+      ..ConditioningProfile::default()
+    };
+
+    let mut conditioner = Conditioner::new(Personality::speed_direction(1, 2), profile);
+    let now = Instant::now();
+
+    conditioner.decode(frame(0, 0), now);
+    assert_eq!(Windmill::Forward(161), conditioner.decode(frame(255, 0), now + Duration::from_millis(100)).windmill);
+    assert_eq!(Windmill::Off, conditioner.decode(frame(0, 0), now + Duration::from_millis(1000)).windmill);
+  }
+}
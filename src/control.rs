@@ -0,0 +1,240 @@
+use tokio::time::{Duration, Instant};
+use crate::fixture::Windmill;
+use crate::motion::StopMode;
+
+/// How long a control channel value has to be held before it does anything. Long enough that sweeping a fader through
+/// the channel (or a stray cue) won't trigger anything, short enough that an operator who means it isn't kept waiting.
+pub const HOLD_TIME: Duration = Duration::from_secs(2);
+
+/// How fast the self-test runs the windmill. Slow enough to be safe with someone standing next to it, fast enough to be
+/// sure it's actually turning.
+pub const SELF_TEST_SPEED: u8 = 64;
+
+/// How long the self-test runs the windmill for, before stopping it again.
+pub const SELF_TEST_DURATION: Duration = Duration::from_secs(5);
+
+/// Maintenance commands sent over the control channel, laid out in value ranges the same way a moving light's control
+/// channel would be. Everything outside the listed ranges does nothing, so a channel parked at zero is always safe.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ControlCommand {
+  /// `10`-`19`: allow the windmill to move.
+  Arm,
+
+  /// `20`-`29`: stop the windmill (however it's configured to stop) and keep it stopped until it's armed again.
+  Disarm,
+
+  /// `30`-`39`: clear a latched fault, so the windmill can move again.
+  ResetFaults,
+
+  /// `40`-`49`: run the windmill forward slowly for a few seconds and stop it again, to check it's all working.
+  SelfTest,
+
+  /// `50`-`59` ramps down, `60`-`69` coasts, and `70`-`79` hard brakes: changes the stop mode used when a cue doesn't
+  /// pick one itself.
+  StopMode(StopMode)
+}
+
+impl ControlCommand {
+  /// Decodes a control command from a DMX value.
+  pub fn from_dmx(value: u8) -> Option<ControlCommand> {
+    match value {
+      10..=19 => Some(ControlCommand::Arm),
+      20..=29 => Some(ControlCommand::Disarm),
+      30..=39 => Some(ControlCommand::ResetFaults),
+      40..=49 => Some(ControlCommand::SelfTest),
+      50..=59 => Some(ControlCommand::StopMode(StopMode::RampDown)),
+      60..=69 => Some(ControlCommand::StopMode(StopMode::Coast)),
+      70..=79 => Some(ControlCommand::StopMode(StopMode::HardBrake)),
+      _ => None
+    }
+  }
+}
+
+/// `ControlHold` debounces the control channel: a command only goes through once it has been held for `HOLD_TIME`,
+/// and then only once. The channel has to let go of the command (or move on to another) before it can fire again.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ControlHold {
+  /// The command currently being held, and since when.
+  held: Option<(ControlCommand, Instant)>,
+
+  /// Whether the held command has already gone through.
+  fired: bool
+}
+
+impl ControlHold {
+  /// Feeds in the command the control channel is asking for at `now`, and returns it if this is the moment it's been
+  /// held for long enough.
+  pub fn update(&mut self, command: Option<ControlCommand>, now: Instant) -> Option<ControlCommand> {
+    let Some(command) = command else {
+      *self = ControlHold::default();
+      return None;
+    };
+
+    match self.held {
+      Some((held, _)) if held == command => {},
+      _ => *self = ControlHold { held: Some((command, now)), fired: false }
+    }
+
+    match self.held {
+      Some((held, since)) if !self.fired && now.saturating_duration_since(since) >= HOLD_TIME => {
+        self.fired = true;
+        Some(held)
+      },
+      _ => None
+    }
+  }
+}
+
+/// `ControlState` is the part of the windmill that sits above motion: whether it's allowed to move at all, whether
+/// something has gone wrong, and whether a self-test is running. It has the final say on where the windmill is headed.
+pub struct ControlState {
+  /// Whether the windmill is allowed to move.
+  armed: bool,
+
+  /// The fault that stopped the windmill, if there is one. Faults latch until they're reset.
+  fault: Option<&'static str>,
+
+  /// If a self-test is running, when it ends.
+  self_test_until: Option<Instant>,
+
+  /// The stop mode to use when a cue doesn't pick one.
+  default_stop_mode: StopMode
+}
+
+impl ControlState {
+  /// Creates a new `ControlState`, either ready to go or waiting to be armed.
+  pub fn new(armed: bool, default_stop_mode: StopMode) -> Self {
+    ControlState {
+      armed,
+      fault: None,
+      self_test_until: None,
+      default_stop_mode
+    }
+  }
+
+  /// Carries out a control command at `now`. `idle` is whether the windmill is currently sitting still, since a
+  /// self-test won't start on a windmill that's busy doing something else.
+  pub fn apply(&mut self, command: ControlCommand, idle: bool, now: Instant) {
+    match command {
+      ControlCommand::Arm => {
+        println!("Armed.");
+        self.armed = true;
+      },
+
+      ControlCommand::Disarm => {
+        println!("Disarmed.");
+        self.armed = false;
+        self.self_test_until = None;
+      },
+
+      ControlCommand::ResetFaults => {
+        if let Some(fault) = self.fault.take() {
+          println!("Reset fault: {fault}");
+        }
+      },
+
+      ControlCommand::SelfTest if idle && self.can_move() => {
+        println!("Starting self-test.");
+        self.self_test_until = Some(now + SELF_TEST_DURATION);
+      },
+
+      ControlCommand::SelfTest => println!("Not starting self-test: the windmill is busy, disarmed or faulted."),
+
+      ControlCommand::StopMode(stop_mode) => {
+        println!("Default stop mode is now {stop_mode:?}.");
+        self.default_stop_mode = stop_mode;
+      }
+    }
+  }
+
+  /// Latches a fault. The windmill hard brakes and stays stopped until the fault is reset.
+  pub fn latch_fault(&mut self, fault: &'static str) {
+    if self.fault.is_none() {
+      eprintln!("Fault latched: {fault}");
+    }
+
+    self.fault = Some(fault);
+    self.self_test_until = None;
+  }
+
+  /// The latched fault, if there is one.
+  pub fn fault(&self) -> Option<&'static str> {
+    self.fault
+  }
+
+  /// The stop mode to use when a cue doesn't pick one.
+  pub fn default_stop_mode(&self) -> StopMode {
+    self.default_stop_mode
+  }
+
+  /// Turns where the console wants the windmill to be into where it's actually allowed to be at `now`. A disarmed or
+  /// faulted windmill stays off, and a self-test overrides the console until it's done.
+  pub fn gate(&mut self, desired: Windmill, now: Instant) -> Windmill {
+    if !self.can_move() {
+      return Windmill::Off;
+    }
+
+    match self.self_test_until {
+      Some(until) if now < until => Windmill::Forward(SELF_TEST_SPEED),
+      Some(_) => {
+        println!("Self-test complete.");
+        self.self_test_until = None;
+        Windmill::Off
+      },
+      None => desired
+    }
+  }
+
+  /// Whether the windmill is allowed to move at all.
+  fn can_move(&self) -> bool {
+    self.armed && self.fault.is_none()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn commands_only_fire_once_held() {
+    let mut hold = ControlHold::default();
+    let now = Instant::now();
+    let arm = ControlCommand::from_dmx(15);
+
+    assert_eq!(None, hold.update(arm, now));
+    assert_eq!(None, hold.update(arm, now + Duration::from_millis(1999)));
+    assert_eq!(Some(ControlCommand::Arm), hold.update(arm, now + HOLD_TIME));
+    assert_eq!(None, hold.update(arm, now + Duration::from_secs(10)));
+
+    // Letting go (or sweeping through something else) starts the clock over.
+    assert_eq!(None, hold.update(None, now + Duration::from_secs(11)));
+    assert_eq!(None, hold.update(arm, now + Duration::from_secs(12)));
+    assert_eq!(Some(ControlCommand::Arm), hold.update(arm, now + Duration::from_secs(14)));
+  }
+
+  #[test]
+  fn disarmed_and_faulted_windmills_stay_off() {
+    let mut control = ControlState::new(false, StopMode::HardBrake);
+    let now = Instant::now();
+
+    assert_eq!(Windmill::Off, control.gate(Windmill::Forward(100), now));
+    control.apply(ControlCommand::Arm, true, now);
+    assert_eq!(Windmill::Forward(100), control.gate(Windmill::Forward(100), now));
+
+    control.latch_fault("oh no");
+    assert_eq!(Windmill::Off, control.gate(Windmill::Forward(100), now));
+    control.apply(ControlCommand::ResetFaults, true, now);
+    assert_eq!(Windmill::Forward(100), control.gate(Windmill::Forward(100), now));
+  }
+
+  #[test]
+  fn self_test_runs_then_stops() {
+    let mut control = ControlState::new(true, StopMode::HardBrake);
+    let now = Instant::now();
+
+    control.apply(ControlCommand::SelfTest, true, now);
+    assert_eq!(Windmill::Forward(SELF_TEST_SPEED), control.gate(Windmill::Reverse(200), now));
+    assert_eq!(Windmill::Off, control.gate(Windmill::Reverse(200), now + SELF_TEST_DURATION));
+    assert_eq!(Windmill::Reverse(200), control.gate(Windmill::Reverse(200), now + SELF_TEST_DURATION));
+  }
+}
@@ -0,0 +1,185 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// The lowest speed a DMX channel can ask for.
+const INPUT_MIN: u8 = u8::MIN;
+
+/// The highest speed a DMX channel can ask for.
+const INPUT_MAX: u8 = u8::MAX;
+
+/// The lowest duty cycle (as a percentage) we'll hand to the PWM output.
+const OUTPUT_MIN: u8 = u8::MIN;
+
+/// The highest duty cycle (as a percentage) we'll hand to the PWM output.
+const OUTPUT_MAX: u8 = 100;
+
+/// How aggressively the `Exponential` curve bends. Bigger numbers keep the bottom of the fader flatter for longer.
+const EXPONENTIAL_STEEPNESS: f64 = 4.0;
+
+/// A response curve maps the speed an operator asked for (a DMX value from `0` to `255`) onto the duty cycle the motor
+/// actually gets (a percentage from `0` to `100`). Neither motors nor audiences perceive speed linearly: most of the
+/// visible change in a big windmill happens at the bottom of the fader, and the top end all looks like "fast". Picking a
+/// curve lets the fader feel even across its whole travel.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResponseCurve {
+  /// Duty cycle is directly proportional to the fader. What you get if you don't think about it.
+  Linear,
+
+  /// Duty cycle follows the square of the fader, giving a lot more resolution at the bottom end.
+  Square,
+
+  /// Like `Square`, but even more so. The bottom of the fader is very gentle and the top end is very steep.
+  Exponential,
+
+  /// Gentle at both ends, steep in the middle. Handy for fine control around both "barely turning" and "flat out".
+  SCurve,
+
+  /// A user-supplied list of `(dmx, duty)` points, linearly interpolated between. Anything outside the first or last
+  /// point is held at that point's duty cycle.
+  Table(Vec<(u8, u8)>)
+}
+
+impl ResponseCurve {
+  /// Maps a DMX `speed` onto a duty cycle percentage according to this curve.
+  pub fn duty_cycle(&self, speed: u8) -> u8 {
+    let input = (speed - INPUT_MIN) as f64 / (INPUT_MAX - INPUT_MIN) as f64;
+
+    let output = match self {
+      ResponseCurve::Linear => input,
+      ResponseCurve::Square => input * input,
+      ResponseCurve::Exponential => (EXPONENTIAL_STEEPNESS * input).exp_m1() / EXPONENTIAL_STEEPNESS.exp_m1(),
+      ResponseCurve::SCurve => input * input * (3.0 - 2.0 * input),
+      ResponseCurve::Table(points) => return Self::interpolate(points, speed)
+    };
+
+    (OUTPUT_MIN as f64 + output * (OUTPUT_MAX - OUTPUT_MIN) as f64).round() as u8
+  }
+
+  /// Linearly interpolates `speed` across a table of points, which are assumed to be sorted by DMX value.
+  fn interpolate(points: &[(u8, u8)], speed: u8) -> u8 {
+    let (first, last) = match (points.first(), points.last()) {
+      (Some(first), Some(last)) => (*first, *last),
+      _ => return OUTPUT_MIN
+    };
+
+    if speed <= first.0 {
+      return first.1;
+    }
+
+    if speed >= last.0 {
+      return last.1;
+    }
+
+    points
+      .windows(2)
+      .find(|pair| speed >= pair[0].0 && speed <= pair[1].0)
+      .map(|pair| {
+        let ((from_dmx, from_duty), (to_dmx, to_duty)) = (pair[0], pair[1]);
+        let progress = (speed - from_dmx) as f64 / (to_dmx - from_dmx) as f64;
+
+        (from_duty as f64 + progress * (to_duty as f64 - from_duty as f64)).round() as u8
+      })
+      .unwrap_or(last.1)
+  }
+}
+
+/// Parses a curve from the command line. The named curves are just their names (`linear`, `square`, `exponential`,
+/// `s-curve`), and a table is written as `table:` followed by comma separated `dmx=duty` points, e.g.
+/// `table:0=0,64=10,192=60,255=100`.
+impl FromStr for ResponseCurve {
+  type Err = &'static str;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    match value {
+      "linear" => Ok(ResponseCurve::Linear),
+      "square" => Ok(ResponseCurve::Square),
+      "exponential" => Ok(ResponseCurve::Exponential),
+      "s-curve" => Ok(ResponseCurve::SCurve),
+      table => {
+        let points = table
+          .strip_prefix("table:")
+          .ok_or("expected linear, square, exponential, s-curve, or table:<dmx>=<duty>,...")?;
+
+        let mut points = points
+          .split(',')
+          .map(|point| {
+            let (dmx, duty) = point.trim().split_once('=').ok_or("table points should look like <dmx>=<duty>")?;
+            let dmx = dmx.trim().parse::<u8>().map_err(|_| "table dmx values should be between 0 and 255")?;
+            let duty = duty.trim().parse::<u8>().map_err(|_| "table duty cycles should be between 0 and 100")?;
+
+            if duty > OUTPUT_MAX {
+              return Err("table duty cycles should be between 0 and 100");
+            }
+
+            Ok((dmx, duty))
+          })
+          .collect::<Result<Vec<_>, _>>()?;
+
+        points.sort_by_key(|(dmx, _)| *dmx);
+
+        if points.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+          return Err("table has more than one duty cycle for the same dmx value");
+        }
+
+        Ok(ResponseCurve::Table(points))
+      }
+    }
+  }
+}
+
+/// The inverse of `FromStr`, so a curve can be printed back out the same way it would be typed in.
+impl Display for ResponseCurve {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ResponseCurve::Linear => write!(f, "linear"),
+      ResponseCurve::Square => write!(f, "square"),
+      ResponseCurve::Exponential => write!(f, "exponential"),
+      ResponseCurve::SCurve => write!(f, "s-curve"),
+      ResponseCurve::Table(points) => {
+        let points = points.iter().map(|(dmx, duty)| format!("{dmx}={duty}")).collect::<Vec<_>>();
+        write!(f, "table:{}", points.join(","))
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn named_curves_share_endpoints() {
+    for curve in [ResponseCurve::Linear, ResponseCurve::Square, ResponseCurve::Exponential, ResponseCurve::SCurve] {
+      assert_eq!(0, curve.duty_cycle(0));
+      assert_eq!(100, curve.duty_cycle(255));
+    }
+  }
+
+  #[test]
+  fn curves_bend_the_way_they_say() {
+    assert_eq!(50, ResponseCurve::Linear.duty_cycle(128));
+    assert_eq!(25, ResponseCurve::Square.duty_cycle(128));
+    assert!(ResponseCurve::Exponential.duty_cycle(128) < 25);
+    assert_eq!(50, ResponseCurve::SCurve.duty_cycle(128));
+    assert!(ResponseCurve::SCurve.duty_cycle(32) < ResponseCurve::Linear.duty_cycle(32));
+  }
+
+  #[test]
+  fn tables_interpolate_and_hold_their_ends() {
+    let curve = "table:200=90,10=20,100=40".parse::<ResponseCurve>().unwrap();
+
+    assert_eq!(20, curve.duty_cycle(0));
+    assert_eq!(30, curve.duty_cycle(55));
+    assert_eq!(40, curve.duty_cycle(100));
+    assert_eq!(90, curve.duty_cycle(255));
+    assert_eq!("table:10=20,100=40,200=90", curve.to_string());
+  }
+
+  #[test]
+  fn rejects_bad_tables() {
+    assert!("table:0=0,0=10".parse::<ResponseCurve>().is_err());
+    assert!("table:0=101".parse::<ResponseCurve>().is_err());
+    assert!("table:256=0".parse::<ResponseCurve>().is_err());
+    assert!("wobbly".parse::<ResponseCurve>().is_err());
+  }
+}
@@ -0,0 +1,323 @@
+use std::f64::consts::TAU;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use tokio::time::{Duration, Instant};
+use crate::fixture::Windmill;
+
+/// The slowest an effect can cycle, in cycles per second, with its rate channel at zero.
+const SLOWEST_RATE: f64 = 0.02;
+
+/// The fastest an effect can cycle, in cycles per second, with its rate channel at full. Anything much quicker than
+/// this is lost on a rotor of any size anyway.
+const FASTEST_RATE: f64 = 2.0;
+
+/// Breathing runs this many times slower than the other effects at the same rate, since it's meant to be slow.
+const BREATHE_SLOWDOWN: f64 = 4.0;
+
+/// How much of each cycle a pulse spends pushing.
+const PULSE_DUTY: f64 = 0.25;
+
+/// The motion effects that can be laid on top of the console's speed. See `EffectKind::from_dmx` for the ranges.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EffectKind {
+  /// `10`-`59`: bursts of extra speed at random intervals and of random strength, like gusts of wind in a storm.
+  Gust,
+
+  /// `60`-`109`: swings smoothly back and forth between two speeds either side of the base speed.
+  Oscillate,
+
+  /// `110`-`159`: short, regular shoves of extra speed.
+  Pulse,
+
+  /// `160`-`209`: a slow swell up from the base speed and back down again.
+  Breathe
+}
+
+impl EffectKind {
+  /// Decodes an effect from a DMX value. Zero to nine, and anything past the listed ranges, is no effect at all.
+  pub fn from_dmx(value: u8) -> Option<EffectKind> {
+    match value {
+      10..=59 => Some(EffectKind::Gust),
+      60..=109 => Some(EffectKind::Oscillate),
+      110..=159 => Some(EffectKind::Pulse),
+      160..=209 => Some(EffectKind::Breathe),
+      _ => None
+    }
+  }
+}
+
+/// Parses an effect by name, e.g. for presets: `gust`, `oscillate`, `pulse` or `breathe`.
+impl FromStr for EffectKind {
+  type Err = &'static str;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    match value {
+      "gust" => Ok(EffectKind::Gust),
+      "oscillate" => Ok(EffectKind::Oscillate),
+      "pulse" => Ok(EffectKind::Pulse),
+      "breathe" => Ok(EffectKind::Breathe),
+      _ => Err("expected gust, oscillate, pulse, or breathe")
+    }
+  }
+}
+
+/// The inverse of `FromStr`.
+impl Display for EffectKind {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      EffectKind::Gust => write!(f, "gust"),
+      EffectKind::Oscillate => write!(f, "oscillate"),
+      EffectKind::Pulse => write!(f, "pulse"),
+      EffectKind::Breathe => write!(f, "breathe")
+    }
+  }
+}
+
+/// An effect, along with how quickly and how hard it should be run.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Effect {
+  /// Which effect.
+  pub kind: EffectKind,
+
+  /// How quickly the effect cycles, from `0` (slowest) to `255` (fastest).
+  pub rate: u8,
+
+  /// How far the effect can push the speed away from the base speed, in the same units as a speed.
+  pub depth: u8
+}
+
+/// Parses an effect as it's written in preset and timeline files: `<effect>:<rate>:<depth>`, e.g. `gust:180:60`.
+impl FromStr for Effect {
+  type Err = &'static str;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    let mut parts = value.split(':');
+    let kind = parts.next().unwrap_or_default().parse()?;
+    let mut level = || parts.next().and_then(|level| level.parse::<u8>().ok());
+    let (rate, depth) = level().zip(level()).ok_or("effects look like <effect>:<rate>:<depth>")?;
+
+    match parts.next() {
+      None => Ok(Effect { kind, rate, depth }),
+      Some(_) => Err("effects look like <effect>:<rate>:<depth>")
+    }
+  }
+}
+
+/// The inverse of `FromStr`.
+impl Display for Effect {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}:{}:{}", self.kind, self.rate, self.depth)
+  }
+}
+
+impl Effect {
+  /// How many times a second the effect cycles.
+  fn frequency(&self) -> f64 {
+    let frequency = SLOWEST_RATE + (FASTEST_RATE - SLOWEST_RATE) * self.rate as f64 / u8::MAX as f64;
+
+    match self.kind {
+      EffectKind::Breathe => frequency / BREATHE_SLOWDOWN,
+      _ => frequency
+    }
+  }
+
+  /// How long one cycle of the effect takes.
+  fn period(&self) -> Duration {
+    Duration::from_secs_f64(1.0 / self.frequency())
+  }
+}
+
+/// A single gust: when it started, how long it blows for, and how hard.
+#[derive(Copy, Clone, Debug)]
+struct Gust {
+  /// When the gust started to blow.
+  start: Instant,
+
+  /// How long the gust blows for, from building up to dying away.
+  duration: Duration,
+
+  /// How hard the gust blows at its peak, from `0.0` to `1.0` of the effect's depth.
+  strength: f64
+}
+
+/// `EffectEngine` generates effects right here on the node, at control loop resolution, rather than leaving a console
+/// to chase the speed channel at whatever rate it happens to refresh at. The effect is blended on top of the base speed
+/// the console asked for, before the ramp gets its say, so the windmill is never pushed any harder than it's allowed.
+pub struct EffectEngine {
+  /// The effect being run, if there is one.
+  effect: Option<Effect>,
+
+  /// When the current effect started, which is where its cycle is measured from.
+  started: Instant,
+
+  /// The gust currently blowing, if there is one.
+  gust: Option<Gust>,
+
+  /// When the next gust is due.
+  next_gust: Instant,
+
+  /// State for a small xorshift generator. Gusts don't need good randomness, just unpredictable looking randomness,
+  /// and being able to seed it makes gusts repeatable.
+  random: u64
+}
+
+impl EffectEngine {
+  /// Creates a new `EffectEngine` with nothing to do, whose gusts are shaped by `seed`.
+  pub fn new(seed: u64, now: Instant) -> Self {
+    EffectEngine {
+      effect: None,
+      started: now,
+      gust: None,
+      next_gust: now,
+      random: seed.max(1)
+    }
+  }
+
+  /// Changes the effect being run. Changing the rate or depth of the same effect carries on from where the cycle was,
+  /// but a different effect starts over at `now`.
+  pub fn set_effect(&mut self, effect: Option<Effect>, now: Instant) {
+    if effect.map(|effect| effect.kind) != self.effect.map(|effect| effect.kind) {
+      self.started = now;
+      self.gust = None;
+      self.next_gust = now;
+    }
+
+    self.effect = effect;
+  }
+
+  /// Lays the effect (if there is one) over the `base` state at `now`. An effect only changes speed, never direction,
+  /// and it never turns a windmill that's meant to be moving off (or one that's meant to be off, on). It also never
+  /// takes a moving windmill below `slowest`, which should be the slowest speed outside the dead band: any slower and
+  /// the windmill would be left in a moving state with no duty cycle behind it.
+  pub fn apply(&mut self, base: Windmill, slowest: u8, now: Instant) -> Windmill {
+    let Some(effect) = self.effect else {
+      return base;
+    };
+
+    let offset = self.offset(effect, now);
+    let slowest = slowest.max(1) as f64;
+    let blend = |speed: u8| (speed as f64 + offset).round().clamp(slowest, u8::MAX as f64) as u8;
+
+    match base {
+      Windmill::Forward(speed) if speed > 0 => Windmill::Forward(blend(speed)),
+      Windmill::Reverse(speed) if speed > 0 => Windmill::Reverse(blend(speed)),
+      base => base
+    }
+  }
+
+  /// How far the effect pushes the speed away from the base speed at `now`.
+  fn offset(&mut self, effect: Effect, now: Instant) -> f64 {
+    let depth = effect.depth as f64;
+    let phase = (now.saturating_duration_since(self.started).as_secs_f64() * effect.frequency()).fract();
+
+    match effect.kind {
+      EffectKind::Oscillate => depth / 2.0 * (TAU * phase).sin(),
+      EffectKind::Pulse => if phase < PULSE_DUTY { depth } else { 0.0 },
+      EffectKind::Breathe => depth * (1.0 - (TAU * phase).cos()) / 2.0,
+      EffectKind::Gust => depth * self.gust(effect, now)
+    }
+  }
+
+  /// How hard the wind is gusting at `now`, from `0.0` to `1.0`. Gusts arrive on average once a period, blow for
+  /// somewhere around half a period, and build quickly and die away slowly.
+  fn gust(&mut self, effect: Effect, now: Instant) -> f64 {
+    let period = effect.period();
+
+    if self.gust.is_none_or(|gust| now >= gust.start + gust.duration) && now >= self.next_gust {
+      let duration = period.mul_f64(0.25 + 0.5 * self.next_random());
+      let strength = 0.3 + 0.7 * self.next_random();
+
+      self.gust = Some(Gust { start: now, duration, strength });
+      self.next_gust = now + duration + period.mul_f64(self.next_random());
+    }
+
+    let Some(gust) = self.gust else {
+      return 0.0;
+    };
+
+    let progress = now.saturating_duration_since(gust.start).as_secs_f64() / gust.duration.as_secs_f64();
+
+    let envelope = match progress {
+      progress if progress >= 1.0 => 0.0,
+      progress if progress < 0.2 => progress / 0.2,
+      progress => (1.0 - progress) / 0.8
+    };
+
+    gust.strength * envelope
+  }
+
+  /// The next number from the generator, from `0.0` to `1.0`.
+  fn next_random(&mut self) -> f64 {
+    self.random ^= self.random << 13;
+    self.random ^= self.random >> 7;
+    self.random ^= self.random << 17;
+
+    (self.random >> 11) as f64 / (1u64 << 53) as f64
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn engine(kind: EffectKind, now: Instant) -> EffectEngine {
+    let mut engine = EffectEngine::new(42, now);
+    engine.set_effect(Some(Effect { kind, rate: 0, depth: 40 }), now);
+    engine
+  }
+
+  #[test]
+  fn effects_stay_around_the_base_speed_and_never_stop_the_windmill() {
+    let now = Instant::now();
+
+    for kind in [EffectKind::Gust, EffectKind::Oscillate, EffectKind::Pulse, EffectKind::Breathe] {
+      let mut engine = engine(kind, now);
+
+      for step in 0..10_000 {
+        let at = now + Duration::from_millis(10 * step);
+
+        match engine.apply(Windmill::Reverse(10), 1, at) {
+          Windmill::Reverse(speed) => assert!((1..=50).contains(&speed), "{kind:?} went to {speed}"),
+          other => panic!("{kind:?} turned into {other:?}")
+        }
+
+        assert_eq!(Windmill::Off, engine.apply(Windmill::Off, 1, at));
+      }
+    }
+  }
+
+  #[test]
+  fn oscillate_swings_either_side() {
+    let now = Instant::now();
+    let mut engine = engine(EffectKind::Oscillate, now);
+    let period = Effect { kind: EffectKind::Oscillate, rate: 0, depth: 40 }.period();
+
+    assert_eq!(Windmill::Forward(100), engine.apply(Windmill::Forward(100), 1, now));
+    assert_eq!(Windmill::Forward(120), engine.apply(Windmill::Forward(100), 1, now + period / 4));
+    assert_eq!(Windmill::Forward(80), engine.apply(Windmill::Forward(100), 1, now + period * 3 / 4));
+  }
+
+  #[test]
+  fn oscillate_stays_out_of_the_dead_band() {
+    let now = Instant::now();
+    let mut engine = engine(EffectKind::Oscillate, now);
+    let period = Effect { kind: EffectKind::Oscillate, rate: 0, depth: 40 }.period();
+
+    // Swinging 20 either side of 15 would take the windmill down to -5, well inside a dead band of 10.
+    assert_eq!(Windmill::Forward(35), engine.apply(Windmill::Forward(15), 11, now + period / 4));
+    assert_eq!(Windmill::Forward(11), engine.apply(Windmill::Forward(15), 11, now + period * 3 / 4));
+    assert_eq!(Windmill::Reverse(11), engine.apply(Windmill::Reverse(15), 11, now + period * 3 / 4));
+  }
+
+  #[test]
+  fn same_seed_same_gusts() {
+    let now = Instant::now();
+    let mut first = engine(EffectKind::Gust, now);
+    let mut second = engine(EffectKind::Gust, now);
+
+    for step in 0..1000 {
+      let at = now + Duration::from_millis(50 * step);
+      assert_eq!(first.apply(Windmill::Forward(100), 1, at), second.apply(Windmill::Forward(100), 1, at));
+    }
+  }
+}
@@ -1,3 +1,10 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+use crate::control::ControlCommand;
+use crate::effects::{Effect, EffectKind};
+use crate::motion::StopMode;
+
 /// Represents a state of the windmill. The windmill can either be `Off` (not spinning), moving `Forward` at some
 /// desired rate, or moving in `Reverse` (again, at a desired rate). This is used for all representations of the
 /// windmill. That is, an instance of this enum is not necessarily the current state. It can be something like a desired
@@ -15,9 +22,9 @@ pub enum Windmill {
   /// The windmill is off. Or should be. Or wants to be.
   Off,
 
-  /// The windmill is tired after just being braked. THe internal integer holds a number of cycles the windmill should
+  /// The windmill is tired after just being stopped. The internal duration holds how much longer the windmill should
   /// wait for momentum to die down and to want to start spinning again.
-  Cooldown(u8),
+  Cooldown(Duration),
 
   /// The windmill should be or is moving forward. The internal integer represents the rate at which it should be
   /// moving.
@@ -27,3 +34,321 @@ pub enum Windmill {
   /// moving backward.
   Reverse(u8)
 }
+
+/// Parses a desired state as it's written in preset and timeline files: `off`, `forward:<speed>` or `reverse:<speed>`.
+/// There's no way to write a cool down, since that's never something anyone should ask for.
+impl FromStr for Windmill {
+  type Err = &'static str;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    let speed = |speed: &str| speed.parse::<u8>().map_err(|_| "speeds should be 0 to 255");
+
+    match (value, value.split_once(':')) {
+      ("off", _) => Ok(Windmill::Off),
+      (_, Some(("forward", forward))) => Ok(Windmill::Forward(speed(forward)?)),
+      (_, Some(("reverse", reverse))) => Ok(Windmill::Reverse(speed(reverse)?)),
+      _ => Err("expected off, forward:<speed> or reverse:<speed>")
+    }
+  }
+}
+
+/// The inverse of `FromStr`. A cool down is written as `off`, since that's where it's headed.
+impl Display for Windmill {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Windmill::Forward(speed) => write!(f, "forward:{speed}"),
+      Windmill::Reverse(speed) => write!(f, "reverse:{speed}"),
+      Windmill::Off | Windmill::Cooldown(_) => write!(f, "off")
+    }
+  }
+}
+
+/// A `Cue` is everything the operator is asking of the windmill at a given moment: the `Windmill` state they'd like it
+/// to be in, plus any extra instructions about how to get there. The extras are all optional; anything left out falls
+/// back to however the windmill was configured.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cue {
+  /// The state the windmill should head towards.
+  pub windmill: Windmill,
+
+  /// How the windmill should stop, if this cue means it has to.
+  pub stop_mode: Option<StopMode>,
+
+  /// How long the next speed change should take, if the console has an opinion. The windmill still won't go any faster
+  /// than its configured ramp allows.
+  pub fade: Option<Duration>,
+
+  /// A maintenance command from the control channel. Straight out of a frame, this is whatever the channel is sitting
+  /// on; once conditioned, it only shows up on the one cue where it's finally been held long enough to count.
+  pub control: Option<ControlCommand>,
+
+  /// The motion effect to lay over the speed, if any.
+  pub effect: Option<Effect>,
+
+  /// The preset picked on the preset channel, if any, counting from `1`. See `Presets::resolve`.
+  pub preset: Option<u8>,
+
+  /// Where the cue came from.
+  pub source: CueSource
+}
+
+impl Cue {
+  /// Whether this cue is asking for nothing at all: no motion, no preset, and no effect. An idle console is one that
+  /// playback is allowed to speak over.
+  pub fn is_idle(&self) -> bool {
+    matches!(self.windmill, Windmill::Off | Windmill::Cooldown(_)) && self.preset.is_none() && self.effect.is_none()
+  }
+}
+
+/// Where a `Cue` came from. Both feed the same channel to the windmill, which needs to know who to listen to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CueSource {
+  /// Live DMX from a console (or OSC, or whatever else `ola` is patched to).
+  Console,
+
+  /// Standalone timeline playback. Live DMX takes over from playback whenever it's asking for anything.
+  Playback
+}
+
+/// A bare `Windmill` state is a `Cue` with no opinions about anything else.
+impl From<Windmill> for Cue {
+  fn from(windmill: Windmill) -> Self {
+    Cue {
+      windmill,
+      stop_mode: None,
+      fade: None,
+      control: None,
+      effect: None,
+      preset: None,
+      source: CueSource::Console
+    }
+  }
+}
+
+/// How the windmill's motion is laid out across DMX channels.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MotionChannels {
+  /// The classic layout: one channel for speed (where `0` is off), and another for direction (`0`-`127` is forward and
+  /// `128`-`255` is reverse).
+  SpeedDirection {
+    /// The channel speed is read from.
+    speed_channel: u32,
+
+    /// The channel direction is read from.
+    direction_channel: u32
+  },
+
+  /// A single centre-zero fader, which a lot of consoles and OSC surfaces are much happier with. `0` is full reverse,
+  /// `255` is full forward, and anything within `centre_band` of the middle is off.
+  Bipolar {
+    /// The channel the fader is read from.
+    channel: u32,
+
+    /// How many values either side of the centre (`127`/`128`) count as off. Faders are rarely dead centre when
+    /// someone thinks they are.
+    centre_band: u8
+  }
+}
+
+/// A `Personality` describes which DMX channels the windmill listens to and what it makes of them, in the same sense as a
+/// moving light's personality (or mode). Channel numbers are DMX channel numbers, i.e. they start at `1`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Personality {
+  /// Where speed and direction come from.
+  pub motion: MotionChannels,
+
+  /// A channel that picks the stop mode per cue, if there is one. See `StopMode::from_dmx`.
+  pub stop_mode_channel: Option<u32>,
+
+  /// A channel that sets how long the next speed change takes, if there is one. See `Personality::fade_from_dmx`.
+  pub fade_time_channel: Option<u32>,
+
+  /// A channel for maintenance commands, if there is one. See `ControlCommand::from_dmx`.
+  pub control_channel: Option<u32>,
+
+  /// A channel that picks a motion effect, if there is one. See `EffectKind::from_dmx`.
+  pub effect_channel: Option<u32>,
+
+  /// A channel that sets how quickly the effect cycles, if there is one. Otherwise effects run at a middling rate.
+  pub effect_rate_channel: Option<u32>,
+
+  /// A channel that sets how hard the effect pushes, if there is one. Otherwise effects run at a middling depth.
+  pub effect_depth_channel: Option<u32>,
+
+  /// A channel that recalls an on-device preset, if there is one. Zero is no preset.
+  pub preset_channel: Option<u32>
+}
+
+impl Personality {
+  /// The widest centre band a bipolar fader can have while still leaving room to move in either direction.
+  pub const MAX_CENTRE_BAND: u8 = 126;
+
+  /// How much fade time each step of the fade time channel is worth. A tenth of a second a step gets up to 25.5
+  /// seconds, which is about as long as anyone wants to watch a windmill change speed.
+  pub const FADE_TIME_STEP: Duration = Duration::from_millis(100);
+
+  /// The rate and depth effects run at when there's no channel to set them.
+  pub const DEFAULT_EFFECT_LEVEL: u8 = 128;
+
+  /// Decodes a `Cue` from a DMX frame, where `channel` looks up the value of a (one-indexed) DMX channel.
+  pub fn decode(&self, channel: impl Fn(u32) -> u8) -> Cue {
+    let windmill = match self.motion {
+      MotionChannels::SpeedDirection { speed_channel, direction_channel } => match channel(speed_channel) {
+        0 => Windmill::Off,
+        speed => match channel(direction_channel) {
+          0..=127 => Windmill::Forward(speed),
+          128..=255 => Windmill::Reverse(speed)
+        }
+      },
+
+      MotionChannels::Bipolar { channel: fader, centre_band } => Self::decode_bipolar(channel(fader), centre_band)
+    };
+
+    Cue {
+      windmill,
+      stop_mode: self.stop_mode_channel.and_then(|stop_mode_channel| StopMode::from_dmx(channel(stop_mode_channel))),
+      fade: self.fade_time_channel.and_then(|fade_time_channel| Self::fade_from_dmx(channel(fade_time_channel))),
+      control: self.control_channel.and_then(|control_channel| ControlCommand::from_dmx(channel(control_channel))),
+      effect: self.decode_effect(&channel),
+      preset: self.preset_channel.map(&channel).filter(|preset| *preset > 0),
+      source: CueSource::Console
+    }
+  }
+
+  /// Every (one-indexed) channel this personality reads, in no particular order.
+  pub fn channels(&self) -> Vec<u32> {
+    let motion = match self.motion {
+      MotionChannels::SpeedDirection { speed_channel, direction_channel } => vec![speed_channel, direction_channel],
+      MotionChannels::Bipolar { channel, .. } => vec![channel]
+    };
+
+    let optional = [
+      self.stop_mode_channel,
+      self.fade_time_channel,
+      self.control_channel,
+      self.effect_channel,
+      self.effect_rate_channel,
+      self.effect_depth_channel,
+      self.preset_channel
+    ];
+
+    motion.into_iter().chain(optional.into_iter().flatten()).collect()
+  }
+
+  /// Decodes the effect channels, if there are any.
+  fn decode_effect(&self, channel: impl Fn(u32) -> u8) -> Option<Effect> {
+    let level = |level_channel: Option<u32>| level_channel.map_or(Self::DEFAULT_EFFECT_LEVEL, &channel);
+
+    self.effect_channel
+      .and_then(|effect_channel| EffectKind::from_dmx(channel(effect_channel)))
+      .map(|kind| Effect { kind, rate: level(self.effect_rate_channel), depth: level(self.effect_depth_channel) })
+  }
+
+  /// Decodes a fade time from a DMX value. Zero means "no preference", leaving it to the configured ramp; everything
+  /// else is that many `FADE_TIME_STEP`s.
+  pub fn fade_from_dmx(value: u8) -> Option<Duration> {
+    match value {
+      0 => None,
+      steps => Some(Self::FADE_TIME_STEP * steps as u32)
+    }
+  }
+
+  /// Splits a centre-zero fader `value` into a direction and a speed. Each side of the centre band is stretched back
+  /// out over the full range of speeds, so both ends of the fader are full speed no matter how wide the band is.
+  fn decode_bipolar(value: u8, centre_band: u8) -> Windmill {
+    let centre_band = std::cmp::min(centre_band, Self::MAX_CENTRE_BAND);
+    let reverse_edge = 127 - centre_band;
+    let forward_edge = 128 + centre_band;
+    let stretch = |distance: u8, range: u8| (distance as f64 / range as f64 * u8::MAX as f64).round() as u8;
+
+    if value < reverse_edge {
+      Windmill::Reverse(stretch(reverse_edge - value, reverse_edge))
+    }
+
+    else if value > forward_edge {
+      Windmill::Forward(stretch(value - forward_edge, u8::MAX - forward_edge))
+    }
+
+    else {
+      Windmill::Off
+    }
+  }
+}
+
+/// Shorthands for tests that only care about speed and direction.
+#[cfg(test)]
+impl Personality {
+  /// A personality with just `motion`, and none of the optional channels.
+  pub fn motion_only(motion: MotionChannels) -> Self {
+    Personality {
+      motion,
+      stop_mode_channel: None,
+      fade_time_channel: None,
+      control_channel: None,
+      effect_channel: None,
+      effect_rate_channel: None,
+      effect_depth_channel: None,
+      preset_channel: None
+    }
+  }
+
+  /// A personality with just a speed channel and a direction channel.
+  pub fn speed_direction(speed_channel: u32, direction_channel: u32) -> Self {
+    Self::motion_only(MotionChannels::SpeedDirection { speed_channel, direction_channel })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn frame(values: &[(u32, u8)]) -> impl Fn(u32) -> u8 + '_ {
+    move |channel| values.iter().find(|(at, _)| *at == channel).map(|(_, value)| *value).unwrap_or(0)
+  }
+
+  #[test]
+  fn speed_direction_decodes_like_it_always_has() {
+    let personality = Personality {
+      motion: MotionChannels::SpeedDirection { speed_channel: 10, direction_channel: 11 },
+      stop_mode_channel: Some(12),
+      fade_time_channel: Some(13),
+      control_channel: Some(14),
+      effect_channel: Some(15),
+      effect_rate_channel: None,
+      effect_depth_channel: Some(16),
+      preset_channel: Some(17)
+    };
+
+    assert_eq!(Windmill::Off, personality.decode(frame(&[(11, 200)])).windmill);
+    assert_eq!(Windmill::Forward(80), personality.decode(frame(&[(10, 80), (11, 127)])).windmill);
+    assert_eq!(Windmill::Reverse(80), personality.decode(frame(&[(10, 80), (11, 128)])).windmill);
+    assert_eq!(Some(StopMode::Coast), personality.decode(frame(&[(12, 150)])).stop_mode);
+    assert_eq!(None, personality.decode(frame(&[(13, 0)])).fade);
+    assert_eq!(Some(Duration::from_secs(8)), personality.decode(frame(&[(13, 80)])).fade);
+    assert_eq!(Some(ControlCommand::Arm), personality.decode(frame(&[(14, 12)])).control);
+    assert_eq!(None, personality.decode(frame(&[(15, 5), (16, 30)])).effect);
+
+    assert_eq!(
+      Some(Effect { kind: EffectKind::Pulse, rate: Personality::DEFAULT_EFFECT_LEVEL, depth: 30 }),
+      personality.decode(frame(&[(15, 120), (16, 30)])).effect
+    );
+
+    assert_eq!(None, personality.decode(frame(&[])).preset);
+    assert_eq!(Some(3), personality.decode(frame(&[(17, 3)])).preset);
+  }
+
+  #[test]
+  fn bipolar_fader_is_off_in_the_centre_band_and_full_at_the_ends() {
+    let personality = Personality::motion_only(MotionChannels::Bipolar { channel: 1, centre_band: 7 });
+
+    let decode = |value: u8| personality.decode(frame(&[(1, value)])).windmill;
+
+    assert_eq!(Windmill::Reverse(255), decode(0));
+    assert_eq!(Windmill::Reverse(2), decode(119));
+    assert_eq!(Windmill::Off, decode(120));
+    assert_eq!(Windmill::Off, decode(127));
+    assert_eq!(Windmill::Off, decode(135));
+    assert_eq!(Windmill::Forward(2), decode(136));
+    assert_eq!(Windmill::Forward(255), decode(255));
+  }
+}
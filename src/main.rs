@@ -20,17 +20,44 @@
 //!                +------+-----+----------+------+---+   OPi 3  +---+------+----------+-----+------+
 //!
 
+use std::sync::Arc;
 use clap::Parser;
 use tokio::select;
 use tokio::signal::unix::SignalKind;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::TryRecvError;
-use crate::fixture::Windmill;
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+use crate::clock::Clock;
+use crate::fixture::{Cue, CueSource, Windmill};
+use crate::motion::{Motion, StopMode};
+use crate::output::{MotorDirection, OutputCommand};
 
+pub mod api;
 pub mod cli;
+pub mod clock;
+pub mod conditioning;
+pub mod control;
+pub mod curve;
+pub mod effects;
 pub mod fixture;
+pub mod motion;
+pub mod network;
 pub mod ola;
+pub mod osc;
+pub mod output;
+pub mod physics;
+pub mod pcap;
+pub mod playback;
+pub mod presets;
 pub mod pwm;
+pub mod ramp;
+pub mod recording;
+pub mod scenario;
+pub mod sensor;
+pub mod timecode;
+#[cfg(test)]
+mod verification;
 pub mod wiringpi;
 
 const BRAKE_PIN: i32 = 3;
@@ -46,13 +73,21 @@ const DRIVING_INACTIVE: i32 = wiringpi::DIGITAL_LOW;
 const DRIVING_ACTIVE: i32 = wiringpi::DIGITAL_HIGH;
 const SAFETY_NO: i32 = wiringpi::DIGITAL_LOW;
 const SAFETY_GO: i32 = wiringpi::DIGITAL_HIGH;
-const INPUT_MIN: u8 = u8::MIN;
-const INPUT_MAX: u8 = u8::MAX;
-const OUTPUT_MIN: u8 = u8::MIN;
-const OUTPUT_MAX: u8 = 100;
-const SCALE: f64 = (OUTPUT_MAX as f64 - OUTPUT_MIN as f64) / (INPUT_MAX as f64 - INPUT_MIN as f64);
-const UPDATE_TICKS: u8 = 6;
-const MAX_SPEED_CHANGE_PER_CYCLE: u8 = 1;
+
+/// Where the hardware starts out: braked, pointing forward, and with the safety relay closed, ready to go.
+const STARTUP: [OutputCommand; 3] = [
+  OutputCommand::Direction(MotorDirection::Forward),
+  OutputCommand::Brake(true),
+  OutputCommand::Safety(true)
+];
+
+/// Pulls the motor controller off and discharges the motor to the braking resistor, as hard and as fast as we can. The
+/// PWM goes to its safe level first, so the brake is never fighting the motor controller.
+const HARD_STOP: [OutputCommand; 3] = [
+  OutputCommand::SafeOutput,
+  OutputCommand::Brake(true),
+  OutputCommand::Safety(false)
+];
 
 /// There's effectively two high level loops running in this process:
 ///
@@ -71,9 +106,56 @@ const MAX_SPEED_CHANGE_PER_CYCLE: u8 = 1;
 async fn main() -> Result<(), &'static str> {
   let args = cli::Args::parse();
 
+  if let Some(command) = &args.command {
+    match command {
+      cli::Command::PwmChips => return list_pwm_chips(),
+      cli::Command::Curve { step } => {
+        print_curve(&args.response_curve, &args.duty_limits()?, *step);
+        return Ok(());
+      },
+
+      // A scenario runs the whole windmill, with the scenario standing in for the console.
+      cli::Command::Scenario { hardware, .. } if *hardware && args.simulate => {
+        return Err("a scenario can't run on the hardware and the simulation at the same time");
+      },
+      cli::Command::Scenario { .. } => {}
+    }
+  }
+
   println!("We're off to see the wizard...");
-  wiringpi::init()?;
-  ola::ensure_patches_exist(args.universe).await?;
+  let simulation = if args.simulating() {
+    let simulation = Arc::new(physics::Simulation::new(args.rotor_model()?, args.simulate_trace, Instant::now()));
+    tokio::spawn(report_simulated_speed(simulation.clone()));
+    Some(simulation)
+  }
+
+  else {
+    wiringpi::init()?;
+    None
+  };
+
+  // A replay, a scenario or listening for ourselves stands in for OLA entirely, so there's no need to wait around for it.
+  if args.replay.is_none() && args.pcap.is_none() && args.listen.is_none() && args.scenario().is_none() {
+    ola::ensure_patches_exist(args.universe).await?;
+  }
+
+  // The hardware is shared between the windmill task (which drives it) and the shutdown path (which needs to be able to
+  // force it back to a safe level on the way out, since `std::process::exit` never gives it a chance to `Drop`). Both
+  // get at it through the same executor, whether that's the GPIO pins and PWM or the simulation.
+  let duty_limits = args.duty_limits()?;
+  let output_stage = Arc::new(output::OutputStage::new(init_pwm(&args, simulation.as_ref())?, duty_limits));
+  let relays: Arc<dyn output::Relays> = match &simulation {
+    Some(simulation) => simulation.clone(),
+    None => Arc::new(GpioRelays::new())
+  };
+  let executor = Arc::new(output::Executor::new(relays, output_stage.clone(), args.audit_outputs));
+  let windmill_executor = executor.clone();
+  let motion_profile = args.motion_profile()?;
+  let response_curve = args.response_curve.clone();
+  let control = control::ControlState::new(!args.require_arm, args.stop_mode);
+  let conditioner = conditioning::Conditioner::new(args.personality(), args.conditioning_profile());
+  let sensor = init_sensor(&args, simulation.as_ref())?;
+  let presets = presets::Presets::load(args.preset_file.clone())?;
 
   // For the two systems to communicate, we set up an unbounded channel for `Windmill` state messages to be passed from
   // one end to the other. This channel is convenient because we only need one-way message passing: from the OLA
@@ -81,90 +163,163 @@ async fn main() -> Result<(), &'static str> {
   // messages quickly enough that there's no need to handle backpressure. Our OrangePi is probably insanely over-powered
   // for this, but this multi-threaded two-loop system is also part of what makes managing this lack of backpressure
   // possible in the first place.
-  let (tx, mut rx) = mpsc::unbounded_channel::<Windmill>();
+  let (tx, rx) = mpsc::unbounded_channel::<Cue>();
 
-  // Start up an OpenLightingArchitecture client and pass the transmission end ownership over to it.
-  let ola_task = tokio::task::spawn_blocking(move || {
-    // Once start is called here, this task should never return. Under the hood it will call `Run` on the underlying
-    // receive server. If this task returns, our fixture has failed.
-    ola::start(tx, args.universe, args.speed_channel, args.direction_channel)
-  });
+  // Preset requests from the local API and OSC are handed to the windmill task, since that's where the live state is. If
+  // there's neither, the sending end is simply dropped and the windmill task never hears anything.
+  let (preset_tx, preset_rx) = mpsc::unbounded_channel::<presets::PresetMessage>();
 
-  // Start another process for the receiving end, which will use the OrangePi's physical GPIO pins to dive a PWM signal
-  // for motor speed and other digital state signals. This task is also always listening, and should never return.
-  let windmill_task = tokio::spawn(async move {
-    wiringpi::pin_mode(BRAKE_PIN, wiringpi::PIN_MODE_OUTPUT);
-    wiringpi::pin_mode(MOTOR_DIRECTION_PIN, wiringpi::PIN_MODE_OUTPUT);
-    wiringpi::pin_mode(FORWARD_DRIVING_PIN, wiringpi::PIN_MODE_OUTPUT);
-    wiringpi::pin_mode(REVERSE_DRIVING_PIN, wiringpi::PIN_MODE_OUTPUT);
-    wiringpi::pin_mode(SAFETY_PIN, wiringpi::PIN_MODE_OUTPUT);
-    set_direction_forward();
-    set_brake(BRAKE_STOP);
-    set_safety(SAFETY_GO);
+  // The windmill task keeps everyone else up to date on where it is, for anything that wants to keep an eye on it.
+  let (state_tx, state_rx) = watch::channel(Windmill::Off);
 
-    let driver = pwm::init(0, 0, 20000)?;
+  // Timeline playback feeds the very same channel the console does, and takes its orders from the API.
+  let playback_tx = match (&args.timeline, args.timecode_source()) {
+    // Chasing timecode, the timeline follows the show's clock rather than its own, so there's nothing to play or pause.
+    (Some(path), Some(source)) => {
+      let freewheel = Duration::try_from_secs_f64(args.timecode_freewheel)
+        .map_err(|_| "the timecode freewheel can't be negative")?;
+      let chase = timecode::Chase::new(playback::Timeline::load(path)?, freewheel);
+      let tx = tx.clone();
 
-    let mut desired_state = Windmill::Off;
-    let mut current_state = Windmill::Off;
-    let mut tick = 0u8;
+      tokio::spawn(async move {
+        if let Err(why) = timecode::run(source, chase, tx).await {
+          eprintln!("Timecode chase stopped: {why}");
+        }
+      });
 
-    loop {
-      // Non-blocking, non-sleeping receive call, so we can continue to emit a full pulse at whatever frequency we're
-      // currently emitting at. In this portion of the loop, all we're doing is updating the system's desired state to
-      // be whatever we've most recently received from the controller.
-      match rx.try_recv() {
-        // Awesome! Some work to do!
-        Ok(value) => desired_state = value,
-
-        // This ain't good...
-        Err(TryRecvError::Disconnected) => return Err("windmill lost connection to incoming DMX messages."),
-
-        // This is actually okay. It's fine if no messages have come in. Some controllers will continuously output the
-        // current desired state of the system, but they may only happen every second or so. We'll get a lot of "nothing
-        // to do" responses.
-        //
-        // However, we shouldn't break here. Our system still may not be in the desired state, so this just means we
-        // don't need to update that desired state.
-        Err(TryRecvError::Empty) => {}
+      None
+    },
+
+    (Some(path), None) => {
+      let mut playback = playback::Playback::new(playback::Timeline::load(path)?);
+      let (playback_tx, playback_rx) = mpsc::unbounded_channel::<playback::PlaybackMessage>();
+      let tx = tx.clone();
+
+      if !args.timeline_paused {
+        playback.command(playback::PlaybackCommand::Play, Instant::now());
       }
 
-      // Simple tick counter that will act as a linear easing function between state updates. We do this _after_ the
-      // desired state so that we're always easing to the most recently desired state and don't get caught lagging
-      // behind.
-      tick = (tick + 1) % UPDATE_TICKS;
+      tokio::spawn(async move {
+        if let Err(why) = playback::run(playback, tx, playback_rx).await {
+          eprintln!("Playback stopped: {why}");
+        }
+      });
+
+      Some(playback_tx)
+    },
 
-      if tick != 0 {
-        continue;
+    (None, _) => None
+  };
+
+  if let Some(address) = args.osc_address {
+    let preset_tx = preset_tx.clone();
+
+    tokio::spawn(async move {
+      // Same as the API, losing OSC is no reason to stop the windmill.
+      if let Err(why) = osc::listen(address, preset_tx).await {
+        eprintln!("OSC stopped: {why}");
       }
+    });
+  }
 
-      // Now we need to reconcile the current state with the desired state.
-      let new_state = state_change_evaluator(current_state, desired_state);
+  if let Some(path) = args.api_socket.clone() {
+    tokio::spawn(async move {
+      // The API going down is a shame, but it's no reason to stop the windmill.
+      if let Err(why) = api::serve(path, preset_tx, playback_tx).await {
+        eprintln!("The local API stopped: {why}");
+      }
+    });
+  }
 
-      if new_state != current_state {
-        let duty_cycle = match new_state {
-          Windmill::Off | Windmill::Cooldown(_) => 0,
-          Windmill::Forward(speed) | Windmill::Reverse(speed) => {
-            let scale = (OUTPUT_MIN as f64 + ((speed as f64 - INPUT_MIN as f64) * SCALE)) as u8;
-            println!("Received {speed}, scaling to: {scale}");
+  // Start up an OpenLightingArchitecture client and pass the transmission end ownership over to it. Or, if we're
+  // replaying a recording or a capture, feed that in instead, in which case this task finishes when the replay does.
+  // Or, if we've been told to listen for the console ourselves, do that. Or if we're running a scenario, that's the
+  // console now, and this task finishes when the scenario does.
+  let replay = match (&args.replay, &args.pcap) {
+    (Some(path), _) => Some((recording::load(path)?, false)),
+    (_, Some(path)) => Some((pcap::load(path, args.universe, &conditioner.personality().channels())?, true)),
+    (None, None) => None
+  };
 
-            scale
-          }
+  let input_task = match (args.scenario(), replay) {
+    (Some((path, target)), _) => {
+      let scenario = scenario::Scenario::load(path)?;
 
-        };
+      if let scenario::Target::Hardware { maximum_duty } = target {
+        await_go_ahead(&scenario, maximum_duty).await?;
+      }
 
-        // Specifically do not break on this particular error.
-        if let Err(why) = driver.set_duty_cycle(duty_cycle) {
-          eprintln!("{}", why);
-        }
+      let sensor = sensor.clone();
+      let look = move || observe(&output_stage, *state_rx.borrow(), sensor.as_deref(), simulation.as_deref());
+      tokio::spawn(scenario::run(scenario, conditioner, tx, look, target != scenario::Target::Simulated))
+    },
 
-        current_state = new_state;
+    (None, Some((frames, report))) => {
+      if args.replay_speed <= 0.0 {
+        return Err("the replay speed has to be more than zero");
       }
 
-      // We're not going to be able to get more granular than this anyway, and updating the state every 10ms, especially
-      // when factoring in acceleration/deceleration/state easing... is completely indistinguishable from realtime busy
-      // waiting.
-      tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+      let speed = args.replay_speed;
+
+      tokio::spawn(async move {
+        recording::replay(frames, speed, conditioner, tx.clone(), report).await?;
+        wind_down(&tx, state_rx).await
+      })
+    },
+
+    (None, None) => {
+      let recorder = args.record
+        .as_deref()
+        .map(|path| recording::Recorder::create(path, conditioner.personality().channels()))
+        .transpose()?;
+
+      match args.listen {
+        Some(address) => tokio::spawn(network::listen(address, args.universe, conditioner, recorder, tx)),
+
+        None => tokio::task::spawn_blocking(move || {
+          // Once start is called here, this task should never return. Under the hood it will call `Run` on the
+          // underlying receive server. If this task returns, our fixture has failed.
+          ola::start(tx, args.universe, conditioner, recorder)
+        })
+      }
     }
+  };
+
+  // Start another process for the receiving end, which will use the OrangePi's physical GPIO pins to dive a PWM signal
+  // for motor speed and other digital state signals. This task is also always listening, and should never return.
+  let windmill_task = tokio::spawn(async move {
+    windmill_executor.execute(&STARTUP)?;
+
+    // Gusts only need to look random, but they shouldn't look the same every time the windmill is switched on.
+    let seed = std::time::SystemTime::now()
+      .duration_since(std::time::UNIX_EPOCH)
+      .map(|since_epoch| since_epoch.as_nanos() as u64)
+      .unwrap_or_default();
+
+    let clock = clock::TokioClock;
+    let mut motion = Motion::new(motion_profile, sensor, clock.now());
+    motion.set_stop_mode(control.default_stop_mode());
+
+    let control_loop = ControlLoop {
+      cues: rx,
+      preset_requests: preset_rx,
+      executor: windmill_executor,
+      duty_limits,
+      response_curve,
+      control,
+      presets,
+      motion,
+      effects: effects::EffectEngine::new(seed, clock.now()),
+      console_cue: Cue::from(Windmill::Off),
+      playback_cue: None,
+      live_cue: Cue::from(Windmill::Off),
+      desired_state: Windmill::Off,
+      current_state: Windmill::Off,
+      current_duty_cycle: 0,
+      state_updates: state_tx
+    };
+
+    control_loop.run(&clock).await
   });
 
   // Establishes the set of signals one should listen to in a long-running process to gracefully handle most types of
@@ -178,171 +333,1053 @@ async fn main() -> Result<(), &'static str> {
   // So here's the thing: if we've done our job correctly, neither of these processes will die, and we'll be happy
   // campers. If something goes wrong, `select!` will make sure that the first thing to die quickly kills the rest of
   // the program and returns that error as the application error.
+  //
+  // Either of those processes dying is a fault, and a fault always means a hard stop on the way out. The one exception
+  // is a replay coming to an end, which is checked first so that the windmill noticing its input went away doesn't get
+  // reported as the reason we stopped. By then the replay has already brought the windmill to a stop the ordinary way,
+  // so the hard stop only has a stopped rotor to hold.
   select! {
-    ola_err = ola_task => ola_err.map_err(|_| "OpenLightingArchitecture thread panicked!")?,
-    windmill_err = windmill_task => windmill_err.map_err(|_| "Windmill thread panicked!")?,
-    _ = ctrl_c => graceful_shutdown(),
-    _ = terminate.recv() => graceful_shutdown(),
-    _ = interrupt.recv() => graceful_shutdown()
+    biased;
+    input_err = input_task => fault_stop(&executor, input_err.unwrap_or(Err("DMX input thread panicked!"))),
+    windmill_err = windmill_task => fault_stop(&executor, windmill_err.unwrap_or(Err("Windmill thread panicked!"))),
+    _ = ctrl_c => graceful_shutdown(&executor),
+    _ = terminate.recv() => graceful_shutdown(&executor),
+    _ = interrupt.recv() => graceful_shutdown(&executor)
+  }
+}
+
+/// How often the windmill task goes around its loop. We're not going to be able to get more granular than this anyway,
+/// and updating the state every 10ms, especially when factoring in acceleration/deceleration/state easing... is
+/// completely indistinguishable from realtime busy waiting.
+const TICK: Duration = Duration::from_millis(10);
+
+/// `ControlLoop` is the windmill task: everything it needs to remember from one trip around its loop to the next, and
+/// the loop itself. It never looks at the time on its own. Each trip is handed the time it's happening at, and the loop
+/// sleeps on whichever `Clock` it's run with, so that tests can step it through ramps and cool downs to the exact
+/// instant rather than sitting around waiting for them.
+struct ControlLoop {
+  /// Cues from the console, playback, or whatever else is feeding the windmill.
+  cues: mpsc::UnboundedReceiver<Cue>,
+
+  /// Requests from the local API for the presets.
+  preset_requests: mpsc::UnboundedReceiver<presets::PresetMessage>,
+
+  /// Carries out whatever the state machine decides on the hardware.
+  executor: Arc<output::Executor>,
+
+  /// Where duty cycles start and end, and the dead band.
+  duty_limits: output::DutyLimits,
+
+  /// How speeds map onto duty cycles.
+  response_curve: curve::ResponseCurve,
+
+  /// Arming, faults and the default stop mode.
+  control: control::ControlState,
+
+  /// The on-device presets.
+  presets: presets::Presets,
+
+  /// Ramps, kick-starts and cool downs.
+  motion: Motion,
+
+  /// Motion effects laid over the console's speed.
+  effects: effects::EffectEngine,
+
+  /// The latest cue from the console.
+  console_cue: Cue,
+
+  /// The latest cue from playback, if there's been one.
+  playback_cue: Option<Cue>,
+
+  /// The cue the windmill is actually following.
+  live_cue: Cue,
+
+  /// Where the windmill has been asked to be, before effects and arming get a say.
+  desired_state: Windmill,
+
+  /// Where the windmill is.
+  current_state: Windmill,
+
+  /// The duty cycle last written to the driver.
+  current_duty_cycle: u8,
+
+  /// Where the windmill is, for anyone watching.
+  state_updates: watch::Sender<Windmill>
+}
+
+impl ControlLoop {
+  /// Goes around the loop forever (or until something goes badly wrong), taking the time from `clock`.
+  async fn run(mut self, clock: &impl Clock) -> Result<(), &'static str> {
+    loop {
+      self.step(clock.now())?;
+      clock.sleep(TICK).await;
+    }
+  }
+
+  /// Makes one trip around the loop at `now`: takes in whatever cues and requests have arrived, then moves the windmill
+  /// one step closer to where it should be.
+  fn step(&mut self, now: Instant) -> Result<(), &'static str> {
+    // Non-blocking, non-sleeping receive call, so we can continue to emit a full pulse at whatever frequency we're
+    // currently emitting at. In this portion of the loop, all we're doing is updating the system's desired state to be
+    // whatever we've most recently received from the controller.
+    let mut cue_changed = false;
+
+    match self.cues.try_recv() {
+      // Awesome! Some work to do! Any control command goes first (and only once), since it might change the default
+      // stop mode.
+      Ok(cue) if cue.source == CueSource::Console => {
+        if let Some(command) = cue.control {
+          self.control.apply(command, self.current_state == Windmill::Off, now);
+        }
+
+        self.console_cue = cue;
+        cue_changed = true;
+      },
+
+      // Playback gets remembered separately, since live DMX might be speaking over it.
+      Ok(cue) => {
+        self.playback_cue = Some(cue);
+        cue_changed = true;
+      },
+
+      // This ain't good... and it's a fault, so stop hard rather than however the last cue said to.
+      Err(TryRecvError::Disconnected) => {
+        self.executor.execute(&[OutputCommand::SafeOutput, OutputCommand::Brake(true)]).ok();
+        return Err("windmill lost connection to incoming DMX messages.");
+      },
+
+      // This is actually okay. It's fine if no messages have come in. Some controllers will continuously output the
+      // current desired state of the system, but they may only happen every second or so. We'll get a lot of "nothing
+      // to do" responses.
+      //
+      // However, we shouldn't break here. Our system still may not be in the desired state, so this just means we
+      // don't need to update that desired state.
+      Err(TryRecvError::Empty) => {}
+    }
+
+    // The local API might want to recall or capture a preset too. Whatever it asks for, the answer goes straight back.
+    while let Ok((request, reply)) = self.preset_requests.try_recv() {
+      reply.send(self.presets.handle(request, &self.live_cue)).ok();
+      cue_changed = true;
+    }
+
+    // Live DMX takes over from playback whenever it's asking for anything, and playback picks up again once the
+    // console goes quiet. A preset may be standing in for either. Whichever way, the cue's stop mode (or our default,
+    // if the console doesn't care) and fade time are handed over right away so that they apply to whatever change this
+    // very cue might be asking for.
+    if cue_changed {
+      let wanted = match self.playback_cue {
+        Some(playback_cue) if self.console_cue.is_idle() => playback_cue,
+        _ => self.console_cue
+      };
+
+      self.live_cue = self.presets.resolve(wanted);
+      self.desired_state = self.duty_limits.apply_dead_band(self.live_cue.windmill);
+      self.motion.set_stop_mode(self.live_cue.stop_mode.unwrap_or(self.control.default_stop_mode()));
+      self.motion.set_fade_time(self.live_cue.fade);
+      self.effects.set_effect(self.live_cue.effect, now);
+    }
+
+    // Effects are laid over the console's speed here, before the ramp, so they can never push the windmill harder than
+    // it's allowed to go, and never down into the dead band where it would be moving with no duty cycle behind it. Then
+    // the console only gets what it asks for if the windmill is armed and healthy. A fault always stops hard, whatever
+    // the cue said.
+    if self.control.fault().is_some() {
+      self.motion.set_stop_mode(StopMode::HardBrake);
+    }
+
+    let slowest = self.duty_limits.dead_band.saturating_add(1);
+    let allowed_state = self.control.gate(self.effects.apply(self.desired_state, slowest, now), now);
+
+    // Now we need to reconcile the current state with the desired state. Ramping is driven by how much time has
+    // actually passed, not by how many times we've been around this loop, since the sleep between trips is only a lower
+    // bound and the writes to the hardware take however long they take.
+    let (next_state, mut commands) = state_change_evaluator(self.current_state, allowed_state, &mut self.motion, now);
+    self.current_state = next_state;
+    self.state_updates.send_replace(self.current_state);
+
+    // The duty cycle mostly follows the state, but not entirely: a kick-start drives the motor harder than its speed
+    // would suggest for a little while. So rather than only writing when the state changes, write whenever the duty
+    // cycle we want is different from the one we've got.
+    let duty_cycle = match (self.current_state, self.motion.kick_duty(now)) {
+      (Windmill::Off | Windmill::Cooldown(_), _) => 0,
+      (Windmill::Forward(_) | Windmill::Reverse(_), Some(kick_duty)) => kick_duty,
+      (Windmill::Forward(speed) | Windmill::Reverse(speed), None) => {
+        let scale = self.duty_limits.duty_cycle(&self.response_curve, speed);
+
+        if scale != self.current_duty_cycle {
+          match self.motion.fade_progress() {
+            Some(progress) => println!("Received {speed}, scaling to: {scale} (fade {:.0}%)", progress * 100.0),
+            None => println!("Received {speed}, scaling to: {scale}")
+          }
+        }
+
+        scale
+      }
+    };
+
+    reconcile_duty_cycle(&mut commands, &mut self.current_duty_cycle, duty_cycle);
+
+    // Specifically do not break on this particular error. But we've lost control of the motor, so latch a fault and
+    // bring the windmill to a stop until someone has had a look and reset it.
+    if let Err(why) = self.executor.execute(&commands) {
+      eprintln!("{}", why);
+      self.control.latch_fault(why);
+    }
+
+    Ok(())
+  }
+}
+
+/// Checks with whoever is standing next to the windmill before a scenario gets to move it, since nothing in a scenario
+/// can see whether there's a ladder in the way.
+async fn await_go_ahead(scenario: &scenario::Scenario, maximum_duty: u8) -> Result<(), &'static str> {
+  println!(
+    "About to run {} steps over {:.1}s on the real windmill, driving the motor at no more than {maximum_duty}%.",
+    scenario.steps.len(),
+    scenario.duration().as_secs_f64()
+  );
+  println!("Stand clear, keep a hand on the stop, and press enter to start (or Ctrl-C to back out).");
+
+  let answered = tokio::task::spawn_blocking(|| std::io::stdin().read_line(&mut String::new()))
+    .await
+    .map_err(|_| "failed to wait for the go-ahead")?;
+
+  match answered {
+    Ok(read) if read > 0 => Ok(()),
+    _ => Err("the scenario never got the go-ahead")
+  }
+}
+
+/// Takes a look at the windmill for a scenario: `state` from the windmill task, the duty cycle from `output`, and the
+/// relays, direction and rotor speed from `simulation`, or failing that from the pins and `sensor`.
+fn observe(
+  output: &output::OutputStage,
+  state: Windmill,
+  sensor: Option<&dyn sensor::SpeedSensor>,
+  simulation: Option<&physics::Simulation>
+) -> scenario::Observation {
+  let observation = scenario::Observation {
+    state,
+    duty_cycle: output.duty_cycle(),
+    brake: false,
+    reverse: false,
+    powered: false,
+    rpm: None
+  };
+
+  if let Some(simulation) = simulation {
+    let drive = simulation.drive();
+
+    return scenario::Observation {
+      brake: drive.brake,
+      reverse: drive.reverse,
+      powered: drive.powered,
+      rpm: Some(simulation.signed_rpm()),
+      ..observation
+    };
+  }
+
+  // An output pin reads back whatever was last written to it. A sensor can't tell which way the rotor is turning, so
+  // that's taken from the direction pin.
+  let reverse = wiringpi::digital_read(MOTOR_DIRECTION_PIN) == MOTOR_DIRECTION_REVERSE;
+
+  scenario::Observation {
+    brake: wiringpi::digital_read(BRAKE_PIN) == BRAKE_STOP,
+    reverse,
+    powered: wiringpi::digital_read(SAFETY_PIN) == SAFETY_GO,
+    rpm: sensor.map(|sensor| if reverse { -sensor.rpm() } else { sensor.rpm() }),
+    ..observation
   }
 }
 
-fn state_change_evaluator(current_state: Windmill, desired_state: Windmill) -> Windmill {
+/// Prints the simulated rotor speed every so often, whenever it's changed enough to be worth mentioning.
+async fn report_simulated_speed(simulation: Arc<physics::Simulation>) {
+  let mut reported = 0.0;
+
+  loop {
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    let rpm = simulation.signed_rpm();
+
+    if (rpm - reported).abs() >= 0.5 || (rpm == 0.0 && reported != 0.0) {
+      println!("[simulated] rotor at {rpm:.1} rpm");
+      reported = rpm;
+    }
+  }
+}
+
+/// Sets up whichever PWM output the arguments ask for. Hardware PWM is the default; software PWM is only used when
+/// explicitly requested, since silently falling back to it would hand the motor a much worse signal than expected.
+fn init_pwm(
+  args: &cli::Args,
+  simulation: Option<&Arc<physics::Simulation>>
+) -> Result<Arc<dyn pwm::Output>, &'static str> {
+  if let Some(simulation) = simulation {
+    println!("Simulating, so there's no PWM to drive.");
+    return Ok(simulation.clone());
+  }
+
+  if let Some(pin) = args.soft_pwm_pin {
+    if [BRAKE_PIN, MOTOR_DIRECTION_PIN, FORWARD_DRIVING_PIN, REVERSE_DRIVING_PIN, SAFETY_PIN].contains(&pin) {
+      return Err("the software pwm pin is already used by the windmill for something else");
+    }
+
+    println!("Driving software PWM on pin {pin}. Expect jitter!");
+    return Ok(Arc::new(pwm::soft::SoftDriver::new(pin)?));
+  }
+
+  let driver = pwm::init(args.pwm_chip, args.pwm_channel, args.pwm_frequency, args.pwm_polarity)?;
+
+  if driver.is_logically_inverted() {
+    println!("PWM polarity could not be set, inverting duty cycles in software instead.");
+  }
+
+  Ok(Arc::new(driver))
+}
+
+/// Starts up the rotation sensor, if one has been configured.
+fn init_sensor(
+  args: &cli::Args,
+  simulation: Option<&Arc<physics::Simulation>>
+) -> Result<Option<Arc<dyn sensor::SpeedSensor>>, &'static str> {
+  if let Some(simulation) = simulation.filter(|_| args.simulated_sensor) {
+    return Ok(Some(simulation.clone()));
+  }
+
+  let Some(pin) = args.sensor_pin else {
+    return Ok(None);
+  };
+
+  if [BRAKE_PIN, MOTOR_DIRECTION_PIN, FORWARD_DRIVING_PIN, REVERSE_DRIVING_PIN, SAFETY_PIN].contains(&pin)
+    || args.soft_pwm_pin == Some(pin) {
+    return Err("the speed sensor pin is already used by the windmill for something else");
+  }
+
+  let sensor = sensor::PulseSensor::new(pin, args.sensor_pulses_per_revolution, args.sensor_stopped_after)?;
+  Ok(Some(Arc::new(sensor)))
+}
+
+/// Prints out what a response curve (within the given duty cycle limits) will do with every `step`th DMX value (and
+/// always the very last one), without going anywhere near the hardware. The little bar chart makes it a lot easier to
+/// eyeball the shape of a lookup table.
+fn print_curve(curve: &curve::ResponseCurve, limits: &output::DutyLimits, step: u8) {
+  println!("Response curve: {curve}");
+  println!("Duty cycle: {}% to {}%, dead band up to {}", limits.minimum, limits.maximum, limits.dead_band);
+  println!("  dmx  duty");
+
+  let step = std::cmp::max(step, 1) as usize;
+
+  for speed in (0..=u8::MAX).filter(|speed| (*speed as usize).is_multiple_of(step) || *speed == u8::MAX) {
+    let duty_cycle = limits.duty_cycle(curve, speed);
+    println!("  {speed:>3}  {duty_cycle:>3}%  {}", "#".repeat(duty_cycle as usize / 2));
+  }
+}
+
+/// Prints out every PWM chip we can find, along with which of its channels are already exported. This is mostly useful
+/// when bringing up a new board, where the chip you want is rarely `pwmchip0`.
+fn list_pwm_chips() -> Result<(), &'static str> {
+  let chips = pwm::PwmChip::enumerate()?;
+
+  if chips.is_empty() {
+    println!("No pwm chips found: is a pwm overlay enabled on your hardware?");
+  }
+
+  for chip in chips {
+    let exported = (0..chip.channel_count())
+      .filter(|channel| chip.is_exported(*channel))
+      .map(|channel| channel.to_string())
+      .collect::<Vec<_>>();
+
+    println!(
+      "pwmchip{}: {} channel(s), device: {}, exported: [{}]",
+      chip.index(),
+      chip.channel_count(),
+      chip.device().unwrap_or("unknown"),
+      exported.join(", ")
+    );
+  }
+
+  Ok(())
+}
+
+/// Makes sure `commands` leave the PWM output at `wanted`, given that it was at `current` before them, and keeps
+/// `current` up to date. The state machine zeroes the duty cycle itself whenever it stops, so whatever it's already
+/// asked for counts as written rather than being written twice.
+fn reconcile_duty_cycle(commands: &mut Vec<OutputCommand>, current: &mut u8, wanted: u8) {
+  let written = commands.iter().rev().find_map(|command| match command {
+    OutputCommand::DutyCycle(duty_cycle) => Some(*duty_cycle),
+    _ => None
+  });
+
+  if let Some(written) = written {
+    *current = written;
+  }
+
+  if wanted != *current {
+    commands.push(OutputCommand::DutyCycle(wanted));
+    *current = wanted;
+  }
+}
+
+/// Works out the next state of the windmill as of `now`, given where it is now and where the operator wants it to be.
+/// Speed changes are eased (and motors kick-started) by `motion`, which keeps track of how much time has passed between
+/// evaluations.
+///
+/// This never touches the hardware itself. Along with the next state comes whatever needs doing to the relays to get
+/// there, in the order it needs doing, for an `output::Executor` to carry out (or just print, or check over). The duty
+/// cycle is mostly left to the caller, since it depends on more than the state. The exception is stopping: the duty
+/// cycle goes to zero before the brake goes on or the safety relay opens, so the brake never fights a driven motor.
+fn state_change_evaluator(
+  current_state: Windmill,
+  desired_state: Windmill,
+  motion: &mut Motion,
+  now: Instant
+) -> (Windmill, Vec<OutputCommand>) {
+  let elapsed = motion.elapsed(now);
+
   match (current_state, desired_state) {
     // You want the windmill off? It's off already!
-    (Windmill::Off, Windmill::Off) => Windmill::Off,
+    (Windmill::Off, Windmill::Off) => (Windmill::Off, vec![]),
 
-    // Begin the cool down process after rapidly braking. This generally takes under a second.
-    (Windmill::Cooldown(cycles), _) if cycles > 0 => Windmill::Cooldown(cycles - 1),
+    // Begin the cool down process after stopping. How long this takes depends on how fast we were going, and if there's
+    // a sensor, we don't take the model's word for it: we wait for the rotor to actually stop, too.
+    (Windmill::Cooldown(remaining), _) if remaining > elapsed || motion.is_rotor_moving() =>
+      (Windmill::Cooldown(remaining.saturating_sub(elapsed)), vec![]),
 
-    // The cool down process has completed, back to normal operation.
-    (Windmill::Cooldown(_), _) => Windmill::Off,
+    // The cool down process has completed, back to normal operation. If we got here by coasting, the brake was never
+    // applied and the safety relay was opened, so put both back where `Off` expects them to be. The rotor has stopped
+    // by now (the sensor said so, or the coast cool down was sized for a full spin-down), so the brake isn't being
+    // slammed on to a turning prop.
+    (Windmill::Cooldown(_), _) => (Windmill::Off, vec![OutputCommand::Brake(true), OutputCommand::Safety(true)]),
 
     // It's never desirable to be in the cool down state, it should only ever be a present state. If this somehow
     // happens, which we should be able to assert that it won't: we're broken somewhere. We can't actually fix it though
     // in this context, so just try to get the windmill off.
-    (_, Windmill::Cooldown(_)) => Windmill::Off,
+    (_, Windmill::Cooldown(_)) => (Windmill::Off, vec![]),
 
     // When going from off to on, we need to enable the brake/run relay and set our direction pin. We won't actually
     // worry about setting the speed yet -- that's easier to just let happen as a part of the next cycle (remember
-    // this is happening every 10ms). To make this happen, we'll actually set the current state to `Forward(0)`.
+    // this is happening every 10ms). To make this happen, we'll actually set the current state to `Forward(0)`. If
+    // the motor needs a kick to get going, that starts now too.
     (Windmill::Off, Windmill::Forward(_)) => {
-      set_direction_forward();
-      set_brake(BRAKE_RUN);
+      motion.start(now);
 
-      Windmill::Forward(0)
+      (Windmill::Forward(0), vec![OutputCommand::Direction(MotorDirection::Forward), OutputCommand::Brake(false)])
     },
 
     // Going in reverse is the same as going forward, but we swap the braking circuit (direction) pin polarity. This
     // will also run the motor controller in reverse.
     (Windmill::Off, Windmill::Reverse(_)) => {
-      set_direction_reverse();
-      set_brake(BRAKE_RUN);
+      motion.start(now);
 
-      Windmill::Reverse(0)
+      (Windmill::Reverse(0), vec![OutputCommand::Direction(MotorDirection::Reverse), OutputCommand::Brake(false)])
     },
 
-    // When going exactly as fast as you want to be going, you're winning!
-    (Windmill::Forward(current), Windmill::Forward(desired)) if current == desired =>
-      Windmill::Forward(current),
+    // Already spinning the right way? Then it's just a matter of getting to the right speed. Too slow, hit the gas; too
+    // fast, slow it down brother! The ramp takes care of how quickly either of those is allowed to happen, and will
+    // settle exactly on the desired speed (which is winning!) rather than bouncing around it. It also waits for any
+    // kick-start to finish before it gets going.
+    (Windmill::Forward(_), Windmill::Forward(desired)) =>
+      (Windmill::Forward(motion.step(desired, elapsed, now)), vec![]),
+
+    // Same thing when we're spinning in reverse.
+    (Windmill::Reverse(_), Windmill::Reverse(desired)) =>
+      (Windmill::Reverse(motion.step(desired, elapsed, now)), vec![]),
 
-    // Same thing when we're spinning in reverse exactly as fast as we want to be.
-    (Windmill::Reverse(current), Windmill::Reverse(desired)) if current == desired =>
-      Windmill::Reverse(current),
+    // If we're going and we want to stop, stop however we've been asked to. See `stop` for the gory details.
+    (_, Windmill::Off) => stop(current_state, motion, elapsed, now),
 
-    // When going too fast, slow down. We need to clamp this to the desired value to fall into the branches above next
-    // cycle, otherwise if MAX_SPEED_CHANGE_PER_CYCLE != 1 we may bounce back and forth but never settle on the desired
-    // actual speed.
-    (Windmill::Forward(current), Windmill::Forward(desired)) if current > desired =>
-      Windmill::Forward(std::cmp::max(current - MAX_SPEED_CHANGE_PER_CYCLE, desired)),
+    // This is potentially the trickiest set of state changes: hard switch of direction. But actually it's not as bad
+    // as it may seem. We stop exactly the same way we would if we'd been asked to turn off, and the goal of the cool
+    // down phase is to handle the rest of this transition. Once the cool down phase asses, the system shut start moving
+    // the motor in the other direction.
+    (Windmill::Forward(_), Windmill::Reverse(_)) | (Windmill::Reverse(_), Windmill::Forward(_)) =>
+      stop(current_state, motion, elapsed, now)
+  }
+}
 
-    // Spinning in reverse too quickly? Same as above, slow it down brother!
-    (Windmill::Reverse(current), Windmill::Reverse(desired)) if current > desired =>
-      Windmill::Reverse(std::cmp::max(current - MAX_SPEED_CHANGE_PER_CYCLE, desired)),
+/// Brings a moving windmill to a stop according to the current `StopMode`. The cool down that follows is sized to the
+/// speed the windmill was doing when it stopped.
+fn stop(
+  current_state: Windmill,
+  motion: &mut Motion,
+  elapsed: Duration,
+  now: Instant
+) -> (Windmill, Vec<OutputCommand>) {
+  let speed = match current_state {
+    Windmill::Forward(speed) | Windmill::Reverse(speed) => speed,
+    Windmill::Off | Windmill::Cooldown(_) => 0
+  };
 
-    // If we're not at the right speed, and we're not going too fast, we must need to accelerate. Same general principle
-    // as slowing down, just not going slower. Faster!
-    (Windmill::Forward(current), Windmill::Forward(desired)) =>
-      Windmill::Forward(std::cmp::min(current + MAX_SPEED_CHANGE_PER_CYCLE, desired)),
+  match (motion.stop_mode(), current_state) {
+    // Ramping down means the windmill keeps running normally (just slower and slower) until it reaches zero, at which
+    // point it falls through to the brake below. If the operator changes their mind halfway, the ramp just turns around.
+    (StopMode::RampDown, Windmill::Forward(speed)) if speed > 0 =>
+      (Windmill::Forward(motion.step(0, elapsed, now)), vec![]),
 
-    // Too slow in reverse? Hit the gas!
-    (Windmill::Reverse(current), Windmill::Reverse(desired)) =>
-      Windmill::Reverse(std::cmp::min(current + MAX_SPEED_CHANGE_PER_CYCLE, desired)),
+    (StopMode::RampDown, Windmill::Reverse(speed)) if speed > 0 =>
+      (Windmill::Reverse(motion.step(0, elapsed, now)), vec![]),
 
-    // If we're going and we want to stop, trigger the brake relay which should pull any residual momentum into the
-    // braking resistor.
-    (_, Windmill::Off) => {
-      set_brake(BRAKE_STOP);
-      Windmill::Cooldown(100)
-    }
+    // Coasting zeroes the PWM and cuts it off at the safety relay too, but leaves the brake released, so the rotor just
+    // spins down on its own. That takes a lot longer than braking does, so the cool down is longer too.
+    (StopMode::Coast, _) => {
+      motion.stop();
+      let commands = vec![OutputCommand::DutyCycle(0), OutputCommand::Safety(false)];
+      (Windmill::Cooldown(motion.cooldown(speed, StopMode::Coast)), commands)
+    },
 
-    // This is potentially the trickiest set of state changes: hard switch of direction. But actually it's not as bad
-    // as it may seem. The goal of the cool down phase is to handle this transition. Once the cool down phase asses, the
-    // system shut start moving the motor in the other direction.
-    (Windmill::Forward(_), Windmill::Reverse(_)) | (Windmill::Reverse(_), Windmill::Forward(_)) => {
-      set_brake(BRAKE_STOP);
-      Windmill::Cooldown(100)
+    // Otherwise stop driving the motor and trigger the brake relay, which should pull any residual momentum into the
+    // braking resistor.
+    _ => {
+      motion.stop();
+      let commands = vec![OutputCommand::DutyCycle(0), OutputCommand::Brake(true)];
+      (Windmill::Cooldown(motion.cooldown(speed, StopMode::HardBrake)), commands)
     }
   }
 }
 
 /// Simple clean up task for when the application is manually killed. This will turn off the brake and disable the
 /// safety which relays the PWM signal. This should pull the motor controller off and discharge the motor to the braking
-/// resistor. This isn't totally fool-proof, but at least if you hit CTRL-C in a panic it'll attempt to also panic stop
-/// the hardware.
+/// resistor. The PWM signal itself is also driven to its safe level, which depending on the polarity the driver ended
+/// up with may mean holding it enabled at a full inverted period rather than just turning it off. This isn't totally
+/// fool-proof, but at least if you hit CTRL-C in a panic it'll attempt to also panic stop the hardware.
 ///
 /// Believe it or not this is not based on a horrific incident that happened or anything, it just dawned on me that
 /// something like this would be the right thing to do and I couldn't sleep until I did it. So now it's done.
-fn graceful_shutdown() -> Result<(), &'static str> {
+fn graceful_shutdown(executor: &output::Executor) -> Result<(), &'static str> {
   println!("I'll get you my pretty!");
-  set_brake(BRAKE_STOP);
-  set_safety(SAFETY_NO);
+  executor.execute(&HARD_STOP).ok();
   std::process::exit(0)
 }
 
-#[cfg(not(test))]
-fn set_brake(value: i32) {
-  wiringpi::digital_write(BRAKE_PIN, value);
-}
+/// Asks the windmill to turn off the same way the console would, and waits until it gets there, ramping down and
+/// cooling down as usual. This is how a replay ends, so that running out of frames doesn't hard brake a spinning rotor.
+async fn wind_down(
+  cues: &mpsc::UnboundedSender<Cue>,
+  mut state: watch::Receiver<Windmill>
+) -> Result<(), &'static str> {
+  cues.send(Cue::from(Windmill::Off)).map_err(|_| "lost connection to the windmill while winding down")?;
 
-#[cfg(test)]
-fn set_brake(value: i32) {
-  // no-op for testing
-}
+  // The windmill publishes its state every time around the loop, so only updates from after the cue went out count.
+  // An `Off` from before then might just be a windmill that hadn't got to the last few frames yet.
+  state.borrow_and_update();
 
-#[cfg(not(test))]
-fn set_safety(value: i32) {
-  wiringpi::digital_write(SAFETY_PIN, value);
-}
+  loop {
+    state.changed().await.map_err(|_| "the windmill stopped before it wound down")?;
 
-#[cfg(test)]
-fn set_safety(value: i32) {
-  // no-op for testing
+    if *state.borrow_and_update() == Windmill::Off {
+      println!("Wound down.");
+      return Ok(());
+    }
+  }
 }
 
-#[cfg(not(test))]
-fn set_direction_forward() {
-  wiringpi::digital_write(MOTOR_DIRECTION_PIN, MOTOR_DIRECTION_FORWARD);
-  wiringpi::digital_write(FORWARD_DRIVING_PIN, DRIVING_ACTIVE);
-  wiringpi::digital_write(REVERSE_DRIVING_PIN, DRIVING_INACTIVE);
+/// Hard stops the hardware after something has gone wrong, then passes the error along. This is the same as what happens
+/// on a graceful shutdown (brake on, safety off, PWM to its safe level), minus the exiting.
+fn fault_stop(executor: &output::Executor, result: Result<(), &'static str>) -> Result<(), &'static str> {
+  executor.execute(&HARD_STOP).ok();
+  result
 }
 
-#[cfg(not(test))]
-fn set_direction_reverse() {
-  wiringpi::digital_write(MOTOR_DIRECTION_PIN, MOTOR_DIRECTION_REVERSE);
-  wiringpi::digital_write(FORWARD_DRIVING_PIN, DRIVING_INACTIVE);
-  wiringpi::digital_write(REVERSE_DRIVING_PIN, DRIVING_ACTIVE);
-}
+/// The brake, direction and safety relays, wired to the OrangePi's GPIO pins.
+struct GpioRelays;
 
-#[cfg(test)]
-fn set_direction_forward() {
-  // no-op for testing
+impl GpioRelays {
+  /// Sets up every pin the relays are wired to as an output.
+  fn new() -> Self {
+    for pin in [BRAKE_PIN, MOTOR_DIRECTION_PIN, FORWARD_DRIVING_PIN, REVERSE_DRIVING_PIN, SAFETY_PIN] {
+      wiringpi::pin_mode(pin, wiringpi::PIN_MODE_OUTPUT);
+    }
+
+    GpioRelays
+  }
 }
 
-#[cfg(test)]
-fn set_direction_reverse() {
-  // no-op for testing
+impl output::Relays for GpioRelays {
+  fn set_brake(&self, brake: bool) {
+    wiringpi::digital_write(BRAKE_PIN, if brake { BRAKE_STOP } else { BRAKE_RUN });
+  }
+
+  /// Besides the direction pin itself, the motor controller wants exactly one of its driving pins active.
+  fn set_direction(&self, direction: MotorDirection) {
+    let (direction, forward, reverse) = match direction {
+      MotorDirection::Forward => (MOTOR_DIRECTION_FORWARD, DRIVING_ACTIVE, DRIVING_INACTIVE),
+      MotorDirection::Reverse => (MOTOR_DIRECTION_REVERSE, DRIVING_INACTIVE, DRIVING_ACTIVE)
+    };
+
+    wiringpi::digital_write(MOTOR_DIRECTION_PIN, direction);
+    wiringpi::digital_write(FORWARD_DRIVING_PIN, forward);
+    wiringpi::digital_write(REVERSE_DRIVING_PIN, reverse);
+  }
+
+  fn set_safety(&self, safety: bool) {
+    wiringpi::digital_write(SAFETY_PIN, if safety { SAFETY_GO } else { SAFETY_NO });
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::motion::{CooldownCurve, CooldownModel, KickStart, MotionProfile};
+  use crate::ramp::RampProfile;
+
+  /// A motion profile with a ramp that takes exactly one second to get to full speed (and back), and cool downs that
+  /// take one second per full speed on top of a minimum, which makes the arithmetic easy.
+  fn profile(kick_start: Option<KickStart>) -> MotionProfile {
+    MotionProfile {
+      ramp: RampProfile {
+        acceleration: Duration::from_secs(1),
+        deceleration: Duration::from_secs(1),
+        s_curve: None
+      },
+      kick_start,
+      brake_cooldown: CooldownModel {
+        minimum: Duration::from_millis(500),
+        full_speed: Duration::from_millis(1500),
+        curve: CooldownCurve::Linear
+      },
+      coast_cooldown: CooldownModel {
+        minimum: Duration::from_secs(2),
+        full_speed: Duration::from_secs(3),
+        curve: CooldownCurve::Linear
+      }
+    }
+  }
+
+  fn motion(kick_start: Option<KickStart>, start: Instant) -> Motion {
+    Motion::new(profile(kick_start), None, start)
+  }
 
   #[test]
   fn off_to_off() {
-    assert_eq!(Windmill::Off, state_change_evaluator(Windmill::Off, Windmill::Off));
+    let start = Instant::now();
+    assert_eq!(
+      (Windmill::Off, vec![]),
+      state_change_evaluator(Windmill::Off, Windmill::Off, &mut motion(None, start), start)
+    );
   }
 
   #[test]
   fn off_to_forward() {
-    assert_eq!(Windmill::Forward(0), state_change_evaluator(Windmill::Off, Windmill::Forward(239)));
+    let start = Instant::now();
+
+    assert_eq!(
+      (Windmill::Forward(0), vec![OutputCommand::Direction(MotorDirection::Forward), OutputCommand::Brake(false)]),
+      state_change_evaluator(
+        Windmill::Off,
+        Windmill::Forward(239),
+        &mut motion(None, start),
+        start + Duration::from_millis(10)
+      )
+    );
   }
 
   #[test]
   fn forward_stopped_to_go() {
+    let start = Instant::now();
+
+    assert_eq!(
+      (Windmill::Forward(51), vec![]),
+      state_change_evaluator(
+        Windmill::Forward(0),
+        Windmill::Forward(239),
+        &mut motion(None, start),
+        start + Duration::from_millis(200)
+      )
+    );
+  }
+
+  #[test]
+  fn forward_ramps_down_by_elapsed_time() {
+    let mut now = Instant::now();
+    let mut motion = motion(None, now);
+    let mut state = Windmill::Forward(0);
+
+    for _ in 0..100 {
+      now += Duration::from_millis(10);
+      state = state_change_evaluator(state, Windmill::Forward(200), &mut motion, now).0;
+    }
+
+    assert_eq!(Windmill::Forward(200), state);
+    assert_eq!(
+      (Windmill::Forward(149), vec![]),
+      state_change_evaluator(state, Windmill::Forward(0), &mut motion, now + Duration::from_millis(200))
+    );
+  }
+
+  #[test]
+  fn kick_start_holds_the_ramp_then_hands_over() {
+    let start = Instant::now();
+    let kick_start = KickStart { duty: 60, duration: Duration::from_millis(300) };
+    let mut motion = motion(Some(kick_start), start);
+
+    let (state, _) = state_change_evaluator(Windmill::Off, Windmill::Forward(239), &mut motion, start);
+    assert_eq!(Windmill::Forward(0), state);
+    assert_eq!(Some(60), motion.kick_duty(start));
+
+    let kicking = start + Duration::from_millis(200);
+    let (state, _) = state_change_evaluator(state, Windmill::Forward(239), &mut motion, kicking);
+    assert_eq!(Windmill::Forward(0), state);
+    assert_eq!(Some(60), motion.kick_duty(kicking));
+
+    let handed_over = start + Duration::from_millis(400);
+    let (state, _) = state_change_evaluator(state, Windmill::Forward(239), &mut motion, handed_over);
+    assert_eq!(Windmill::Forward(51), state);
+    assert_eq!(None, motion.kick_duty(handed_over));
+  }
+
+  #[test]
+  fn kick_start_skipped_when_already_turning() {
+    struct Coasting;
+
+    impl sensor::SpeedSensor for Coasting {
+      fn rpm(&self) -> f64 {
+        12.0
+      }
+    }
+
+    let start = Instant::now();
+    let kick_start = KickStart { duty: 60, duration: Duration::from_millis(300) };
+    let mut motion = Motion::new(profile(Some(kick_start)), Some(Arc::new(Coasting)), start);
+
+    state_change_evaluator(Windmill::Off, Windmill::Forward(239), &mut motion, start);
+    assert_eq!(None, motion.kick_duty(start));
+  }
+
+  #[test]
+  fn ramp_down_stop_eases_to_zero_before_braking() {
+    let mut now = Instant::now();
+    let mut motion = motion(None, now);
+    motion.set_stop_mode(StopMode::RampDown);
+
+    now += Duration::from_millis(400);
+    let (state, _) = state_change_evaluator(Windmill::Forward(0), Windmill::Forward(102), &mut motion, now);
+    assert_eq!(Windmill::Forward(102), state);
+
+    now += Duration::from_millis(200);
+    let (state, commands) = state_change_evaluator(state, Windmill::Off, &mut motion, now);
+    assert_eq!((Windmill::Forward(51), vec![]), (state, commands));
+
+    now += Duration::from_millis(200);
+    let (state, commands) = state_change_evaluator(state, Windmill::Off, &mut motion, now);
+    assert_eq!((Windmill::Forward(0), vec![]), (state, commands));
+
+    now += Duration::from_millis(10);
+    assert_eq!(
+      (Windmill::Cooldown(Duration::from_millis(500)), vec![OutputCommand::DutyCycle(0), OutputCommand::Brake(true)]),
+      state_change_evaluator(state, Windmill::Off, &mut motion, now)
+    );
+  }
+
+  #[test]
+  fn coasting_and_hard_braking_both_cool_down() {
+    let now = Instant::now();
+    let mut motion = motion(None, now);
+
+    motion.set_stop_mode(StopMode::Coast);
+    assert_eq!(
+      (Windmill::Cooldown(Duration::from_secs(3)), vec![OutputCommand::DutyCycle(0), OutputCommand::Safety(false)]),
+      state_change_evaluator(Windmill::Reverse(255), Windmill::Forward(90), &mut motion, now)
+    );
+
+    motion.set_stop_mode(StopMode::HardBrake);
+    assert_eq!(
+      (Windmill::Cooldown(Duration::from_millis(1500)), vec![OutputCommand::DutyCycle(0), OutputCommand::Brake(true)]),
+      state_change_evaluator(Windmill::Reverse(255), Windmill::Off, &mut motion, now)
+    );
+  }
+
+  #[test]
+  fn cooldown_counts_down_in_real_time() {
+    let mut now = Instant::now();
+    let mut motion = motion(None, now);
+
+    now += Duration::from_millis(300);
+    let (state, commands) =
+      state_change_evaluator(Windmill::Cooldown(Duration::from_millis(500)), Windmill::Off, &mut motion, now);
+    assert_eq!((Windmill::Cooldown(Duration::from_millis(200)), vec![]), (state, commands));
+
+    // Coming out of a cool down always puts the relays back where `Off` has them, however the windmill stopped.
+    now += Duration::from_millis(200);
+    assert_eq!(
+      (Windmill::Off, vec![OutputCommand::Brake(true), OutputCommand::Safety(true)]),
+      state_change_evaluator(state, Windmill::Off, &mut motion, now)
+    );
+  }
+
+  #[test]
+  fn cooldown_waits_for_the_sensor_to_see_a_stop() {
+    struct StillTurning;
+
+    impl sensor::SpeedSensor for StillTurning {
+      fn rpm(&self) -> f64 {
+        3.0
+      }
+    }
+
+    let mut now = Instant::now();
+    let mut motion = Motion::new(profile(None), Some(Arc::new(StillTurning)), now);
+
+    now += Duration::from_secs(10);
     assert_eq!(
-      Windmill::Forward(MAX_SPEED_CHANGE_PER_CYCLE),
-      state_change_evaluator(Windmill::Forward(0), Windmill::Forward(239))
+      (Windmill::Cooldown(Duration::ZERO), vec![]),
+      state_change_evaluator(Windmill::Cooldown(Duration::from_millis(500)), Windmill::Reverse(20), &mut motion, now)
     );
   }
+
+  /// Runs the windmill back and forth between full forward and full reverse on the default settings, against a
+  /// simulated rotor described by `model`, and makes sure it never changes direction while the rotor is still turning.
+  /// The rotor gets exactly what the state machine's commands would have done to it. Returns the top speed the rotor got
+  /// to, or how fast it was turning when the direction changed.
+  fn reverse_back_and_forth(model: physics::RotorModel, stop_mode: StopMode) -> Result<f64, String> {
+    let args = cli::Args::parse_from(["windmill"]);
+    let limits = args.duty_limits().unwrap();
+    let mut now = Instant::now();
+    let mut motion = Motion::new(args.motion_profile().unwrap(), None, now);
+    let mut rotor = physics::Rotor::new(model);
+    let mut state = Windmill::Off;
+    let mut drive = physics::Drive::default();
+    let mut top_rpm = 0.0f64;
+
+    motion.set_stop_mode(stop_mode);
+
+    for step in 0..12000u64 {
+      let desired = if (step / 2000) % 2 == 0 { Windmill::Forward(255) } else { Windmill::Reverse(255) };
+
+      now += Duration::from_millis(10);
+      let (next, commands) = state_change_evaluator(state, desired, &mut motion, now);
+      state = next;
+
+      for command in commands {
+        match command {
+          OutputCommand::Brake(brake) => drive.brake = brake,
+          OutputCommand::Safety(safety) => drive.powered = safety,
+          OutputCommand::Direction(direction) => {
+            let reverse = direction == MotorDirection::Reverse;
+
+            if reverse != drive.reverse && rotor.is_turning() {
+              return Err(format!("{stop_mode:?} changed direction at {:.1} rpm", rotor.rpm()));
+            }
+
+            drive.reverse = reverse;
+          },
+          OutputCommand::DutyCycle(_) | OutputCommand::SafeOutput => {}
+        }
+      }
+
+      drive.duty_cycle = match state {
+        Windmill::Forward(speed) | Windmill::Reverse(speed) => limits.duty_cycle(&args.response_curve, speed),
+        Windmill::Cooldown(_) | Windmill::Off => 0
+      };
+
+      rotor.run(drive, Duration::from_millis(10));
+      top_rpm = top_rpm.max(rotor.rpm().abs());
+    }
+
+    Ok(top_rpm)
+  }
+
+  #[test]
+  fn never_reverses_while_the_simulated_rotor_is_turning() {
+    for stop_mode in [StopMode::HardBrake, StopMode::Coast, StopMode::RampDown] {
+      let top_rpm = reverse_back_and_forth(physics::RotorModel::default(), stop_mode).unwrap();
+
+      // Make sure the windmill actually got going, or there'd be nothing to check.
+      assert!(top_rpm > 30.0, "{stop_mode:?} only got to {top_rpm:.1} rpm");
+    }
+  }
+
+  /// The check above is only worth anything if it can fail. A rotor five times as heavy takes far longer to brake to a
+  /// stop than the default cool down allows for, so it's still turning when the direction changes.
+  #[test]
+  fn a_rotor_too_slow_for_the_cool_down_is_caught_reversing() {
+    let model = physics::RotorModel { inertia: 60.0, ..physics::RotorModel::default() };
+    assert!(reverse_back_and_forth(model, StopMode::HardBrake).is_err());
+  }
+
+  /// A coast is meant to let the rotor spin down on its own, so on the default settings, the brake mustn't go back on at
+  /// the end of the cool down until the simulated rotor has actually stopped. Otherwise it's just a hard brake, late.
+  #[test]
+  fn coasting_from_full_speed_only_brakes_once_the_rotor_has_stopped() {
+    let args = cli::Args::parse_from(["windmill"]);
+    let limits = args.duty_limits().unwrap();
+    let mut now = Instant::now();
+    let mut motion = Motion::new(args.motion_profile().unwrap(), None, now);
+    let mut rotor = physics::Rotor::new(physics::RotorModel::default());
+    let mut drive = physics::Drive::default();
+    let mut state = Windmill::Off;
+    let mut coasting_from = None;
+
+    motion.set_stop_mode(StopMode::Coast);
+
+    for step in 0..6000u64 {
+      let desired = if step < 2500 { Windmill::Forward(255) } else { Windmill::Off };
+
+      now += Duration::from_millis(10);
+      let (next, commands) = state_change_evaluator(state, desired, &mut motion, now);
+
+      if let (Windmill::Forward(_), Windmill::Cooldown(_)) = (state, next) {
+        coasting_from = Some(rotor.rpm());
+      }
+
+      state = next;
+
+      for command in commands {
+        match command {
+          OutputCommand::Brake(brake) => {
+            let braking_a_coast = brake && !drive.brake && coasting_from.is_some();
+            assert!(!braking_a_coast || !rotor.is_turning(), "braked a coasting rotor at {:.1} rpm", rotor.rpm());
+            drive.brake = brake;
+          },
+          OutputCommand::Safety(safety) => drive.powered = safety,
+          OutputCommand::Direction(_) | OutputCommand::DutyCycle(_) | OutputCommand::SafeOutput => {}
+        }
+      }
+
+      drive.duty_cycle = match state {
+        Windmill::Forward(speed) | Windmill::Reverse(speed) => limits.duty_cycle(&args.response_curve, speed),
+        Windmill::Cooldown(_) | Windmill::Off => 0
+      };
+
+      rotor.run(drive, Duration::from_millis(10));
+    }
+
+    assert!(coasting_from.is_some_and(|rpm| rpm > 30.0), "never got going: {coasting_from:?}");
+    assert_eq!(Windmill::Off, state);
+  }
+
+  /// A PWM output that remembers every duty cycle it was given, and when, by the clock the control loop is running on.
+  struct RecordingOutput {
+    clock: clock::VirtualClock,
+    writes: std::sync::Mutex<Vec<(Instant, u8)>>
+  }
+
+  impl pwm::Output for RecordingOutput {
+    fn set_duty_cycle(&self, duty_cycle: u8) -> Result<(), &'static str> {
+      self.writes.lock().unwrap().push((self.clock.now(), duty_cycle));
+      Ok(())
+    }
+
+    fn set_safe(&self) -> Result<(), &'static str> {
+      self.set_duty_cycle(0)
+    }
+  }
+
+  /// Relays that aren't wired to anything.
+  struct NoRelays;
+
+  impl output::Relays for NoRelays {
+    fn set_brake(&self, _: bool) {}
+    fn set_direction(&self, _: MotorDirection) {}
+    fn set_safety(&self, _: bool) {}
+  }
+
+  /// A control loop on the test motion profile, fed by `cues` and driving `driver`, starting at `now`.
+  fn control_loop(cues: mpsc::UnboundedReceiver<Cue>, driver: Arc<dyn pwm::Output>, now: Instant) -> ControlLoop {
+    ControlLoop {
+      cues,
+      preset_requests: mpsc::unbounded_channel().1,
+      executor: Arc::new(output::Executor::new(Arc::new(NoRelays), driver, false)),
+      duty_limits: output::DutyLimits::default(),
+      response_curve: curve::ResponseCurve::Linear,
+      control: control::ControlState::new(true, StopMode::HardBrake),
+      presets: presets::Presets::load(None).unwrap(),
+      motion: motion(None, now),
+      effects: effects::EffectEngine::new(1, now),
+      console_cue: Cue::from(Windmill::Off),
+      playback_cue: None,
+      live_cue: Cue::from(Windmill::Off),
+      desired_state: Windmill::Off,
+      current_state: Windmill::Off,
+      current_duty_cycle: 0,
+      state_updates: watch::channel(Windmill::Off).0
+    }
+  }
+
+  #[test]
+  fn steps_through_a_ramp_and_cool_down_at_exact_instants() {
+    let start = Instant::now();
+    let clock = clock::VirtualClock::new(start);
+    let driver = Arc::new(RecordingOutput { clock: clock.clone(), writes: Default::default() });
+    let (cues, receiver) = mpsc::unbounded_channel();
+    let mut control_loop = control_loop(receiver, driver.clone(), start);
+    let mut step_until = |until: Duration| {
+      while clock.now() < start + until {
+        clock.advance(TICK);
+        control_loop.step(clock.now()).unwrap();
+      }
+
+      control_loop.current_state
+    };
+
+    cues.send(Cue::from(Windmill::Forward(255))).unwrap();
+    assert_eq!(Windmill::Forward(0), step_until(TICK));
+
+    // The ramp takes exactly a second from when it starts to get to full speed, and not a tick less.
+    assert_eq!(Windmill::Forward(252), step_until(Duration::from_millis(1000)));
+    assert_eq!(Windmill::Forward(255), step_until(Duration::from_millis(1010)));
+
+    // Braking from full speed cools down for exactly a second and a half.
+    cues.send(Cue::from(Windmill::Off)).unwrap();
+    assert_eq!(Windmill::Cooldown(Duration::from_millis(1500)), step_until(Duration::from_millis(1020)));
+    assert_eq!(Windmill::Cooldown(Duration::from_millis(10)), step_until(Duration::from_millis(2510)));
+    assert_eq!(Windmill::Off, step_until(Duration::from_millis(2520)));
+
+    let writes = driver.writes.lock().unwrap();
+    assert_eq!(Some(&(start + Duration::from_millis(1010), 100)), writes.iter().find(|(_, duty)| *duty == 100));
+    assert_eq!(Some(&(start + Duration::from_millis(1020), 0)), writes.last());
+  }
+
+  #[test]
+  fn effects_never_swing_a_moving_windmill_into_the_dead_band() {
+    let start = Instant::now();
+    let clock = clock::VirtualClock::new(start);
+    let driver = Arc::new(RecordingOutput { clock: clock.clone(), writes: Default::default() });
+    let (cues, receiver) = mpsc::unbounded_channel();
+    let mut control_loop = control_loop(receiver, driver.clone(), start);
+    control_loop.duty_limits = output::DutyLimits { minimum: 20, maximum: 100, dead_band: 10 };
+
+    // A fast, deep oscillation around a speed just above the dead band would dip well inside it every cycle.
+    let effect = effects::Effect { kind: effects::EffectKind::Oscillate, rate: 255, depth: 60 };
+    cues.send(Cue { effect: Some(effect), ..Cue::from(Windmill::Forward(15)) }).unwrap();
+
+    while clock.now() < start + Duration::from_secs(10) {
+      clock.advance(TICK);
+      control_loop.step(clock.now()).unwrap();
+
+      if clock.now() >= start + Duration::from_secs(2) {
+        let state = control_loop.current_state;
+        assert!(matches!(state, Windmill::Forward(speed) if speed > 10), "dipped to {state:?}");
+      }
+    }
+
+    let writes = driver.writes.lock().unwrap();
+    assert!(writes.iter().filter(|(at, _)| *at >= start + Duration::from_secs(2)).all(|(_, duty)| *duty >= 20));
+  }
+
+  #[tokio::test]
+  async fn runs_on_a_virtual_clock_without_waiting() {
+    let start = Instant::now();
+    let clock = clock::VirtualClock::new(start);
+    let driver = Arc::new(RecordingOutput { clock: clock.clone(), writes: Default::default() });
+    let (cues, receiver) = mpsc::unbounded_channel();
+    let control_loop = control_loop(receiver, driver.clone(), start);
+
+    cues.send(Cue::from(Windmill::Reverse(255))).unwrap();
+
+    // Ten minutes of virtual time go by in no time at all.
+    let later = async {
+      while clock.now() < start + Duration::from_secs(600) {
+        tokio::task::yield_now().await;
+      }
+    };
+
+    select! {
+      result = control_loop.run(&clock) => panic!("the control loop stopped: {result:?}"),
+      _ = later => {}
+    }
+
+    assert!(std::time::Instant::now() - start.into_std() < Duration::from_secs(10));
+    assert_eq!(Some(&(start + Duration::from_millis(1000), 100)), driver.writes.lock().unwrap().last());
+  }
 }
@@ -0,0 +1,248 @@
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+use crate::ramp::{Ramp, RampProfile};
+use crate::sensor::SpeedSensor;
+
+/// A kick-start is a short burst of extra duty cycle given to the motor as it starts, to break it free of static
+/// friction. A big rotor sitting still can need a lot more shove to get going than it needs to keep going, and without
+/// a kick, a slow ramp can sit there stalled at a low duty cycle for quite a while.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct KickStart {
+  /// The duty cycle (in percent) to drive while kicking.
+  pub duty: u8,
+
+  /// How long to kick for before handing over to the ramp.
+  pub duration: Duration
+}
+
+/// The shape of a `CooldownModel` between its minimum and full speed durations.
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum CooldownCurve {
+  /// Cool down time grows in proportion to speed.
+  Linear,
+
+  /// Cool down time grows with the square of speed. The energy stored in a spinning rotor goes up with the square of
+  /// its speed, so this is the more physically honest option when the brake is doing the work.
+  Quadratic
+}
+
+/// Describes how long the windmill needs to settle after being stopped, as a function of how fast it was going when it
+/// was stopped. A windmill that was barely turning doesn't need anywhere near as long as one that was flat out.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CooldownModel {
+  /// How long to cool down after stopping from a standstill (or close to it).
+  pub minimum: Duration,
+
+  /// How long to cool down after stopping from full speed.
+  pub full_speed: Duration,
+
+  /// How to get from one to the other.
+  pub curve: CooldownCurve
+}
+
+impl CooldownModel {
+  /// How long to cool down after stopping from `speed`.
+  pub fn duration(&self, speed: u8) -> Duration {
+    let fraction = speed as f64 / u8::MAX as f64;
+
+    let fraction = match self.curve {
+      CooldownCurve::Linear => fraction,
+      CooldownCurve::Quadratic => fraction * fraction
+    };
+
+    let extra = self.full_speed.saturating_sub(self.minimum).mul_f64(fraction);
+    self.minimum + extra
+  }
+}
+
+/// Everything about how the windmill is configured to move: how it ramps, how it starts, and how long it needs to
+/// settle after it stops.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MotionProfile {
+  /// How speed changes are eased.
+  pub ramp: RampProfile,
+
+  /// How to kick-start the motor, if at all.
+  pub kick_start: Option<KickStart>,
+
+  /// How long to cool down after braking.
+  pub brake_cooldown: CooldownModel,
+
+  /// How long to cool down after coasting. Without the brake, this is generally quite a bit longer, and it has to be long
+  /// enough for the rotor to stop on its own, since the brake goes back on once it's over.
+  pub coast_cooldown: CooldownModel
+}
+
+/// How the windmill comes to a stop, whether that's because it was asked to turn off or because it needs to stop before
+/// it can change direction.
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum StopMode {
+  /// Ease the speed down to nothing at the normal deceleration rate, and only then apply the brake. The graceful
+  /// option, and the one you usually want in a scene.
+  RampDown,
+
+  /// Cut the motor off by opening the safety relay and let the rotor coast down on its own, without the brake. Slow,
+  /// but about as gentle as it gets.
+  Coast,
+
+  /// Dump whatever momentum the rotor has into the braking resistor right now. This is always what happens on faults
+  /// and on shutdown, whatever mode has been selected.
+  HardBrake
+}
+
+impl StopMode {
+  /// Decodes a stop mode from a DMX control value. The low end of the fader means "no preference", leaving it up to
+  /// whatever the windmill was configured with; the rest is split into equal ranges for each mode.
+  pub fn from_dmx(value: u8) -> Option<StopMode> {
+    match value {
+      0..=63 => None,
+      64..=127 => Some(StopMode::RampDown),
+      128..=191 => Some(StopMode::Coast),
+      192..=255 => Some(StopMode::HardBrake)
+    }
+  }
+}
+
+/// `Motion` holds everything about how the windmill gets from one speed to another that has to be remembered between
+/// evaluations of the state machine: where the ramp is up to, when we last looked at the clock, and whether we're
+/// in the middle of kicking the motor into life.
+#[derive(Clone)]
+pub struct Motion {
+  /// How the windmill is configured to move.
+  profile: MotionProfile,
+
+  /// Eases speed changes over time.
+  ramp: Ramp,
+
+  /// Tells us whether the rotor is actually moving, if we have one.
+  sensor: Option<Arc<dyn SpeedSensor>>,
+
+  /// When the state machine was last evaluated.
+  last_evaluation: Instant,
+
+  /// If we're kicking, when the kick ends.
+  kick_until: Option<Instant>,
+
+  /// How to stop the next time the windmill needs stopping.
+  stop_mode: StopMode
+}
+
+impl Motion {
+  /// Creates a new `Motion`, starting out stopped as of `now`. Stops are hard brakes until told otherwise.
+  pub fn new(profile: MotionProfile, sensor: Option<Arc<dyn SpeedSensor>>, now: Instant) -> Self {
+    Motion {
+      profile,
+      ramp: Ramp::new(profile.ramp),
+      sensor,
+      last_evaluation: now,
+      kick_until: None,
+      stop_mode: StopMode::HardBrake
+    }
+  }
+
+  /// Records that the state machine is being evaluated at `now`, and returns how long it has been since the last time.
+  pub fn elapsed(&mut self, now: Instant) -> Duration {
+    let elapsed = now.saturating_duration_since(self.last_evaluation);
+    self.last_evaluation = now;
+
+    elapsed
+  }
+
+  /// The motor is starting from a standstill. The ramp starts over from zero, and if a kick-start is configured, it
+  /// begins now -- unless the sensor can see that the rotor is already turning (say, it's still coasting, or someone
+  /// gave it a push), in which case there's no friction to break and the kick would just be a lurch.
+  pub fn start(&mut self, now: Instant) {
+    self.ramp.reset();
+
+    let already_moving = self.is_rotor_moving();
+
+    self.kick_until = self.profile.kick_start
+      .filter(|_| !already_moving)
+      .map(|kick_start| now + kick_start.duration);
+  }
+
+  /// The motor has been stopped out from under us (e.g. by the brake). Forget about any ramp or kick in progress.
+  pub fn stop(&mut self) {
+    self.ramp.reset();
+    self.kick_until = None;
+  }
+
+  /// Moves the speed towards `target` over `elapsed`. While a kick-start is in progress the ramp is held at a standstill,
+  /// so it picks up from zero once the kick hands over.
+  pub fn step(&mut self, target: u8, elapsed: Duration, now: Instant) -> u8 {
+    if self.is_kicking(now) {
+      return self.ramp.current();
+    }
+
+    self.kick_until = None;
+    self.ramp.step(target, elapsed)
+  }
+
+  /// Asks for speed changes to take `fade_time`, or to go as fast as the profile allows if it's `None`. See
+  /// `Ramp::set_fade_time`.
+  pub fn set_fade_time(&mut self, fade_time: Option<Duration>) {
+    self.ramp.set_fade_time(fade_time);
+  }
+
+  /// How far through a timed speed change we are, from `0.0` to `1.0`, if one is in progress.
+  pub fn fade_progress(&self) -> Option<f64> {
+    self.ramp.fade_progress()
+  }
+
+  /// If the motor should currently be kicked, the duty cycle to kick it with.
+  pub fn kick_duty(&self, now: Instant) -> Option<u8> {
+    self.profile.kick_start
+      .filter(|_| self.is_kicking(now))
+      .map(|kick_start| kick_start.duty)
+  }
+
+  /// How the windmill will stop the next time it needs to.
+  pub fn stop_mode(&self) -> StopMode {
+    self.stop_mode
+  }
+
+  /// Changes how the windmill will stop. This can change mid-stop, e.g. to cut a slow ramp down short with the brake.
+  pub fn set_stop_mode(&mut self, stop_mode: StopMode) {
+    self.stop_mode = stop_mode;
+  }
+
+  /// How long to cool down for after stopping from `speed` with the given `stop_mode`.
+  pub fn cooldown(&self, speed: u8, stop_mode: StopMode) -> Duration {
+    match stop_mode {
+      StopMode::Coast => self.profile.coast_cooldown.duration(speed),
+      StopMode::RampDown | StopMode::HardBrake => self.profile.brake_cooldown.duration(speed)
+    }
+  }
+
+  /// Whether the sensor can see the rotor still turning. Without a sensor, we have no idea, so this is always `false`
+  /// and the cool down model is all we have to go on.
+  pub fn is_rotor_moving(&self) -> bool {
+    self.sensor.as_ref().is_some_and(|sensor| sensor.is_moving())
+  }
+
+  /// Whether a kick-start is in progress at `now`.
+  fn is_kicking(&self, now: Instant) -> bool {
+    self.kick_until.is_some_and(|kick_until| now < kick_until)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn cooldown_scales_with_speed() {
+    let linear = CooldownModel {
+      minimum: Duration::from_secs(1),
+      full_speed: Duration::from_secs(5),
+      curve: CooldownCurve::Linear
+    };
+
+    let quadratic = CooldownModel { curve: CooldownCurve::Quadratic, ..linear };
+
+    assert_eq!(Duration::from_secs(1), linear.duration(0));
+    assert_eq!(Duration::from_secs(5), linear.duration(255));
+    assert_eq!(Duration::from_secs(5), quadratic.duration(255));
+    assert!(quadratic.duration(128) < linear.duration(128));
+  }
+}
@@ -0,0 +1,59 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::Instant;
+use crate::conditioning::Conditioner;
+use crate::fixture::Cue;
+use crate::pcap::Dmx;
+use crate::recording::Recorder;
+
+/// Listens for Art-Net and sACN on `address` and feeds the DMX for `universe` through `conditioner` to the windmill,
+/// without going anywhere near OLA. This is for when there's no `olad` to talk to: a bench setup, a laptop, or a test
+/// with a fake console on loopback. Which protocol a packet is doesn't matter, so one address is enough for either
+/// (6454 is Art-Net's port and 5568 is sACN's). Listening on an unspecified IPv4 address also joins the universe's sACN
+/// multicast group, since that's where consoles send sACN unless told otherwise.
+///
+/// There's no merging here like OLA would do: whichever packet arrived last wins, whatever its priority. With `recorder`,
+/// every frame is written out as it arrives, exactly as `ola::start` does.
+pub async fn listen(
+  address: SocketAddr,
+  universe: u32,
+  mut conditioner: Conditioner,
+  mut recorder: Option<Recorder>,
+  sender: UnboundedSender<Cue>
+) -> Result<(), &'static str> {
+  let socket = UdpSocket::bind(address).await.map_err(|_| "failed to listen for Art-Net and sACN")?;
+
+  if address.ip() == IpAddr::V4(Ipv4Addr::UNSPECIFIED) {
+    let group = Ipv4Addr::new(239, 255, (universe >> 8) as u8, universe as u8);
+
+    if socket.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED).is_err() {
+      eprintln!("Couldn't join the sACN multicast group {group}, so only unicast sACN will get through.");
+    }
+  }
+
+  println!("Listening for Art-Net and sACN on {address}.");
+  let mut buffer = [0; 1500];
+
+  loop {
+    let length = socket.recv(&mut buffer).await.map_err(|_| "failed to receive Art-Net or sACN")?;
+    let now = Instant::now();
+
+    let Some(dmx) = Dmx::decode(&buffer[..length]).filter(|dmx| dmx.universe == universe) else {
+      continue;
+    };
+
+    // Same as with OLA, a recording that can't be written to is given up on rather than stopping the show.
+    let recorded = recorder
+      .as_mut()
+      .map(|recorder| recorder.record(universe, dmx.priority, |channel| dmx.value(channel), now));
+
+    if let Some(Err(why)) = recorded {
+      eprintln!("Stopped recording: {why}");
+      recorder = None;
+    }
+
+    let cue = conditioner.decode(|channel| dmx.value(channel), now);
+    sender.send(cue).map_err(|_| "the network listener lost connection to the windmill")?;
+  }
+}
@@ -1,43 +1,60 @@
 pub mod dmx;
 pub mod logging;
 
+use std::cell::RefCell;
 use cxx::UniquePtr;
 use tokio::sync::mpsc::{UnboundedSender, error::SendError};
 use tokio_retry::Retry;
 use tokio_retry::strategy::{ExponentialBackoff, jitter};
-use crate::fixture::Windmill;
+use crate::conditioning::Conditioner;
+use crate::fixture::Cue;
 use crate::ola::dmx::{Buffer, Metadata};
+use crate::recording::Recorder;
 
 /// Starts the OpenLightingArchitecture task with a small adapter to convert the DMX signals transmitted over something
-/// like OSC or ArtNet and translates them to high-level `Windmill` commands. `speed_channel` and `direction_channel`
-/// will dictate which channels the speed and direction values are read from, somewhat obviously. What is ever so
-/// slightly less obvious is that these values are represented by their DMX channel numbers for ease of readability. But
-/// the internal code is zero-indexed, which honestly in this situation I'm not sure if I dig or not. Either way,
-/// decrement by one when actually indexing with these channel references.
+/// like OSC or ArtNet and translates them to high-level `Cue`s. The `conditioner`'s personality will dictate which
+/// channels are read and what they mean, somewhat obviously, and the conditioner itself cleans up whatever noise the
+/// console sends before it can reach the windmill. What is ever so slightly less obvious is that its channels are
+/// represented by their DMX channel numbers for ease of readability. But the internal code is zero-indexed, which
+/// honestly in this situation I'm not sure if I dig or not. Either way, decrement by one when actually indexing with
+/// these channel references.
+///
+/// With a `recorder`, every frame is also written out as it arrives, before any conditioning, so that it can be
+/// replayed later exactly as it came in.
 pub fn start(
-  sender: UnboundedSender<Windmill>,
+  sender: UnboundedSender<Cue>,
   universe: u32,
-  speed_channel: u32,
-  direction_channel: u32
+  conditioner: Conditioner,
+  recorder: Option<Recorder>
 ) -> Result<(), &'static str> {
   if !logging::init(logging::LogLevel::Info, logging::LogOutput::StdErr) {
     return Err("Failed to initialize Open Lighting Architecture logging system.");
   }
 
-  let on_dmx = move |_: &Metadata, data: &Buffer| {
-    let direction = data.get(direction_channel - 1);
-    let speed = data.get(speed_channel - 1);
-
-    if let Err(SendError(unsent_windmill)) = sender.send(
-      match speed {
-        0 => Windmill::Off,
-        speed => match direction {
-          0..=127 => Windmill::Forward(speed),
-          128..=255 => Windmill::Reverse(speed)
-        }
-      }
-    ) {
-      eprintln!("Failed to send: {:?}", unsent_windmill)
+  // The bridge only hands us a shared reference to call back with, but conditioning has to remember what it's seen. The
+  // callback is only ever invoked from the client's own thread, so a `RefCell` is all that's needed.
+  let conditioner = RefCell::new(conditioner);
+  let recorder = RefCell::new(recorder);
+
+  let on_dmx = move |metadata: &Metadata, data: &Buffer| {
+    let now = tokio::time::Instant::now();
+
+    // A recording that can't be written to is given up on, rather than complaining about it at 44 frames a second. The
+    // show goes on either way.
+    let recorded = recorder
+      .borrow_mut()
+      .as_mut()
+      .map(|recorder| recorder.record(metadata.universe, metadata.priority, |channel| data.get(channel - 1), now));
+
+    if let Some(Err(why)) = recorded {
+      eprintln!("Stopped recording: {why}");
+      recorder.borrow_mut().take();
+    }
+
+    let cue = conditioner.borrow_mut().decode(|channel| data.get(channel - 1), now);
+
+    if let Err(SendError(unsent_cue)) = sender.send(cue) {
+      eprintln!("Failed to send: {:?}", unsent_cue)
     }
   };
 
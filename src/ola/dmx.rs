@@ -32,7 +32,7 @@ pub struct Bridge<'a> {
   universe: u32,
 
   /// A reference to a callback function to trigger when DMX packets are received.
-  on_dmx_fn: &'a dyn Fn(&Metadata, &Buffer) -> ()
+  on_dmx_fn: &'a dyn Fn(&Metadata, &Buffer)
 }
 
 /// cxx binding representation for `Bridge` that allows it to be passed over the boundary. This type at one point was
@@ -46,7 +46,7 @@ unsafe impl<'a> ExternType for Bridge<'a> {
 impl<'a> Bridge<'a> {
   /// Creates a new bridge that will listen for messages on the given universe and will call the referenced callback
   /// function with any new data.
-  pub fn new(universe: u32, on_dmx_fn: &'a dyn Fn(&Metadata, &Buffer) -> ()) -> Self {
+  pub fn new(universe: u32, on_dmx_fn: &'a dyn Fn(&Metadata, &Buffer)) -> Self {
     Bridge {
       universe,
       on_dmx_fn
@@ -74,11 +74,11 @@ impl<'a> Bridge<'a> {
   }
 }
 
-/// Convenience implementation of `Into` that allows us to turn a `Bridge` into a `Client` without exposing the `ffi`
+/// Convenience implementation of `From` that allows us to turn a `Bridge` into a `Client` without exposing the `ffi`
 /// boundary on the public API.
-impl<'a> Into<UniquePtr<Client<'a>>> for Bridge<'a> {
-  fn into(self) -> UniquePtr<Client<'a>> {
-    ffi::create(Box::new(self))
+impl<'a> From<Bridge<'a>> for UniquePtr<Client<'a>> {
+  fn from(bridge: Bridge<'a>) -> Self {
+    ffi::create(Box::new(bridge))
   }
 }
 
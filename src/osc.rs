@@ -0,0 +1,148 @@
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::UnboundedSender;
+use crate::api;
+use crate::presets::{PresetMessage, PresetRequest};
+
+/// Listens for OSC on `address` and passes preset requests along to the windmill task over `presets`, the same way the
+/// local API does. This is for show control software (QLab and friends) that would rather say which preset it wants by
+/// name than by a DMX value on the preset channel. The messages are:
+///
+///   - `/windmill/recall <preset>`: hold the windmill on a preset, overriding the console.
+///   - `/windmill/release`: hand the windmill back to the console.
+///   - `/windmill/capture <preset>`: save what the windmill is doing right now as a preset.
+///
+/// OSC doesn't answer back, so anything that goes wrong is only printed. Bundles are unpacked and their messages
+/// handled in order, ignoring their time tags: a preset is recalled as soon as it arrives.
+pub async fn listen(address: SocketAddr, presets: UnboundedSender<PresetMessage>) -> Result<(), &'static str> {
+  let socket = UdpSocket::bind(address).await.map_err(|_| "failed to listen for OSC")?;
+  println!("Listening for OSC on {address}.");
+
+  let mut buffer = [0; 1500];
+
+  loop {
+    let length = socket.recv(&mut buffer).await.map_err(|_| "failed to receive OSC")?;
+
+    for request in decode(&buffer[..length]) {
+      match request.map(|request| api::ask(&presets, request)) {
+        Ok(answer) => match answer.await {
+          Ok(message) => println!("osc: {message}"),
+          Err(why) => eprintln!("osc: {why}")
+        },
+
+        Err(why) => eprintln!("osc: {why}")
+      }
+    }
+  }
+}
+
+/// Decodes an OSC packet (a message, or a bundle of them) into the preset requests it makes. Anything that isn't for
+/// the windmill is skipped quietly, since plenty of show control software sprays OSC at everything on the network.
+fn decode(packet: &[u8]) -> Vec<Result<PresetRequest, &'static str>> {
+  let mut requests = Vec::new();
+  unpack(packet, &mut requests);
+  requests
+}
+
+/// Adds whatever requests `packet` makes to `requests`, going into bundles (and bundles within bundles) as needed.
+fn unpack(packet: &[u8], requests: &mut Vec<Result<PresetRequest, &'static str>>) {
+  let Some(rest) = packet.strip_prefix(b"#bundle\0") else {
+    if let Some(request) = message(packet) {
+      requests.push(request);
+    }
+
+    return;
+  };
+
+  // After the 8 byte time tag, a bundle is a run of elements, each a big-endian 32 bit length and then the element.
+  let mut elements = rest.get(8..).unwrap_or_default();
+
+  while let Some((length, rest)) = elements.split_first_chunk::<4>() {
+    let length = u32::from_be_bytes(*length) as usize;
+
+    let Some(element) = rest.get(..length) else {
+      return;
+    };
+
+    unpack(element, requests);
+    elements = &rest[length..];
+  }
+}
+
+/// Decodes a single OSC message, if it's one for the windmill.
+fn message(packet: &[u8]) -> Option<Result<PresetRequest, &'static str>> {
+  let (address, rest) = string(packet)?;
+  let command = address.strip_prefix("/windmill/")?;
+
+  // The type tags say what arguments follow. Really old senders leave them out entirely, but those can't send a name.
+  let (tags, arguments) = match string(rest) {
+    Some((tags, arguments)) if tags.starts_with(',') => (&tags[1..], arguments),
+    _ => ("", rest)
+  };
+
+  let name = match tags {
+    "" => None,
+    "s" => Some(string(arguments)?.0.to_string()),
+    _ => return Some(Err("osc preset messages take a single preset name"))
+  };
+
+  Some(match (command, name) {
+    ("recall", Some(name)) => Ok(PresetRequest::Recall(name)),
+    ("release", None) => Ok(PresetRequest::Release),
+    ("capture", Some(name)) => Ok(PresetRequest::Capture(name)),
+    _ => Err("expected /windmill/recall <preset>, /windmill/release, or /windmill/capture <preset>")
+  })
+}
+
+/// Reads an OSC string off the front of `bytes`: text, terminated by a nul, padded with more nuls out to a multiple of
+/// four bytes. Returns the string and whatever follows it.
+fn string(bytes: &[u8]) -> Option<(&str, &[u8])> {
+  let end = bytes.iter().position(|byte| *byte == 0)?;
+  let text = std::str::from_utf8(&bytes[..end]).ok()?;
+  let padded = (end + 4) & !3;
+
+  Some((text, bytes.get(padded..)?))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Encodes an OSC string, nul terminated and padded.
+  fn encode(text: &str) -> Vec<u8> {
+    let mut bytes = text.as_bytes().to_vec();
+    bytes.resize((text.len() + 4) & !3, 0);
+    bytes
+  }
+
+  #[test]
+  fn decodes_preset_messages() {
+    let recall = [encode("/windmill/recall"), encode(",s"), encode("storm")].concat();
+    let release = [encode("/windmill/release"), encode(",")].concat();
+
+    assert_eq!(vec![Ok(PresetRequest::Recall(String::from("storm")))], decode(&recall));
+    assert_eq!(vec![Ok(PresetRequest::Release)], decode(&release));
+    assert_eq!(vec![Ok(PresetRequest::Release)], decode(&encode("/windmill/release")));
+    assert!(decode(&[encode("/windmill/recall"), encode(",")].concat())[0].is_err());
+    assert!(decode(&[encode("/windmill/recall"), encode(",i"), vec![0, 0, 0, 1]].concat())[0].is_err());
+    assert!(decode(&[encode("/lights/go"), encode(",")].concat()).is_empty());
+    assert!(decode(&[0xff, 0x00]).is_empty());
+  }
+
+  #[test]
+  fn unpacks_bundles_in_order() {
+    let recall = [encode("/windmill/recall"), encode(",s"), encode("calm")].concat();
+    let release = encode("/windmill/release");
+
+    let bundle = [
+      encode("#bundle"),
+      vec![0, 0, 0, 0, 0, 0, 0, 1],
+      (recall.len() as u32).to_be_bytes().to_vec(),
+      recall,
+      (release.len() as u32).to_be_bytes().to_vec(),
+      release
+    ].concat();
+
+    assert_eq!(vec![Ok(PresetRequest::Recall(String::from("calm"))), Ok(PresetRequest::Release)], decode(&bundle));
+  }
+}
@@ -0,0 +1,355 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use tokio::time::Instant;
+use crate::curve::ResponseCurve;
+use crate::fixture::Windmill;
+use crate::pwm::Output;
+
+/// The duty cycle that actually makes the motor turn is not the same as the duty cycle the motor controller will
+/// accept. Below some threshold, static friction wins and the windmill just sits there and hums (while the rest of the
+/// program happily believes it's moving `Forward`). At the other end, the rig may simply not be safe at 100%.
+/// `DutyLimits` describes those boundaries for a particular fixture.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DutyLimits {
+  /// The lowest duty cycle that reliably keeps the motor turning. The first speed above the `dead_band` maps here, so
+  /// the whole rest of the fader is spent on speeds that actually move.
+  pub minimum: u8,
+
+  /// The highest duty cycle the motor is ever allowed to see, no matter who's asking. Full speed maps here.
+  pub maximum: u8,
+
+  /// Speeds at or below this are treated as "off". This soaks up faders that don't quite make it back down to zero.
+  pub dead_band: u8
+}
+
+impl DutyLimits {
+  /// Sanity checks the limits, since getting them backwards would be a confusing way to never turn on.
+  pub fn new(minimum: u8, maximum: u8, dead_band: u8) -> Result<Self, &'static str> {
+    if maximum > 100 {
+      return Err("the maximum duty cycle can't be more than 100%");
+    }
+
+    if minimum > maximum {
+      return Err("the minimum duty cycle can't be more than the maximum duty cycle");
+    }
+
+    if dead_band == u8::MAX {
+      return Err("a dead band covering the whole fader would never let the windmill turn on");
+    }
+
+    Ok(DutyLimits { minimum, maximum, dead_band })
+  }
+
+  /// Swallows any speed that falls inside the dead band, turning it into `Off`.
+  pub fn apply_dead_band(&self, windmill: Windmill) -> Windmill {
+    match windmill {
+      Windmill::Forward(speed) | Windmill::Reverse(speed) if speed <= self.dead_band => Windmill::Off,
+      windmill => windmill
+    }
+  }
+
+  /// Maps a speed onto a duty cycle. The speeds above the dead band are stretched back out over the full fader, run
+  /// through the `curve`, and then squeezed in between the minimum and maximum duty cycles.
+  pub fn duty_cycle(&self, curve: &ResponseCurve, speed: u8) -> u8 {
+    if speed <= self.dead_band {
+      return 0;
+    }
+
+    let above_dead_band = (speed - self.dead_band) as f64 / (u8::MAX - self.dead_band) as f64;
+    let curved = curve.duty_cycle((above_dead_band * u8::MAX as f64).round() as u8) as f64 / 100.0;
+
+    (self.minimum as f64 + curved * (self.maximum - self.minimum) as f64).round() as u8
+  }
+}
+
+impl Default for DutyLimits {
+  fn default() -> Self {
+    DutyLimits { minimum: 0, maximum: 100, dead_band: 0 }
+  }
+}
+
+/// `OutputStage` is the last thing between the rest of the program and the PWM output. Its job is to enforce the hard
+/// maximum duty cycle on every single write, so that it doesn't matter which part of the program came up with the
+/// number (or whether it remembered to apply the limits itself).
+pub struct OutputStage {
+  /// The output actually being driven.
+  output: Arc<dyn Output>,
+
+  /// No duty cycle above this will ever be written.
+  maximum_duty: u8,
+
+  /// The duty cycle last written (or zero, once made safe).
+  written: AtomicU8
+}
+
+impl OutputStage {
+  /// Wraps `output`, capping it to the maximum duty cycle in `limits`.
+  pub fn new(output: Arc<dyn Output>, limits: DutyLimits) -> Self {
+    OutputStage {
+      output,
+      maximum_duty: limits.maximum,
+      written: AtomicU8::new(0)
+    }
+  }
+
+  /// The duty cycle last written to the output, after capping. That's as close as we can get to asking the hardware
+  /// what it's doing, since a PWM chip can't be read back.
+  pub fn duty_cycle(&self) -> u8 {
+    self.written.load(Ordering::Relaxed)
+  }
+}
+
+impl Output for OutputStage {
+  fn set_duty_cycle(&self, duty_cycle: u8) -> Result<(), &'static str> {
+    let duty_cycle = std::cmp::min(duty_cycle, self.maximum_duty);
+    self.output.set_duty_cycle(duty_cycle)?;
+    self.written.store(duty_cycle, Ordering::Relaxed);
+    Ok(())
+  }
+
+  fn set_safe(&self) -> Result<(), &'static str> {
+    self.output.set_safe()?;
+    self.written.store(0, Ordering::Relaxed);
+    Ok(())
+  }
+}
+
+/// Which way the motor controller drives the motor.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MotorDirection {
+  /// Turning the windmill forwards.
+  Forward,
+
+  /// Turning the windmill in reverse.
+  Reverse
+}
+
+/// One thing to do to the windmill's hardware. The state machine works out what needs doing and hands back a list of
+/// these, in the order they need doing, rather than doing any of it itself. That way it can be tested (and audited)
+/// for exactly what it would have done, and an `Executor` gets to decide what actually happens.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputCommand {
+  /// Shorts the motor into the braking resistor (`true`), or releases it (`false`).
+  Brake(bool),
+
+  /// Sets which way the motor is driven. Only ever safe with the brake on.
+  Direction(MotorDirection),
+
+  /// Closes the safety relay, passing PWM through to the motor controller (`true`), or opens it (`false`).
+  Safety(bool),
+
+  /// Sets the PWM duty cycle, in percent.
+  DutyCycle(u8),
+
+  /// Drives the PWM output to its safe level, which depending on its polarity isn't necessarily a zero duty cycle.
+  SafeOutput
+}
+
+impl Display for OutputCommand {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let on = |on: bool| if on { "on" } else { "off" };
+
+    match self {
+      OutputCommand::Brake(brake) => write!(f, "brake {}", on(*brake)),
+      OutputCommand::Direction(MotorDirection::Forward) => write!(f, "direction forward"),
+      OutputCommand::Direction(MotorDirection::Reverse) => write!(f, "direction reverse"),
+      OutputCommand::Safety(safety) => write!(f, "safety {}", on(*safety)),
+      OutputCommand::DutyCycle(duty_cycle) => write!(f, "duty {duty_cycle}%"),
+      OutputCommand::SafeOutput => write!(f, "duty safe")
+    }
+  }
+}
+
+/// `Relays` are the digital side of the windmill's hardware: the brake, the direction, and the safety relay. On the
+/// real thing, they're GPIO pins; with `--simulate`, they're the simulation.
+pub trait Relays: Send + Sync {
+  /// Applies (`true`) or releases the brake.
+  fn set_brake(&self, brake: bool);
+
+  /// Sets which way the motor is driven.
+  fn set_direction(&self, direction: MotorDirection);
+
+  /// Closes (`true`) or opens the safety relay.
+  fn set_safety(&self, safety: bool);
+}
+
+/// `Executor` carries out `OutputCommand`s on whichever hardware the windmill was started with. It's shared between
+/// the windmill task and the shutdown path, so that both go through exactly the same door to the hardware.
+///
+/// With `audit`, every batch of commands is printed as it's carried out, which is a complete record of everything the
+/// windmill did to its hardware, and when.
+pub struct Executor {
+  /// The brake, direction and safety relays.
+  relays: Arc<dyn Relays>,
+
+  /// The motor speed signal.
+  output: Arc<dyn Output>,
+
+  /// Whether to print every batch of commands.
+  audit: bool,
+
+  /// When the executor was created, which is what the audit measures from.
+  started: Instant
+}
+
+impl Executor {
+  /// Creates a new `Executor` driving `relays` and `output`.
+  pub fn new(relays: Arc<dyn Relays>, output: Arc<dyn Output>, audit: bool) -> Self {
+    Executor { relays, output, audit, started: Instant::now() }
+  }
+
+  /// Carries out `commands`, in order. Every command gets carried out even if an earlier one failed, since the later
+  /// ones might be what makes the windmill safe again, but the first failure is passed along.
+  pub fn execute(&self, commands: &[OutputCommand]) -> Result<(), &'static str> {
+    if commands.is_empty() {
+      return Ok(());
+    }
+
+    if self.audit {
+      let commands = commands.iter().map(OutputCommand::to_string).collect::<Vec<_>>();
+      println!("[output] {:.3}s {}", self.started.elapsed().as_secs_f64(), commands.join(", "));
+    }
+
+    let mut result = Ok(());
+
+    for command in commands {
+      let executed = match *command {
+        OutputCommand::Brake(brake) => {
+          self.relays.set_brake(brake);
+          Ok(())
+        },
+
+        OutputCommand::Direction(direction) => {
+          self.relays.set_direction(direction);
+          Ok(())
+        },
+
+        OutputCommand::Safety(safety) => {
+          self.relays.set_safety(safety);
+          Ok(())
+        },
+
+        OutputCommand::DutyCycle(duty_cycle) => self.output.set_duty_cycle(duty_cycle),
+        OutputCommand::SafeOutput => self.output.set_safe()
+      };
+
+      result = result.and(executed);
+    }
+
+    result
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Mutex;
+
+  /// Remembers everything written to it.
+  #[derive(Default)]
+  struct Recording(Mutex<Vec<u8>>);
+
+  impl Output for Recording {
+    fn set_duty_cycle(&self, duty_cycle: u8) -> Result<(), &'static str> {
+      self.0.lock().unwrap().push(duty_cycle);
+      Ok(())
+    }
+
+    fn set_safe(&self) -> Result<(), &'static str> {
+      self.set_duty_cycle(0)
+    }
+  }
+
+  #[test]
+  fn first_speed_above_dead_band_is_the_minimum() {
+    let limits = DutyLimits::new(30, 80, 10).unwrap();
+
+    assert_eq!(0, limits.duty_cycle(&ResponseCurve::Linear, 10));
+    assert_eq!(30, limits.duty_cycle(&ResponseCurve::Linear, 11));
+    assert_eq!(80, limits.duty_cycle(&ResponseCurve::Linear, 255));
+    assert_eq!(Windmill::Off, limits.apply_dead_band(Windmill::Reverse(10)));
+    assert_eq!(Windmill::Reverse(11), limits.apply_dead_band(Windmill::Reverse(11)));
+  }
+
+  #[test]
+  fn defaults_leave_the_curve_alone() {
+    let limits = DutyLimits::default();
+
+    for speed in 0..=u8::MAX {
+      assert_eq!(ResponseCurve::Square.duty_cycle(speed), limits.duty_cycle(&ResponseCurve::Square, speed));
+    }
+  }
+
+  #[test]
+  fn output_stage_caps_every_write() {
+    let recording = Arc::new(Recording::default());
+    let stage = OutputStage::new(recording.clone(), DutyLimits::new(0, 60, 0).unwrap());
+
+    stage.set_duty_cycle(100).unwrap();
+    stage.set_duty_cycle(40).unwrap();
+
+    assert_eq!(vec![60, 40], *recording.0.lock().unwrap());
+  }
+
+  /// Keeps every command carried out on it, but won't take a duty cycle over 100.
+  #[derive(Default)]
+  struct Hardware(Mutex<Vec<OutputCommand>>);
+
+  impl Relays for Hardware {
+    fn set_brake(&self, brake: bool) {
+      self.0.lock().unwrap().push(OutputCommand::Brake(brake));
+    }
+
+    fn set_direction(&self, direction: MotorDirection) {
+      self.0.lock().unwrap().push(OutputCommand::Direction(direction));
+    }
+
+    fn set_safety(&self, safety: bool) {
+      self.0.lock().unwrap().push(OutputCommand::Safety(safety));
+    }
+  }
+
+  impl Output for Hardware {
+    fn set_duty_cycle(&self, duty_cycle: u8) -> Result<(), &'static str> {
+      if duty_cycle > 100 {
+        return Err("too much");
+      }
+
+      self.0.lock().unwrap().push(OutputCommand::DutyCycle(duty_cycle));
+      Ok(())
+    }
+
+    fn set_safe(&self) -> Result<(), &'static str> {
+      self.0.lock().unwrap().push(OutputCommand::SafeOutput);
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn executor_carries_on_past_a_failure() {
+    let hardware = Arc::new(Hardware::default());
+    let executor = Executor::new(hardware.clone(), hardware.clone(), false);
+
+    let commands = [
+      OutputCommand::Brake(true),
+      OutputCommand::DutyCycle(120),
+      OutputCommand::Direction(MotorDirection::Reverse),
+      OutputCommand::Safety(false),
+      OutputCommand::SafeOutput
+    ];
+
+    assert_eq!(Err("too much"), executor.execute(&commands));
+
+    let mut carried_out = commands.to_vec();
+    carried_out.remove(1);
+    assert_eq!(carried_out, *hardware.0.lock().unwrap());
+  }
+
+  #[test]
+  fn rejects_backwards_limits() {
+    assert!(DutyLimits::new(50, 40, 0).is_err());
+    assert!(DutyLimits::new(0, 101, 0).is_err());
+    assert!(DutyLimits::new(0, 100, 255).is_err());
+  }
+}
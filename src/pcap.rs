@@ -0,0 +1,445 @@
+use std::path::Path;
+use tokio::time::Duration;
+use crate::recording::Frame;
+
+/// The priority OLA gives Art-Net, which doesn't have priorities of its own.
+const ART_NET_PRIORITY: u8 = 100;
+
+/// The Art-Net opcode for ArtDmx, as it appears on the wire (little endian).
+const OP_DMX: [u8; 2] = [0x00, 0x50];
+
+/// The identifier every E1.31 (sACN) packet carries in its root layer.
+const ACN_ID: &[u8] = b"ASC-E1.17\0\0\0";
+
+/// Reads a packet capture (pcap or pcapng, as saved by Wireshark or tcpdump) and pulls out the DMX for `universe` from
+/// any ArtDmx and E1.31 packets in it, as frames that can be replayed just like a recording. Only `channels` are kept.
+/// Everything else in the capture is skipped over, so there's no need to filter it down first.
+///
+/// The timing of each frame is kept as it was captured, measured from the first frame for our universe.
+pub fn load(path: &Path, universe: u32, channels: &[u32]) -> Result<Vec<Frame>, &'static str> {
+  let bytes = std::fs::read(path).map_err(|_| "failed to read the capture")?;
+  frames(&bytes, universe, channels)
+}
+
+/// Pulls the frames for `universe` out of a capture's `bytes`.
+fn frames(bytes: &[u8], universe: u32, channels: &[u32]) -> Result<Vec<Frame>, &'static str> {
+  let mut frames = Vec::new();
+  let mut started = None;
+
+  for packet in packets(bytes)? {
+    let Some(dmx) = udp_payload(packet.link_type, packet.data).and_then(Dmx::decode) else {
+      continue;
+    };
+
+    if dmx.universe != universe {
+      continue;
+    }
+
+    let started = *started.get_or_insert(packet.at);
+
+    frames.push(Frame {
+      at: packet.at.saturating_sub(started),
+      universe: dmx.universe,
+      priority: dmx.priority,
+      channels: channels.iter().map(|channel| (*channel, dmx.value(*channel))).collect()
+    });
+  }
+
+  Ok(frames)
+}
+
+/// A packet from a capture, still wrapped in whatever link layer it was captured on.
+#[derive(Copy, Clone, Debug)]
+struct Packet<'a> {
+  /// When it was captured.
+  at: Duration,
+
+  /// The link layer type (`LINKTYPE_*`), which says how to unwrap `data`.
+  link_type: u32,
+
+  /// The packet itself.
+  data: &'a [u8]
+}
+
+/// Splits a capture into its packets, whichever of the two formats it's in.
+fn packets(bytes: &[u8]) -> Result<Vec<Packet<'_>>, &'static str> {
+  match bytes.get(..4) {
+    Some([0x0a, 0x0d, 0x0d, 0x0a]) => pcapng(bytes),
+    Some(_) => pcap(bytes),
+    None => Err("the capture is empty")
+  }
+}
+
+/// Reads a `u16` from `bytes` at `offset`, in whichever byte order the capture was written in.
+fn u16_at(bytes: &[u8], offset: usize, little: bool) -> Option<u16> {
+  let value = bytes.get(offset..offset + 2)?.try_into().ok()?;
+  Some(if little { u16::from_le_bytes(value) } else { u16::from_be_bytes(value) })
+}
+
+/// Reads a `u32` from `bytes` at `offset`, in whichever byte order the capture was written in.
+fn u32_at(bytes: &[u8], offset: usize, little: bool) -> Option<u32> {
+  let value = bytes.get(offset..offset + 4)?.try_into().ok()?;
+  Some(if little { u32::from_le_bytes(value) } else { u32::from_be_bytes(value) })
+}
+
+/// Splits a classic pcap file into its packets.
+fn pcap(bytes: &[u8]) -> Result<Vec<Packet<'_>>, &'static str> {
+  // The magic number says both which byte order the file was written in and whether timestamps are in micro or
+  // nanoseconds.
+  let (little, nanos) = match bytes.get(..4) {
+    Some([0xd4, 0xc3, 0xb2, 0xa1]) => (true, false),
+    Some([0xa1, 0xb2, 0xc3, 0xd4]) => (false, false),
+    Some([0x4d, 0x3c, 0xb2, 0xa1]) => (true, true),
+    Some([0xa1, 0xb2, 0x3c, 0x4d]) => (false, true),
+    _ => return Err("that doesn't look like a pcap or pcapng capture")
+  };
+
+  let link_type = u32_at(bytes, 20, little).ok_or("the capture ends partway through its header")?;
+  let mut offset = 24;
+  let mut packets = Vec::new();
+
+  while offset < bytes.len() {
+    let header = (u32_at(bytes, offset, little), u32_at(bytes, offset + 4, little), u32_at(bytes, offset + 8, little));
+
+    let (Some(seconds), Some(fraction), Some(length)) = header else {
+      return Err("the capture ends partway through a packet");
+    };
+
+    let start = offset + 16;
+    let data = bytes.get(start..start + length as usize).ok_or("the capture ends partway through a packet")?;
+    let fraction = if nanos { fraction } else { fraction.saturating_mul(1000) };
+
+    packets.push(Packet { at: Duration::new(seconds as u64, fraction), link_type, data });
+    offset = start + length as usize;
+  }
+
+  Ok(packets)
+}
+
+/// A network interface described by a pcapng capture. Packets say which interface they were captured on, and that's
+/// what says how to read them.
+#[derive(Copy, Clone, Debug)]
+struct Interface {
+  /// The link layer type (`LINKTYPE_*`).
+  link_type: u32,
+
+  /// How many timestamp units there are in a second.
+  units_per_second: u64
+}
+
+/// Splits a pcapng file into its packets. Only enhanced packet blocks are read, since those are what Wireshark writes
+/// and they're the only ones with timestamps. Files with several sections (i.e. several captures glued together) are
+/// fine, as long as they're all in the same byte order.
+fn pcapng(bytes: &[u8]) -> Result<Vec<Packet<'_>>, &'static str> {
+  let little = match bytes.get(8..12) {
+    Some([0x4d, 0x3c, 0x2b, 0x1a]) => true,
+    Some([0x1a, 0x2b, 0x3c, 0x4d]) => false,
+    _ => return Err("the capture's section header is broken")
+  };
+
+  let mut interfaces = Vec::new();
+  let mut packets = Vec::new();
+  let mut offset = 0;
+
+  while offset < bytes.len() {
+    let block_type = u32_at(bytes, offset, little).ok_or("the capture ends partway through a block")?;
+    let length = u32_at(bytes, offset + 4, little).ok_or("the capture ends partway through a block")? as usize;
+
+    if length < 12 || !length.is_multiple_of(4) {
+      return Err("the capture has a block with a broken length");
+    }
+
+    let body = bytes.get(offset + 8..offset + length - 4).ok_or("the capture ends partway through a block")?;
+
+    match block_type {
+      // A new section starts over with its own interfaces.
+      0x0a0d0d0a => interfaces.clear(),
+
+      0x00000001 => {
+        let link_type = u16_at(body, 0, little).ok_or("the capture has a broken interface")? as u32;
+        let units_per_second = timestamp_resolution(body.get(8..).unwrap_or_default(), little);
+        interfaces.push(Interface { link_type, units_per_second });
+      },
+
+      0x00000006 => {
+        let interface = u32_at(body, 0, little)
+          .and_then(|interface| interfaces.get(interface as usize))
+          .ok_or("the capture has a packet from an interface it never described")?;
+
+        let high = u32_at(body, 4, little).ok_or("the capture has a broken packet")? as u64;
+        let low = u32_at(body, 8, little).ok_or("the capture has a broken packet")? as u64;
+        let captured = u32_at(body, 12, little).ok_or("the capture has a broken packet")? as usize;
+        let data = body.get(20..20 + captured).ok_or("the capture has a broken packet")?;
+
+        let timestamp = high << 32 | low;
+        let per_second = interface.units_per_second;
+        let at = Duration::from_secs(timestamp / per_second)
+          + Duration::from_secs_f64((timestamp % per_second) as f64 / per_second as f64);
+
+        packets.push(Packet { at, link_type: interface.link_type, data });
+      },
+
+      // Statistics, name resolution, simple packets without timestamps, and anything newer than us.
+      _ => {}
+    }
+
+    offset += length;
+  }
+
+  Ok(packets)
+}
+
+/// Works out how many timestamp units there are in a second from an interface's `options`. Without an `if_tsresol`
+/// option, timestamps are in microseconds.
+fn timestamp_resolution(mut options: &[u8], little: bool) -> u64 {
+  while let (Some(code), Some(length)) = (u16_at(options, 0, little), u16_at(options, 2, little)) {
+    let length = length as usize;
+
+    if code == 0 {
+      break;
+    }
+
+    // The high bit says whether the rest is a power of two or a power of ten.
+    if code == 9 && length == 1 {
+      return match options.get(4).copied().unwrap_or(6) {
+        resolution if resolution & 0x80 != 0 => 1u64.checked_shl((resolution & 0x7f) as u32).unwrap_or(1),
+        resolution => 10u64.checked_pow(resolution as u32).unwrap_or(1)
+      }
+      .max(1);
+    }
+
+    options = options.get(4 + length.next_multiple_of(4)..).unwrap_or_default();
+  }
+
+  1_000_000
+}
+
+/// Unwraps a UDP payload from a packet captured on link layer `link_type`. Anything that isn't a whole, unfragmented
+/// UDP datagram over IPv4 or IPv6 is `None`.
+fn udp_payload(link_type: u32, data: &[u8]) -> Option<&[u8]> {
+  let (ether_type, ip) = match link_type {
+    // BSD loopback, with the address family up front. The IP version is just as good for telling them apart.
+    0 => (None, data.get(4..)?),
+
+    // Ethernet, possibly with an 802.1Q VLAN tag.
+    1 => match u16_at(data, 12, false)? {
+      0x8100 => (Some(u16_at(data, 16, false)?), data.get(18..)?),
+      ether_type => (Some(ether_type), data.get(14..)?)
+    },
+
+    // Raw IP.
+    101 | 228 | 229 => (None, data),
+
+    // Linux "cooked" captures (`tcpdump -i any`), versions one and two.
+    113 => (Some(u16_at(data, 14, false)?), data.get(16..)?),
+    276 => (Some(u16_at(data, 0, false)?), data.get(20..)?),
+
+    _ => return None
+  };
+
+  let udp = match (ether_type, ip.first()? >> 4) {
+    (Some(0x0800) | None, 4) => {
+      let header = (ip[0] & 0x0f) as usize * 4;
+      let fragmented = u16_at(ip, 6, false)? & 0x3fff != 0;
+
+      if ip.get(9) != Some(&17) || fragmented {
+        return None;
+      }
+
+      ip.get(header..)?
+    },
+
+    (Some(0x86dd) | None, 6) if ip.get(6) == Some(&17) => ip.get(40..)?,
+
+    _ => return None
+  };
+
+  let length = u16_at(udp, 4, false)? as usize;
+  udp.get(8..length)
+}
+
+/// The DMX carried by an ArtDmx or E1.31 packet.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Dmx<'a> {
+  /// The universe (for Art-Net, the fifteen bit port address).
+  pub universe: u32,
+
+  /// The priority it was sent at.
+  pub priority: u8,
+
+  /// The channel values, starting from channel one.
+  pub data: &'a [u8]
+}
+
+impl<'a> Dmx<'a> {
+  /// Decodes a UDP payload as either an ArtDmx or E1.31 data packet. Anything else (including Art-Net polls, sACN
+  /// discovery, and DMX with a non-zero start code) is `None`.
+  pub fn decode(payload: &'a [u8]) -> Option<Self> {
+    if payload.starts_with(b"Art-Net\0") {
+      return Self::art_dmx(payload);
+    }
+
+    if payload.get(4..16) == Some(ACN_ID) {
+      return Self::e131(payload);
+    }
+
+    None
+  }
+
+  /// Decodes an ArtDmx packet.
+  fn art_dmx(payload: &'a [u8]) -> Option<Self> {
+    if payload.get(8..10)? != OP_DMX {
+      return None;
+    }
+
+    let universe = u16_at(payload, 14, true)? as u32 & 0x7fff;
+    let length = u16_at(payload, 16, false)? as usize;
+
+    Some(Dmx { universe, priority: ART_NET_PRIORITY, data: payload.get(18..18 + length)? })
+  }
+
+  /// Decodes an E1.31 data packet.
+  fn e131(payload: &'a [u8]) -> Option<Self> {
+    let data_packet = u32_at(payload, 18, false)? == 0x00000004 && u32_at(payload, 40, false)? == 0x00000002;
+    let options = *payload.get(112)?;
+
+    // Preview data is meant for visualisers, not fixtures, and a terminated stream has nothing more to say.
+    if !data_packet || options & 0xc0 != 0 || payload.get(117) != Some(&0x02) || payload.get(125) != Some(&0) {
+      return None;
+    }
+
+    let universe = u16_at(payload, 113, false)? as u32;
+    let count = u16_at(payload, 123, false)? as usize;
+
+    Some(Dmx { universe, priority: *payload.get(108)?, data: payload.get(126..125 + count)? })
+  }
+
+  /// The value of a (one-indexed) DMX channel. Channels past the end of the packet are zero.
+  pub fn value(&self, channel: u32) -> u8 {
+    (channel as usize).checked_sub(1).and_then(|index| self.data.get(index)).copied().unwrap_or_default()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// An ArtDmx packet for `universe`, with `data` starting at channel one.
+  fn art_dmx(universe: u16, data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::from(&b"Art-Net\0"[..]);
+    packet.extend_from_slice(&OP_DMX);
+    packet.extend_from_slice(&[0, 14, 0, 0]);
+    packet.extend_from_slice(&universe.to_le_bytes());
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+    packet
+  }
+
+  /// An E1.31 data packet for `universe` at `priority`, with `data` starting at channel one.
+  fn e131(universe: u16, priority: u8, data: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x00, 0x10, 0x00, 0x00];
+    packet.extend_from_slice(ACN_ID);
+    packet.extend_from_slice(&[0x70, 0x00, 0x00, 0x00, 0x00, 0x04]);
+    packet.extend_from_slice(&[0; 16]);
+    packet.extend_from_slice(&[0x70, 0x00, 0x00, 0x00, 0x00, 0x02]);
+    packet.extend_from_slice(&[0; 64]);
+    packet.extend_from_slice(&[priority, 0, 0, 0, 0]);
+    packet.extend_from_slice(&universe.to_be_bytes());
+    packet.extend_from_slice(&[0x70, 0x00, 0x02, 0xa1, 0x00, 0x00, 0x00, 0x01]);
+    packet.extend_from_slice(&(data.len() as u16 + 1).to_be_bytes());
+    packet.push(0);
+    packet.extend_from_slice(data);
+    packet
+  }
+
+  /// Wraps a UDP `payload` in UDP, IPv4 and Ethernet headers.
+  fn ethernet(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0xff; 12];
+    frame.extend_from_slice(&[0x08, 0x00]);
+    frame.extend_from_slice(&[0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 255]);
+    frame.extend_from_slice(&[0x19, 0x36, 0x19, 0x36]);
+    frame.extend_from_slice(&(payload.len() as u16 + 8).to_be_bytes());
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(payload);
+    frame
+  }
+
+  #[test]
+  fn reads_art_net_and_sacn_from_pcap() {
+    let mut capture = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+    capture.extend_from_slice(&[0; 8]);
+    capture.extend_from_slice(&65535u32.to_le_bytes());
+    capture.extend_from_slice(&1u32.to_le_bytes());
+
+    let packets = [
+      (1_000_000u64, ethernet(&art_dmx(5, &[0, 200, 255]))),
+      (1_100_000, ethernet(&art_dmx(6, &[9, 9, 9]))),
+      (1_250_000, ethernet(&e131(5, 150, &[0, 50]))),
+      (1_300_000, ethernet(b"not dmx at all"))
+    ];
+
+    for (micros, packet) in packets {
+      capture.extend_from_slice(&((micros / 1_000_000) as u32).to_le_bytes());
+      capture.extend_from_slice(&((micros % 1_000_000) as u32).to_le_bytes());
+      capture.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+      capture.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+      capture.extend_from_slice(&packet);
+    }
+
+    let frames = frames(&capture, 5, &[2, 3]).unwrap();
+
+    assert_eq!(2, frames.len());
+    let first = Frame { at: Duration::ZERO, universe: 5, priority: 100, channels: vec![(2, 200), (3, 255)] };
+    let second = Frame { at: Duration::from_millis(250), universe: 5, priority: 150, channels: vec![(2, 50), (3, 0)] };
+    assert_eq!(vec![first, second], frames);
+  }
+
+  #[test]
+  fn reads_pcapng() {
+    let block = |block_type: u32, body: &[u8]| {
+      let padded = body.len().next_multiple_of(4);
+      let mut block = block_type.to_le_bytes().to_vec();
+      block.extend_from_slice(&(padded as u32 + 12).to_le_bytes());
+      block.extend_from_slice(body);
+      block.resize(padded + 8, 0);
+      block.extend_from_slice(&(padded as u32 + 12).to_le_bytes());
+      block
+    };
+
+    // One interface with millisecond timestamps, and two packets a second and a half apart.
+    let mut capture = block(0x0a0d0d0a, &[0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+      0xff]);
+    capture.extend(block(1, &[1, 0, 0, 0, 0, 0, 0, 0, 9, 0, 1, 0, 3, 0, 0, 0, 0, 0, 0, 0]));
+
+    for (millis, value) in [(10_000u64, 10u8), (11_500, 20)] {
+      let packet = ethernet(&e131(1, 100, &[value]));
+      let mut body = 0u32.to_le_bytes().to_vec();
+      body.extend_from_slice(&((millis >> 32) as u32).to_le_bytes());
+      body.extend_from_slice(&(millis as u32).to_le_bytes());
+      body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+      body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+      body.extend_from_slice(&packet);
+      capture.extend(block(6, &body));
+    }
+
+    let frames = frames(&capture, 1, &[1]).unwrap();
+
+    assert_eq!(vec![(1, 10)], frames[0].channels);
+    assert_eq!(vec![(1, 20)], frames[1].channels);
+    assert_eq!(Duration::from_millis(1500), frames[1].at);
+  }
+
+  #[test]
+  fn ignores_what_it_should() {
+    let mut preview = e131(1, 100, &[255]);
+    preview[112] = 0x80;
+    assert_eq!(None, Dmx::decode(&preview));
+
+    let mut poll = art_dmx(1, &[255]);
+    poll[9] = 0x20;
+    assert_eq!(None, Dmx::decode(&poll));
+
+    assert_eq!(Some(255), Dmx::decode(&art_dmx(1, &[255])).map(|dmx| dmx.value(1)));
+    assert!(frames(b"nope", 1, &[1]).is_err());
+  }
+}
@@ -0,0 +1,292 @@
+use std::f64::consts::TAU;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use crate::output::{MotorDirection, Relays};
+use crate::pwm::Output;
+use crate::sensor::SpeedSensor;
+
+/// How finely the rotor's motion is worked out. Braking is fast enough that bigger steps start to overshoot.
+const STEP: Duration = Duration::from_millis(1);
+
+/// Anything at least this fast (in RPM) counts as the rotor still turning, matching what a real speed sensor would
+/// notice.
+pub const TURNING_RPM: f64 = 1.0;
+
+/// `RotorModel` describes the physical windmill: the rotor, the motor turning it, and the braking resistor that stops
+/// it. The defaults are my best guess at the twelve foot prop, worked out on paper rather than measured: four sails of
+/// about 2.5 kg, each 1.8 m long, come to roughly 11 kg·m² as rods about the hub, and a bit more for the hub itself. On
+/// those numbers the braking resistor takes a bit over four seconds to stop the rotor from full speed, and coasting
+/// takes about ten. Nobody has put the real thing on a dynamometer, so if the rig disagrees, the rig wins.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RotorModel {
+  /// The rotor's moment of inertia, in kg·m².
+  pub inertia: f64,
+
+  /// Friction that grows with speed (bearings, air), in N·m per rad/s.
+  pub viscous_friction: f64,
+
+  /// Friction that doesn't care about speed, in N·m. This is also what the motor has to overcome to get going at all.
+  pub static_friction: f64,
+
+  /// The torque the motor makes at a standstill on full duty, in N·m.
+  pub stall_torque: f64,
+
+  /// How fast the motor would turn on full duty with nothing attached, in RPM. The motor's torque falls away towards
+  /// this speed, and pushes back if it's driven past it.
+  pub top_speed: f64,
+
+  /// How hard the braking resistor holds the rotor back, in N·m per rad/s. It's dumping the motor's back EMF, so it
+  /// fades away as the rotor slows and friction has to finish the job.
+  pub brake_damping: f64
+}
+
+impl Default for RotorModel {
+  fn default() -> Self {
+    RotorModel {
+      inertia: 12.0,
+      viscous_friction: 0.5,
+      static_friction: 4.0,
+      stall_torque: 20.0,
+      top_speed: 60.0,
+      brake_damping: 5.0
+    }
+  }
+}
+
+/// What the hardware is doing to the motor: the PWM duty cycle, which way it's being driven, and the two relays.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Drive {
+  /// The PWM duty cycle, in percent.
+  pub duty_cycle: u8,
+
+  /// Whether the motor is being driven in reverse.
+  pub reverse: bool,
+
+  /// Whether the brake relay has the motor shorted into the braking resistor.
+  pub brake: bool,
+
+  /// Whether the safety relay is passing the PWM signal through to the motor controller.
+  pub powered: bool
+}
+
+/// The state the windmill starts up in: braked, powered and going nowhere.
+impl Default for Drive {
+  fn default() -> Self {
+    Drive { duty_cycle: 0, reverse: false, brake: true, powered: true }
+  }
+}
+
+/// `Rotor` is the simulated rotor itself, turning (or not) according to a `RotorModel` and whatever `Drive` it's given.
+#[derive(Copy, Clone, Debug)]
+pub struct Rotor {
+  /// The physical model.
+  model: RotorModel,
+
+  /// How fast the rotor is turning, in rad/s. Forward is positive.
+  velocity: f64
+}
+
+impl Rotor {
+  /// Creates a new `Rotor` at a standstill.
+  pub fn new(model: RotorModel) -> Self {
+    Rotor { model, velocity: 0.0 }
+  }
+
+  /// How fast the rotor is turning, in RPM. Forward is positive and reverse is negative.
+  pub fn rpm(&self) -> f64 {
+    self.velocity * 60.0 / TAU
+  }
+
+  /// Whether the rotor is turning fast enough to notice.
+  pub fn is_turning(&self) -> bool {
+    self.rpm().abs() >= TURNING_RPM
+  }
+
+  /// Runs the rotor forward by `elapsed` under `drive`.
+  pub fn run(&mut self, drive: Drive, elapsed: Duration) {
+    let mut remaining = elapsed;
+
+    while !remaining.is_zero() {
+      let step = remaining.min(STEP);
+      self.step(drive, step.as_secs_f64());
+      remaining -= step;
+    }
+  }
+
+  /// Runs the rotor forward by a single small step of `dt` seconds.
+  fn step(&mut self, drive: Drive, dt: f64) {
+    let model = &self.model;
+    let top_speed = model.top_speed * TAU / 60.0;
+
+    // The braking resistor wins over everything. Otherwise, with the safety relay closed, the motor controller pushes
+    // towards the speed the duty cycle asks for (even at zero duty, which holds the rotor back). With it open, the
+    // rotor is on its own.
+    let torque = match (drive.brake, drive.powered) {
+      (true, _) => -model.brake_damping * self.velocity,
+      (false, true) => {
+        let direction = if drive.reverse { -1.0 } else { 1.0 };
+        model.stall_torque * (direction * drive.duty_cycle.min(100) as f64 / 100.0 - self.velocity / top_speed)
+      },
+      (false, false) => 0.0
+    } - model.viscous_friction * self.velocity;
+
+    // Static friction holds a stopped rotor still until there's enough torque to break it free, and otherwise always
+    // pulls against the direction of travel. It can bring the rotor to a stop, but never push it back the other way.
+    if self.velocity == 0.0 && torque.abs() <= model.static_friction {
+      return;
+    }
+
+    let moving = if self.velocity != 0.0 { self.velocity.signum() } else { torque.signum() };
+    let velocity = self.velocity + (torque - moving * model.static_friction) / model.inertia * dt;
+
+    self.velocity = if velocity.signum() != moving { 0.0 } else { velocity };
+  }
+}
+
+/// `Simulation` stands in for the windmill's hardware when running with `--simulate`. It takes the PWM duty cycle and
+/// relay states that would have gone to the real thing, and turns them into a simulated rotor speed, moving the rotor
+/// along to the current time whenever anything changes or anyone asks. That's handy for seeing what different ramps
+/// and cool downs would actually do to the rotor without a twelve foot prop to watch.
+///
+/// It also keeps an eye out for the one thing that really matters: the windmill should never be asked to change
+/// direction while the rotor is still turning the other way.
+///
+/// With `trace`, every change to the drive is printed as it happens, which makes a complete timeline of what the
+/// windmill did to its hardware. That's what the end-to-end tests read back to see what happened.
+pub struct Simulation {
+  /// The drive, the rotor, and when the rotor was last brought up to date.
+  state: Mutex<(Drive, Rotor, Instant)>,
+
+  /// When the simulation started, which is what the trace measures from.
+  started: Instant,
+
+  /// Whether to print every change to the drive.
+  trace: bool
+}
+
+impl Simulation {
+  /// Creates a new `Simulation` of a stopped windmill described by `model`, optionally tracing every change.
+  pub fn new(model: RotorModel, trace: bool, now: Instant) -> Self {
+    Simulation { state: Mutex::new((Drive::default(), Rotor::new(model), now)), started: now, trace }
+  }
+
+  /// Brings the rotor up to date, then changes the drive with `change`.
+  fn update(&self, change: impl FnOnce(&mut Drive, &Rotor)) -> f64 {
+    let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let (drive, rotor, updated) = &mut *state;
+    let now = Instant::now();
+
+    rotor.run(*drive, now.saturating_duration_since(*updated));
+    *updated = now;
+
+    let before = *drive;
+    change(drive, rotor);
+
+    if self.trace && *drive != before {
+      println!(
+        "[simulated] {:.3}s duty={} {} brake={} power={} rpm={:.1}",
+        now.saturating_duration_since(self.started).as_secs_f64(),
+        drive.duty_cycle,
+        if drive.reverse { "reverse" } else { "forward" },
+        if drive.brake { "on" } else { "off" },
+        if drive.powered { "on" } else { "off" },
+        rotor.rpm()
+      );
+    }
+
+    rotor.rpm()
+  }
+
+  /// The simulated rotor speed right now, in RPM. Forward is positive and reverse is negative.
+  pub fn signed_rpm(&self) -> f64 {
+    self.update(|_, _| {})
+  }
+
+  /// What the hardware is doing to the motor right now.
+  pub fn drive(&self) -> Drive {
+    self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).0
+  }
+
+}
+
+impl Relays for Simulation {
+  fn set_brake(&self, brake: bool) {
+    self.update(|drive, _| drive.brake = brake);
+  }
+
+  /// Sets the direction, complaining if the rotor is still turning the other way.
+  fn set_direction(&self, direction: MotorDirection) {
+    let reverse = direction == MotorDirection::Reverse;
+
+    self.update(|drive, rotor| {
+      if rotor.is_turning() && (rotor.rpm() < 0.0) != reverse {
+        eprintln!("[simulated] changed direction with the rotor still turning at {:.1} rpm!", rotor.rpm());
+      }
+
+      drive.reverse = reverse;
+    });
+  }
+
+  fn set_safety(&self, safety: bool) {
+    self.update(|drive, _| drive.powered = safety);
+  }
+}
+
+impl Output for Simulation {
+  fn set_duty_cycle(&self, duty_cycle: u8) -> Result<(), &'static str> {
+    self.update(|drive, _| drive.duty_cycle = duty_cycle.min(100));
+    Ok(())
+  }
+
+  fn set_safe(&self) -> Result<(), &'static str> {
+    self.set_duty_cycle(0)
+  }
+}
+
+/// The simulation can stand in for a rotation sensor too, which is a cheap way to try out sensor-gated cool downs.
+impl SpeedSensor for Simulation {
+  fn rpm(&self) -> f64 {
+    self.signed_rpm().abs()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn driven(duty_cycle: u8) -> Drive {
+    Drive { duty_cycle, reverse: false, brake: false, powered: true }
+  }
+
+  #[test]
+  fn motor_needs_enough_duty_to_get_going_and_tops_out() {
+    let mut rotor = Rotor::new(RotorModel::default());
+
+    rotor.run(driven(15), Duration::from_secs(10));
+    assert!(!rotor.is_turning());
+
+    rotor.run(driven(100), Duration::from_secs(30));
+    let top = rotor.rpm();
+    assert!((30.0..60.0).contains(&top), "topped out at {top}");
+
+    rotor.run(driven(100), Duration::from_secs(5));
+    assert!((rotor.rpm() - top).abs() < 0.1);
+  }
+
+  #[test]
+  fn braking_stops_quicker_than_coasting_and_neither_turns_around() {
+    let mut braked = Rotor::new(RotorModel::default());
+    braked.run(Drive { reverse: true, ..driven(100) }, Duration::from_secs(30));
+    let mut coasted = braked;
+
+    assert!(braked.rpm() < -30.0);
+
+    braked.run(Drive { brake: true, ..driven(0) }, Duration::from_secs(5));
+    coasted.run(Drive { powered: false, ..driven(0) }, Duration::from_secs(5));
+    assert_eq!(0.0, braked.rpm());
+    assert!(coasted.rpm() < -TURNING_RPM);
+
+    coasted.run(Drive { powered: false, ..driven(0) }, Duration::from_secs(30));
+    assert_eq!(0.0, coasted.rpm());
+  }
+}
@@ -0,0 +1,368 @@
+use std::path::Path;
+use std::str::FromStr;
+use tokio::select;
+use tokio::signal::unix::SignalKind;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::{Duration, Instant};
+use crate::effects::Effect;
+use crate::fixture::{Cue, CueSource, Windmill};
+
+/// How often playback works out where it's up to. This is about the rate a console refreshes DMX at, which is plenty
+/// for anything a timeline can describe, and keeps playback from flooding the channel to the windmill.
+const TICK: Duration = Duration::from_millis(25);
+
+/// A point on a `Timeline`: where the windmill should be at a particular time.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Keyframe {
+  /// How far into the timeline this keyframe sits.
+  pub at: Duration,
+
+  /// Where the windmill should be.
+  pub windmill: Windmill,
+
+  /// The effect to run from here until the next keyframe.
+  pub effect: Option<Effect>,
+
+  /// Whether to jump straight here when the time comes, rather than easing in from the keyframe before.
+  pub step: bool
+}
+
+/// A `Timeline` is a scripted sequence of keyframes, for running the windmill with no console attached (lobbies,
+/// pre-show, that sort of thing). Between keyframes the speed is interpolated, passing through a stop when the
+/// direction changes, so a timeline going from `forward:100` to `reverse:100` slows to a stop halfway and then speeds
+/// back up.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeline {
+  /// Every keyframe, in order.
+  keyframes: Vec<Keyframe>,
+
+  /// Whether to go back to the start after the last keyframe.
+  looping: bool
+}
+
+impl Timeline {
+  /// Loads a timeline file.
+  pub fn load(path: &Path) -> Result<Self, &'static str> {
+    std::fs::read_to_string(path).map_err(|_| "failed to read the timeline file")?.parse()
+  }
+
+  /// How long the timeline runs for, i.e. when its last keyframe is.
+  pub fn duration(&self) -> Duration {
+    self.keyframes.last().map(|keyframe| keyframe.at).unwrap_or_default()
+  }
+
+  /// Whether playback at `position` has run off the end of the timeline.
+  pub fn is_finished(&self, position: Duration) -> bool {
+    !self.looping && position >= self.duration()
+  }
+
+  /// Where the windmill should be at `position`.
+  pub fn sample(&self, position: Duration) -> Cue {
+    let duration = self.duration();
+
+    let position = if self.looping && !duration.is_zero() {
+      Duration::from_secs_f64(position.as_secs_f64() % duration.as_secs_f64())
+    }
+
+    else {
+      position
+    };
+
+    let next = self.keyframes.iter().position(|keyframe| keyframe.at > position);
+
+    let (windmill, effect) = match next {
+      // Before the first keyframe, or after the last, just hold it.
+      Some(0) => (self.keyframes[0].windmill, self.keyframes[0].effect),
+      None => self.keyframes.last().map(|last| (last.windmill, last.effect)).unwrap_or((Windmill::Off, None)),
+
+      Some(next) => {
+        let (from, to) = (self.keyframes[next - 1], self.keyframes[next]);
+
+        let windmill = if to.step {
+          from.windmill
+        }
+
+        else {
+          let progress = (position - from.at).as_secs_f64() / (to.at - from.at).as_secs_f64();
+          let (from_speed, to_speed) = (signed_speed(from.windmill), signed_speed(to.windmill));
+          unsigned_speed(from_speed + progress * (to_speed - from_speed))
+        };
+
+        (windmill, from.effect)
+      }
+    };
+
+    Cue { effect, source: CueSource::Playback, ..Cue::from(windmill) }
+  }
+}
+
+/// Parses a timeline file. Each line is a keyframe, written as `<seconds> <motion> [effect=<effect>:<rate>:<depth>]
+/// [step]`, where motion is `off`, `forward:<speed>` or `reverse:<speed>`. A line that just says `loop` makes the whole
+/// timeline loop. Blank lines and lines starting with `#` are ignored. For example:
+///
+/// ```text
+/// loop
+/// 0 off
+/// 10 forward:120
+/// 40 forward:200 effect=gust:150:60
+/// 60 forward:200
+/// 70 reverse:80
+/// 90 off step
+/// ```
+impl FromStr for Timeline {
+  type Err = &'static str;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    let mut timeline = Timeline { keyframes: Vec::new(), looping: false };
+
+    for line in value.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+      if line == "loop" {
+        timeline.looping = true;
+        continue;
+      }
+
+      let mut words = line.split_whitespace();
+      let at = words.next().unwrap_or_default().parse::<f64>().map_err(|_| "keyframes start with a time in seconds")?;
+      let at = Duration::try_from_secs_f64(at).map_err(|_| "keyframe times can't be negative")?;
+      let windmill = words.next().ok_or("a keyframe needs a motion")?.parse()?;
+      let mut keyframe = Keyframe { at, windmill, effect: None, step: false };
+
+      for word in words {
+        match (word, word.split_once('=')) {
+          ("step", _) => keyframe.step = true,
+          (_, Some(("effect", effect))) => keyframe.effect = Some(effect.parse()?),
+          _ => return Err("keyframe options should be effect=<effect>:<rate>:<depth> or step")
+        }
+      }
+
+      timeline.keyframes.push(keyframe);
+    }
+
+    if timeline.keyframes.is_empty() {
+      return Err("a timeline needs at least one keyframe");
+    }
+
+    if timeline.keyframes.windows(2).any(|pair| pair[0].at > pair[1].at) {
+      return Err("timeline keyframes should be in order");
+    }
+
+    Ok(timeline)
+  }
+}
+
+/// Turns a `Windmill` into a single number, positive forward and negative in reverse, which is much easier to
+/// interpolate.
+fn signed_speed(windmill: Windmill) -> f64 {
+  match windmill {
+    Windmill::Forward(speed) => speed as f64,
+    Windmill::Reverse(speed) => -(speed as f64),
+    Windmill::Off | Windmill::Cooldown(_) => 0.0
+  }
+}
+
+/// The inverse of `signed_speed`.
+fn unsigned_speed(speed: f64) -> Windmill {
+  match speed.round().clamp(-(u8::MAX as f64), u8::MAX as f64) as i16 {
+    0 => Windmill::Off,
+    speed if speed > 0 => Windmill::Forward(speed as u8),
+    speed => Windmill::Reverse(speed.unsigned_abs() as u8)
+  }
+}
+
+/// Things that can be done to playback, from the API or by signal.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PlaybackCommand {
+  /// Start playing, or carry on from where it was paused.
+  Play,
+
+  /// Hold wherever playback is up to.
+  Pause,
+
+  /// Stop the windmill and go back to the start of the timeline.
+  Stop
+}
+
+/// A `PlaybackCommand`, along with somewhere to send the answer.
+pub type PlaybackMessage = (PlaybackCommand, oneshot::Sender<Result<String, &'static str>>);
+
+/// Where playback is up to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PlaybackState {
+  /// Not playing, and back at the start.
+  Stopped,
+
+  /// Playing, having been at `offset` as of `since`.
+  Playing { since: Instant, offset: Duration },
+
+  /// Holding at `position`.
+  Paused { position: Duration }
+}
+
+/// `Playback` runs a `Timeline` against the clock.
+pub struct Playback {
+  /// What to play.
+  timeline: Timeline,
+
+  /// Where we're up to.
+  state: PlaybackState
+}
+
+impl Playback {
+  /// Creates a new `Playback` of `timeline`, stopped at the start.
+  pub fn new(timeline: Timeline) -> Self {
+    Playback { timeline, state: PlaybackState::Stopped }
+  }
+
+  /// Whether playback is currently playing.
+  pub fn is_playing(&self) -> bool {
+    matches!(self.state, PlaybackState::Playing { .. })
+  }
+
+  /// Carries out `command` at `now`, and describes what happened.
+  pub fn command(&mut self, command: PlaybackCommand, now: Instant) -> String {
+    self.state = match (command, self.state) {
+      (PlaybackCommand::Play, PlaybackState::Stopped) => PlaybackState::Playing { since: now, offset: Duration::ZERO },
+      (PlaybackCommand::Play, PlaybackState::Paused { position }) =>
+        PlaybackState::Playing { since: now, offset: position },
+      (PlaybackCommand::Pause, PlaybackState::Playing { .. }) => PlaybackState::Paused { position: self.position(now) },
+      (PlaybackCommand::Stop, _) => PlaybackState::Stopped,
+      (_, state) => state
+    };
+
+    match self.state {
+      PlaybackState::Stopped => String::from("stopped"),
+      PlaybackState::Playing { .. } => format!("playing at {:.1}s", self.position(now).as_secs_f64()),
+      PlaybackState::Paused { position } => format!("paused at {:.1}s", position.as_secs_f64())
+    }
+  }
+
+  /// How far into the timeline playback is at `now`.
+  pub fn position(&self, now: Instant) -> Duration {
+    match self.state {
+      PlaybackState::Stopped => Duration::ZERO,
+      PlaybackState::Playing { since, offset } => offset + now.saturating_duration_since(since),
+      PlaybackState::Paused { position } => position
+    }
+  }
+
+  /// Where the windmill should be at `now`. A stopped timeline stops the windmill, and one that has run off its end
+  /// stops itself.
+  pub fn cue(&mut self, now: Instant) -> Cue {
+    if self.is_playing() && self.timeline.is_finished(self.position(now)) {
+      let position = self.timeline.duration();
+      self.state = PlaybackState::Paused { position };
+    }
+
+    match self.state {
+      PlaybackState::Stopped => Cue { source: CueSource::Playback, ..Cue::from(Windmill::Off) },
+      _ => self.timeline.sample(self.position(now))
+    }
+  }
+}
+
+/// Runs `playback`, feeding cues to the windmill over `sender` (the same channel `ola::start` feeds), until the
+/// windmill stops listening. Playback can be controlled by `commands` from the API, or by signal: `SIGUSR1` toggles
+/// between playing and paused, and `SIGUSR2` stops.
+pub async fn run(
+  mut playback: Playback,
+  sender: UnboundedSender<Cue>,
+  mut commands: UnboundedReceiver<PlaybackMessage>
+) -> Result<(), &'static str> {
+  let mut toggle = tokio::signal::unix::signal(SignalKind::user_defined1())
+    .map_err(|_| "could not wire up listener for user signal 1")?;
+  let mut stop = tokio::signal::unix::signal(SignalKind::user_defined2())
+    .map_err(|_| "could not wire up listener for user signal 2")?;
+
+  let mut last_cue = None;
+
+  loop {
+    select! {
+      _ = tokio::time::sleep(TICK) => {},
+
+      Some((command, reply)) = commands.recv() => {
+        reply.send(Ok(playback.command(command, Instant::now()))).ok();
+      },
+
+      Some(_) = toggle.recv() => {
+        let command = if playback.is_playing() { PlaybackCommand::Pause } else { PlaybackCommand::Play };
+        println!("Playback {}.", playback.command(command, Instant::now()));
+      },
+
+      Some(_) = stop.recv() => {
+        println!("Playback {}.", playback.command(PlaybackCommand::Stop, Instant::now()));
+      }
+    }
+
+    // Only send cues that are actually different, so that a timeline sitting still doesn't crowd out the console.
+    let cue = playback.cue(Instant::now());
+
+    if last_cue != Some(cue) {
+      sender.send(cue).map_err(|_| "playback lost connection to the windmill")?;
+      last_cue = Some(cue);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn timeline(looping: bool) -> Timeline {
+    let timeline = "0 off\n10 forward:100\n20 reverse:100 effect=pulse:10:20\n30 off step".parse::<Timeline>().unwrap();
+    Timeline { looping, ..timeline }
+  }
+
+  #[test]
+  fn interpolates_through_a_stop_when_changing_direction() {
+    let timeline = timeline(false);
+    let at = |seconds: f64| timeline.sample(Duration::from_secs_f64(seconds)).windmill;
+
+    assert_eq!(Windmill::Off, at(0.0));
+    assert_eq!(Windmill::Forward(50), at(5.0));
+    assert_eq!(Windmill::Forward(100), at(10.0));
+    assert_eq!(Windmill::Off, at(15.0));
+    assert_eq!(Windmill::Reverse(50), at(17.5));
+
+    // A stepped keyframe holds the one before it until its time comes.
+    assert_eq!(Windmill::Reverse(100), at(29.9));
+    assert_eq!(Windmill::Off, at(30.0));
+    assert_eq!(Windmill::Off, at(100.0));
+  }
+
+  #[test]
+  fn effects_and_looping() {
+    let timeline = timeline(true);
+
+    assert!(timeline.sample(Duration::from_secs(25)).effect.is_some());
+    assert_eq!(Windmill::Forward(50), timeline.sample(Duration::from_secs(35)).windmill);
+    assert_eq!(CueSource::Playback, timeline.sample(Duration::from_secs(35)).source);
+    assert!(!timeline.is_finished(Duration::from_secs(100)));
+  }
+
+  #[test]
+  fn pause_holds_and_stop_rewinds() {
+    let mut playback = Playback::new(timeline(false));
+    let now = Instant::now();
+
+    assert_eq!(Windmill::Off, playback.cue(now).windmill);
+    playback.command(PlaybackCommand::Play, now);
+    playback.command(PlaybackCommand::Pause, now + Duration::from_secs(5));
+    assert_eq!(Windmill::Forward(50), playback.cue(now + Duration::from_secs(50)).windmill);
+
+    playback.command(PlaybackCommand::Play, now + Duration::from_secs(50));
+    assert_eq!(Windmill::Forward(100), playback.cue(now + Duration::from_secs(55)).windmill);
+
+    playback.command(PlaybackCommand::Stop, now + Duration::from_secs(55));
+    assert_eq!(Duration::ZERO, playback.position(now + Duration::from_secs(60)));
+  }
+
+  #[test]
+  fn rejects_bad_timelines() {
+    assert!("".parse::<Timeline>().is_err());
+    assert!("loop".parse::<Timeline>().is_err());
+    assert!("10 off\n5 off".parse::<Timeline>().is_err());
+    assert!("-1 off".parse::<Timeline>().is_err());
+    assert!("0 forward:10 bounce".parse::<Timeline>().is_err());
+  }
+}
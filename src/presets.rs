@@ -0,0 +1,317 @@
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::sync::oneshot;
+use tokio::time::Duration;
+use crate::effects::Effect;
+use crate::fixture::{Cue, Windmill};
+
+/// A `Preset` is a known-good look for the windmill, saved on the device itself so it can be brought back without the
+/// console having to know how it was programmed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preset {
+  /// What the preset is called. Names are a single word, since they're typed into the API and live in a text file.
+  pub name: String,
+
+  /// The speed and direction.
+  pub windmill: Windmill,
+
+  /// How long getting there should take, if it matters.
+  pub fade: Option<Duration>,
+
+  /// The motion effect to run, if any.
+  pub effect: Option<Effect>
+}
+
+impl Preset {
+  /// Captures the look described by `cue` as a preset called `name`. A windmill in the middle of cooling down is
+  /// captured as off, since that's where it's headed.
+  pub fn capture(name: &str, cue: &Cue) -> Result<Self, &'static str> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+      return Err("preset names have to be a single word");
+    }
+
+    // The preset file would read it back as a comment, and the preset would quietly disappear.
+    if name.starts_with('#') {
+      return Err("preset names can't start with a #");
+    }
+
+    let windmill = match cue.windmill {
+      Windmill::Cooldown(_) => Windmill::Off,
+      windmill => windmill
+    };
+
+    Ok(Preset { name: name.to_string(), windmill, fade: cue.fade, effect: cue.effect })
+  }
+
+  /// The `Cue` that recalls this preset.
+  pub fn cue(&self) -> Cue {
+    Cue { fade: self.fade, effect: self.effect, ..Cue::from(self.windmill) }
+  }
+}
+
+/// Parses a preset from a line of the preset file, which looks like `<name> <motion> [fade=<seconds>]
+/// [effect=<effect>:<rate>:<depth>]`, where motion is `off`, `forward:<speed>` or `reverse:<speed>`. For example,
+/// `storm forward:200 fade=8 effect=gust:180:60`.
+impl FromStr for Preset {
+  type Err = &'static str;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    let mut words = value.split_whitespace();
+    let name = words.next().ok_or("a preset needs a name")?.to_string();
+    let windmill = words.next().ok_or("a preset needs a motion")?.parse()?;
+
+    let mut preset = Preset { name, windmill, fade: None, effect: None };
+
+    for word in words {
+      match word.split_once('=') {
+        Some(("fade", seconds)) => {
+          let seconds = seconds.parse::<f64>().map_err(|_| "preset fades should be a number of seconds")?;
+          preset.fade = Some(Duration::try_from_secs_f64(seconds).map_err(|_| "preset fades can't be negative")?);
+        },
+
+        Some(("effect", effect)) => preset.effect = Some(effect.parse()?),
+
+        _ => return Err("preset options should be fade=<seconds> or effect=<effect>:<rate>:<depth>")
+      }
+    }
+
+    Ok(preset)
+  }
+}
+
+/// The inverse of `FromStr`, which is how presets are written back out to the preset file.
+impl Display for Preset {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} {}", self.name, self.windmill)?;
+
+    if let Some(fade) = self.fade {
+      write!(f, " fade={}", fade.as_secs_f64())?;
+    }
+
+    if let Some(effect) = self.effect {
+      write!(f, " effect={effect}")?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Something the local API has asked of the presets. These are handled by the windmill task itself, since capturing
+/// needs to know what the windmill is doing right now.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PresetRequest {
+  /// Recall the preset with this name, holding it until it's released.
+  Recall(String),
+
+  /// Let go of a recalled preset, handing control back to the console.
+  Release,
+
+  /// Save what the windmill is currently doing as a preset with this name.
+  Capture(String),
+
+  /// List every preset.
+  List
+}
+
+/// A `PresetRequest`, along with somewhere to send the answer.
+pub type PresetMessage = (PresetRequest, oneshot::Sender<Result<String, &'static str>>);
+
+/// `Presets` is the store of presets on the device, and keeps track of which (if any) is currently overriding the
+/// console. Presets are kept in a plain text file, one per line, in the order they're recalled by from the preset
+/// channel: the first preset is `1`, the second `2`, and so on. Blank lines and lines starting with `#` are ignored,
+/// but kept: capturing a preset only ever rewrites (or adds) that preset's own line, so notes in the file survive.
+pub struct Presets {
+  /// Where presets are saved. Without a file, presets can still be captured, but are forgotten on restart.
+  path: Option<PathBuf>,
+
+  /// The preset file, line by line, exactly as it was written (comments, blank lines and all).
+  lines: Vec<String>,
+
+  /// Every preset, in order.
+  presets: Vec<Preset>,
+
+  /// The preset recalled over the API, if there is one.
+  recalled: Option<String>
+}
+
+impl Presets {
+  /// Loads presets from `path`, if there is one. A file that doesn't exist yet is just an empty store.
+  pub fn load(path: Option<PathBuf>) -> Result<Self, &'static str> {
+    let contents = match &path {
+      Some(path) if path.exists() => std::fs::read_to_string(path).map_err(|_| "failed to read the preset file")?,
+      _ => String::new()
+    };
+
+    let presets = contents
+      .lines()
+      .map(str::trim)
+      .filter(|line| !line.is_empty() && !line.starts_with('#'))
+      .map(str::parse)
+      .collect::<Result<Vec<Preset>, _>>()?;
+
+    let lines = contents.lines().map(String::from).collect();
+
+    Ok(Presets { path, lines, presets, recalled: None })
+  }
+
+  /// Works out the cue the windmill should actually follow, given the one that just came from the console. A preset
+  /// picked on the preset channel wins over everything, then a preset recalled over the API, and then the console.
+  /// Whatever happens, maintenance commands and stop modes always come from the console.
+  pub fn resolve(&self, console: Cue) -> Cue {
+    let slot = console.preset.and_then(|slot| self.presets.get((slot as usize).checked_sub(1)?));
+    let recalled = self.recalled.as_ref().and_then(|name| self.find(name));
+
+    match slot.or(recalled) {
+      Some(preset) => Cue { control: console.control, stop_mode: console.stop_mode, ..preset.cue() },
+      None => console
+    }
+  }
+
+  /// Carries out a request from the API, where `live` is the cue the windmill is currently following.
+  pub fn handle(&mut self, request: PresetRequest, live: &Cue) -> Result<String, &'static str> {
+    match request {
+      PresetRequest::Recall(name) => {
+        self.find(&name).ok_or("no preset by that name")?;
+        self.recalled = Some(name.clone());
+        Ok(format!("recalled {name}"))
+      },
+
+      PresetRequest::Release => Ok(match self.recalled.take() {
+        Some(name) => format!("released {name}"),
+        None => String::from("nothing to release")
+      }),
+
+      PresetRequest::Capture(name) => {
+        let preset = Preset::capture(&name, live)?;
+
+        // The preset's line in the file is the first one that isn't blank or a comment and starts with its name.
+        let mut lines = self.lines.clone();
+        let line = lines.iter().position(|line| {
+          let line = line.trim();
+          !line.starts_with('#') && line.split_whitespace().next() == Some(name.as_str())
+        });
+
+        match line {
+          Some(index) => lines[index] = preset.to_string(),
+          None => lines.push(preset.to_string())
+        }
+
+        // The file goes first, so that if it can't be written, what's in memory still matches what's on disk.
+        self.save(&lines)?;
+        self.lines = lines;
+
+        match self.presets.iter_mut().find(|existing| existing.name == name) {
+          Some(existing) => *existing = preset,
+          None => self.presets.push(preset)
+        }
+
+        Ok(format!("captured {name}"))
+      },
+
+      PresetRequest::List => Ok(self.presets
+        .iter()
+        .enumerate()
+        .map(|(index, preset)| format!("{} {preset}", index + 1))
+        .collect::<Vec<_>>()
+        .join("\n"))
+    }
+  }
+
+  /// Finds a preset by name.
+  pub fn find(&self, name: &str) -> Option<&Preset> {
+    self.presets.iter().find(|preset| preset.name == name)
+  }
+
+  /// Writes `lines` out as the preset file, if there is one.
+  fn save(&self, lines: &[String]) -> Result<(), &'static str> {
+    let Some(path) = &self.path else {
+      return Ok(());
+    };
+
+    let contents = lines.iter().map(|line| format!("{line}\n")).collect::<String>();
+    std::fs::write(path, contents).map_err(|_| "failed to write the preset file")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::effects::EffectKind;
+
+  #[test]
+  fn presets_read_and_write_the_same_way() {
+    let preset = "storm forward:200 fade=8 effect=gust:180:60".parse::<Preset>().unwrap();
+
+    assert_eq!(Windmill::Forward(200), preset.windmill);
+    assert_eq!(Some(Duration::from_secs(8)), preset.fade);
+    assert_eq!(Some(Effect { kind: EffectKind::Gust, rate: 180, depth: 60 }), preset.effect);
+    assert_eq!("storm forward:200 fade=8 effect=gust:180:60", preset.to_string());
+    assert_eq!(Windmill::Off, "calm off".parse::<Preset>().unwrap().windmill);
+
+    assert!("".parse::<Preset>().is_err());
+    assert!("storm sideways:10".parse::<Preset>().is_err());
+    assert!("storm forward:256".parse::<Preset>().is_err());
+    assert!("storm forward:10 effect=gust:10".parse::<Preset>().is_err());
+    assert!(Preset::capture("#storm", &Cue::from(Windmill::Forward(10))).is_err());
+  }
+
+  #[test]
+  fn channel_beats_api_beats_console() {
+    let mut presets = Presets::load(None).unwrap();
+    let console = Cue::from(Windmill::Forward(10));
+
+    presets.handle(PresetRequest::Capture(String::from("first")), &Cue::from(Windmill::Reverse(1))).unwrap();
+    presets.handle(PresetRequest::Capture(String::from("second")), &Cue::from(Windmill::Reverse(2))).unwrap();
+    assert_eq!(console, presets.resolve(console));
+
+    presets.handle(PresetRequest::Recall(String::from("second")), &console).unwrap();
+    assert_eq!(Windmill::Reverse(2), presets.resolve(console).windmill);
+    assert_eq!(Windmill::Reverse(1), presets.resolve(Cue { preset: Some(1), ..console }).windmill);
+
+    presets.handle(PresetRequest::Release, &console).unwrap();
+    assert_eq!(console, presets.resolve(console));
+    assert!(presets.handle(PresetRequest::Recall(String::from("third")), &console).is_err());
+  }
+
+  #[test]
+  fn captures_are_saved_to_disk() {
+    let path = std::env::temp_dir().join(format!("windmill-presets-{}", std::process::id()));
+    std::fs::remove_file(&path).ok();
+
+    let mut presets = Presets::load(Some(path.clone())).unwrap();
+    presets.handle(PresetRequest::Capture(String::from("breeze")), &Cue::from(Windmill::Forward(40))).unwrap();
+
+    let reloaded = Presets::load(Some(path.clone())).unwrap();
+    assert_eq!(Some(Windmill::Forward(40)), reloaded.find("breeze").map(|preset| preset.windmill));
+
+    std::fs::remove_file(&path).ok();
+  }
+
+  #[test]
+  fn a_capture_that_cant_be_saved_isnt_kept() {
+    let path = std::env::temp_dir().join(format!("windmill-presets-missing-{}", std::process::id())).join("presets");
+    let mut presets = Presets::load(Some(path)).unwrap();
+
+    assert!(presets.handle(PresetRequest::Capture(String::from("breeze")), &Cue::from(Windmill::Forward(40))).is_err());
+    assert!(presets.find("breeze").is_none());
+    assert!(presets.lines.is_empty());
+  }
+
+  #[test]
+  fn captures_leave_the_rest_of_the_file_alone() {
+    let path = std::env::temp_dir().join(format!("windmill-presets-layout-{}", std::process::id()));
+    std::fs::write(&path, "# act one\nstorm forward:200 fade=8\n\n# act two\n  calm off\n").unwrap();
+
+    let mut presets = Presets::load(Some(path.clone())).unwrap();
+    presets.handle(PresetRequest::Capture(String::from("storm")), &Cue::from(Windmill::Reverse(90))).unwrap();
+    presets.handle(PresetRequest::Capture(String::from("breeze")), &Cue::from(Windmill::Forward(40))).unwrap();
+
+    assert_eq!(
+      "# act one\nstorm reverse:90\n\n# act two\n  calm off\nbreeze forward:40\n",
+      std::fs::read_to_string(&path).unwrap()
+    );
+
+    std::fs::remove_file(&path).ok();
+  }
+}
@@ -1,12 +1,24 @@
-use std::path::PathBuf;
+pub mod soft;
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Where the kernel exposes every PWM chip it knows about. Each chip shows up as a `pwmchip<N>` directory in here, which
+/// is the only reliable way I've found of figuring out what a new board actually calls its PWM controllers.
+pub const SYSFS_PWM_ROOT: &str = "/sys/class/pwm";
+
+/// Every channel with a live `Driver`, by the path of its chip. This lives out here rather than on a `PwmChip` so that
+/// opening the same chip twice (which `init` does every time it's called) still can't hand out the same channel twice.
+static CLAIMED: Mutex<BTreeSet<(PathBuf, u8)>> = Mutex::new(BTreeSet::new());
 
 /// The polarity of the PWM signal. For whatever it's worth, the OrangePi 3 LTS seems to default to `Inverse`. This has
 /// the implication that an inverse signal with a default zero duty cycle is actually held high. This is extremely
 /// annoying when booting up the OrangePi, as anything connected to it will potentially want to start to be driven.
 ///
 /// I've combated this by triggering the run/brake relay where the run will be high. Those pins will start low, and
-/// prevent the motor from actually running.
-#[derive(Copy, Clone)]
+/// prevent the motor from actually running. Where the kernel won't let us flip the polarity back, see `PolarityMode`.
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Polarity {
   /// Under `Normal` `Polarity`, the duty cycle of a PWM signal represents the active-high time, and the remaining time
   /// is spent low.
@@ -17,6 +29,198 @@ pub enum Polarity {
   Inverse
 }
 
+/// How a `Driver` should go about making its output behave as active-high (`Normal`), which is what everything else in
+/// the windmill assumes. Some chips let us write the polarity, some ignore the request, and some are wired up inverted
+/// with no way to change it. In the latter cases, the `Driver` can do the inversion itself by writing `period - duty`
+/// instead of `duty`, which ends up producing the exact same waveform on the pin.
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum PolarityMode {
+  /// Ask the kernel for `Normal` polarity, and fail if it can't be written.
+  Hardware,
+
+  /// Leave the kernel's polarity alone and assume it is stuck at `Inverse`. The `Driver` inverts every duty cycle
+  /// itself.
+  Logical,
+
+  /// Try `Hardware` first. If the polarity can't be written, fall back to `Logical` if the chip reports that it's
+  /// `Inverse`, otherwise carry on as-is.
+  Auto
+}
+
+/// The interface the rest of the windmill uses to drive the motor speed signal, regardless of what's actually generating
+/// it. Duty cycles are always expressed as a percentage (`0` to `100`) of active-high time.
+pub trait Output: Send + Sync {
+  /// Adjusts the duty cycle of the signal. Values above `100` are clamped.
+  fn set_duty_cycle(&self, duty_cycle: u8) -> Result<(), &'static str>;
+
+  /// Drives the signal to its "motor off" level, whatever that takes for this particular output.
+  fn set_safe(&self) -> Result<(), &'static str>;
+}
+
+/// `PwmChip` represents one of the `pwmchip<N>` controllers the kernel exposes. Boards love to renumber these between
+/// kernel versions and overlays, so rather than guessing indices, a `PwmChip` can be discovered, inspected, and then
+/// asked to hand out a `Driver` for each of its channels.
+///
+/// Which channels have been handed out is kept track of for the whole process, however many times a chip is opened, so
+/// the same channel can't accidentally be driven by two `Driver`s at once. Once a `Driver` is dropped, its channel is
+/// free to be claimed again.
+pub struct PwmChip {
+  /// The `N` in `pwmchip<N>`.
+  index: u8,
+
+  /// The path to the chip's directory, e.g. `/sys/class/pwm/pwmchip0`.
+  path: PathBuf,
+
+  /// How many channels the chip reports via `npwm`.
+  channel_count: u8,
+
+  /// The name of the device backing this chip, pulled from the `device` symlink. This is usually the most useful bit
+  /// of information when trying to match a chip up against a board's pinout or device tree.
+  device: Option<String>,
+
+  /// Whether or not `Driver`s handed out by this chip should unexport their channel when they are dropped.
+  unexport_on_drop: bool
+}
+
+impl PwmChip {
+  /// Discovers every PWM chip the kernel currently exposes, sorted by index. An empty list is not an error: it most
+  /// likely means that no PWM overlay has been enabled.
+  pub fn enumerate() -> Result<Vec<PwmChip>, &'static str> {
+    Self::enumerate_in(Path::new(SYSFS_PWM_ROOT))
+  }
+
+  /// Same as `enumerate`, but looks for chips underneath the given `root` rather than the real sysfs.
+  pub fn enumerate_in(root: &Path) -> Result<Vec<PwmChip>, &'static str> {
+    let entries = std::fs::read_dir(root)
+      .map_err(|_io_err| "unable to list pwm chips: is pwm enabled on your hardware?")?;
+
+    let mut chips = entries
+      .filter_map(|entry| entry.ok())
+      .filter_map(|entry| entry.file_name().to_str()?.strip_prefix("pwmchip")?.parse::<u8>().ok())
+      .map(|index| Self::open_in(root, index))
+      .collect::<Result<Vec<_>, _>>()?;
+
+    chips.sort_by_key(|chip| chip.index);
+    Ok(chips)
+  }
+
+  /// Opens the chip `pwmchip<index>`. Returns an error if the query isn't able to be made (permissions issue, or pwm not
+  /// enabled on the device), or if the chip reports a channel count we can't interpret.
+  pub fn open(index: u8) -> Result<Self, &'static str> {
+    Self::open_in(Path::new(SYSFS_PWM_ROOT), index)
+  }
+
+  /// Same as `open`, but looks for the chip underneath the given `root` rather than the real sysfs.
+  pub fn open_in(root: &Path, index: u8) -> Result<Self, &'static str> {
+    let path = root.join(format!("pwmchip{index}"));
+
+    let channel_count = std::fs::read_to_string(path.join("npwm"))
+      .map_err(|_io_err| "unable to detect pwm chip: is it enabled on your hardware?")
+      .and_then(|result|
+        result
+          .trim()
+          .parse::<u8>()
+          .map_err(|_parse_err|
+            "unable to parse pwm channel count: perhaps it is configured incorrectly?"
+          )
+      )?;
+
+    let device = std::fs::read_link(path.join("device"))
+      .ok()
+      .and_then(|target| target.file_name().map(|name| name.to_string_lossy().into_owned()));
+
+    Ok(PwmChip {
+      index,
+      path,
+      channel_count,
+      device,
+      unexport_on_drop: false
+    })
+  }
+
+  /// Configures whether `Driver`s handed out from here on should unexport their channel when dropped. By default
+  /// channels are left exported, which is friendlier to restarts (and to anything else on the system that expects them
+  /// to be there).
+  pub fn with_unexport_on_drop(mut self, unexport_on_drop: bool) -> Self {
+    self.unexport_on_drop = unexport_on_drop;
+    self
+  }
+
+  /// The `N` in `pwmchip<N>`.
+  pub fn index(&self) -> u8 {
+    self.index
+  }
+
+  /// How many channels this chip supports.
+  pub fn channel_count(&self) -> u8 {
+    self.channel_count
+  }
+
+  /// The name of the device backing this chip, if the kernel told us.
+  pub fn device(&self) -> Option<&str> {
+    self.device.as_deref()
+  }
+
+  /// Whether the given `channel` has already been exported, either by us or by someone else.
+  pub fn is_exported(&self, channel: u8) -> bool {
+    std::fs::metadata(self.path.join(format!("pwm{channel}"))).is_ok()
+  }
+
+  /// Claims `channel` on this chip and initializes a `Driver` for it that operates at the given `frequency`. To start,
+  /// the channel will behave as `Normal` `Polarity` (achieved however `polarity_mode` says to) and will start at a
+  /// `duty_cycle` of `0` regardless of frequency setting. Fails if the chip doesn't have that many channels, or if the
+  /// channel is already being driven.
+  pub fn driver(&self, channel: u8, frequency: u16, polarity_mode: PolarityMode) -> Result<Driver, &'static str> {
+    if channel >= self.channel_count {
+      return Err("there aren't enough channels on the specified chip to support the pwm interface");
+    }
+
+    if !CLAIMED.lock().map_err(|_| "pwm chip claims were poisoned")?.insert((self.path.clone(), channel)) {
+      return Err("the requested pwm channel is already claimed by another driver");
+    }
+
+    let claim = Claim {
+      chip: self.path.clone(),
+      channel,
+      unexport: self.unexport_on_drop.then(|| self.path.join("unexport"))
+    };
+
+    let mut driver: Driver = Driver::new(&self.path, channel, frequency, claim);
+    driver.ensure_export_channel()?;
+    driver.apply_polarity_mode(polarity_mode)?;
+    driver.set_frequency()?;
+    driver.set_duty_cycle(0)?;
+    driver.set_enabled(true)?;
+
+    Ok(driver)
+  }
+}
+
+/// A `Driver`'s hold on its channel. Releasing this (on drop) frees the channel up to be claimed again, and unexports it
+/// if the chip was configured to do so.
+struct Claim {
+  /// The path to the chip the channel belongs to.
+  chip: PathBuf,
+
+  /// The channel being held.
+  channel: u8,
+
+  /// The chip's `unexport` control, if the channel should be unexported on release.
+  unexport: Option<PathBuf>
+}
+
+impl Drop for Claim {
+  fn drop(&mut self) {
+    if let Some(unexport) = &self.unexport {
+      std::fs::write(unexport, self.channel.to_string()).ok();
+    }
+
+    if let Ok(mut claimed) = CLAIMED.lock() {
+      claimed.remove(&(std::mem::take(&mut self.chip), self.channel));
+    }
+  }
+}
+
 /// `Driver` is a PWM driver representation that can own a physical GPIO pin (that is compatible with hardware PWM) and
 /// drive it at various frequencies and duty cycles. This happens in userspace, so performance is pretty decent from the
 /// get-go because we don't have to continually jump into kernel space to interface with the pin.
@@ -33,6 +237,10 @@ pub struct Driver {
   /// The `pwmchip` channel to be driven.
   channel: u8,
 
+  /// Whether this `Driver` is inverting duty cycles itself because the hardware is stuck at `Inverse` `Polarity`. See
+  /// `PolarityMode`.
+  inverted: bool,
+
   /// The pin's `period` as a pre-allocated `String`. This driver focuses on runtime performance over flexibility, so it
   /// does not make any optimizations for dynamically changing the period. It should be possible to `Drop` this `Driver`
   /// and instantiate a new one for a given `chip` and `channel` with a new `frequency` to adjust the period: but this
@@ -45,6 +253,9 @@ pub struct Driver {
   /// from what I've seen with all (albeit a small amount) of the boards and controllers -- this signal is often exposed
   /// to the user on a scale of 0->100, so it's probably moot to expose that much granularity anyway, since the end user
   /// can't actually access it.
+  ///
+  /// When the `Driver` is `inverted`, this map already holds `period - duty` for each entry, so writes stay just as
+  /// cheap either way.
   duty_cycle_map: [String; 101],
 
   /// To make the compiler happy where it can't verify things at compile-time, this is the value that should be exported
@@ -52,7 +263,10 @@ pub struct Driver {
   default_duty_cycle_string: String,
 
   /// A collection of pre-allocated paths to the various controls of the PWM chip and channel this `Driver` controls.
-  paths: Paths
+  paths: Paths,
+
+  /// This `Driver`'s claim on its channel. Held purely so that it is released when the `Driver` goes away.
+  _claim: Claim
 }
 
 /// Internal helper struct to allow access to scoped paths for either the PWM chip itself, or one of it's internal
@@ -67,9 +281,6 @@ struct Paths {
 
 /// Internal helper struct to allow access to paths related to the PWM chip this `Driver` controls.
 struct ChipPaths {
-  /// The path to the read-only query for how many channels the PWM chip supports.
-  max_channels: PathBuf,
-
   /// The path to the write-only mutation for the channel you want to export. Note: this will fail if called multiple
   /// times (i.e. already exported). So this should not be made public as it's arguably unsafe to call without meeting
   /// preconditions.
@@ -94,60 +305,36 @@ struct ChannelPaths {
 }
 
 impl Driver {
-  /// Creates a new `Driver` to control the given pwmchip indexed `chip` and channel indexed `channel`. It will operate
-  /// at the given `frequency` in Hz (e.g. 10_000 for 10kHz). No guarding is taken over the frequency, it is up to the
-  /// caller to understand their hardware and the support it has.
-  fn new(chip: u8, channel: u8, frequency: u16) -> Self {
+  /// Creates a new `Driver` to control channel indexed `channel` of the pwmchip living at `chip_path`. It will operate
+  /// at the given `frequency` in Hz (e.g. 10_000 for 10kHz), which mustn't be zero.
+  fn new(chip_path: &Path, channel: u8, frequency: u16, claim: Claim) -> Self {
     // PWM period time is set in nanoseconds, so convert incoming frequency to period.
     let period: u64 = 1_000_000_000u64 / frequency as u64;
+    let channel_path = chip_path.join(format!("pwm{channel}"));
 
     Driver {
       channel,
+      inverted: false,
       period_string: period.to_string(),
-      duty_cycle_map: Self::calculate_duty_cycle_map(period),
+      duty_cycle_map: Self::calculate_duty_cycle_map(period, false),
       default_duty_cycle_string: String::from("0"),
       paths: Paths {
         chip: ChipPaths {
-          max_channels: PathBuf::from(format!("/sys/class/pwm/pwmchip{chip}/npwm")),
-          export: PathBuf::from(format!("/sys/class/pwm/pwmchip{chip}/export"))
+          export: chip_path.join("export")
         },
         channel: ChannelPaths {
-          polarity: PathBuf::from(format!("/sys/class/pwm/pwmchip{chip}/pwm{channel}/polarity")),
-          period: PathBuf::from(format!("/sys/class/pwm/pwmchip{chip}/pwm{channel}/period")),
-          duty_cycle: PathBuf::from(format!("/sys/class/pwm/pwmchip{chip}/pwm{channel}/duty_cycle")),
-          enable: PathBuf::from(format!("/sys/class/pwm/pwmchip{chip}/pwm{channel}/enable"))
+          polarity: channel_path.join("polarity"),
+          period: channel_path.join("period"),
+          duty_cycle: channel_path.join("duty_cycle"),
+          enable: channel_path.join("enable")
         }
-      }
+      },
+      _claim: claim
     }
   }
 
-  /// Sanity check to make sure that the `pwmchip` indexed has support for the `channel` that is provided. Returns an
-  /// error if the query isn't able to be made (permissions issue, or pwm not enabled on the device), if the query
-  /// returns a response we can't interpret, or finally if the query returns a value saying that we've tried to allocate
-  /// a channel higher than it supports.
-  fn check_available_channels(&self) -> Result<(), &'static str> {
-    let available_channels = std::fs::read_to_string(&self.paths.chip.max_channels)
-      .map_err(|_io_err| "unable to detect pwm chip: is it enabled on your hardware?")
-      .and_then(|result|
-        result.
-          trim()
-          .parse::<u8>()
-          .map_err(|_parse_err|
-            "unable to parse pwm channel count: perhaps it is configured incorrectly?"
-          )
-      )?;
-
-    if self.channel > available_channels {
-      Err("there aren't enough channels on the specified chip to support the pwm interface")
-    }
-
-    else {
-      Ok(())
-    }
-  }
-
-  /// Exports the channel desired from the pwmchip, if necessary. Given the query call before this one, if this fails
-  /// there is likely a hardware problem.
+  /// Exports the channel desired from the pwmchip, if necessary. Given the chip was already queried for its channel
+  /// count before this, if this fails there is likely a hardware problem.
   fn ensure_export_channel(&self) -> Result<(), &'static str> {
     // The channel already exists. It's either been exported externally (i.e. mapped to an existing external export) or
     // was exported by us previously (e.g. application restart). No need to panic here.
@@ -176,6 +363,43 @@ impl Driver {
     ).map_err(|_io_err| "failed to update polarity for chip channel")
   }
 
+  /// Reads back the `Polarity` the channel is currently running at, if the kernel is willing to tell us.
+  pub fn polarity(&self) -> Option<Polarity> {
+    match std::fs::read_to_string(&self.paths.channel.polarity).ok()?.trim() {
+      "normal" => Some(Polarity::Normal),
+      "inverse" | "inversed" => Some(Polarity::Inverse),
+      _ => None
+    }
+  }
+
+  /// Whether this `Driver` is inverting duty cycles itself rather than relying on the hardware.
+  pub fn is_logically_inverted(&self) -> bool {
+    self.inverted
+  }
+
+  /// Gets the channel behaving as `Normal` `Polarity` according to the given `PolarityMode`. This has to happen before
+  /// the first duty cycle is written, as it decides what every duty cycle is written as.
+  fn apply_polarity_mode(&mut self, polarity_mode: PolarityMode) -> Result<(), &'static str> {
+    let inverted = match polarity_mode {
+      PolarityMode::Hardware => {
+        self.set_polarity(Polarity::Normal)?;
+        false
+      },
+
+      PolarityMode::Logical => true,
+
+      // If the kernel can't (or won't) take the write, trust whatever it says it's doing. If it won't even tell us,
+      // there's a good chance the chip has no concept of polarity at all, and those are active-high in my experience.
+      PolarityMode::Auto => self.set_polarity(Polarity::Normal).is_err() && self.polarity() == Some(Polarity::Inverse)
+    };
+
+    let period = self.period_string.parse::<u64>().map_err(|_| "pwm period was not a number")?;
+    self.inverted = inverted;
+    self.duty_cycle_map = Self::calculate_duty_cycle_map(period, inverted);
+
+    Ok(())
+  }
+
   /// Sets the frequency of the channel. As you may have read numerous times already (if not, please read the
   /// documentation for `Driver`), or guessed from the lack of `pub` or parameters: this merely initializes the
   /// frequency (which is represented as a `period` under the hood). This `Driver` implementation is not optimized for
@@ -198,6 +422,22 @@ impl Driver {
       .map_err(|_io_err| "failed to update duty cycle for chip channel")
   }
 
+  /// Drives the channel to its "motor off" level. Normally that's a zero duty cycle with the channel disabled, but when
+  /// we're inverting logically, a disabled channel idles at the hardware's inactive level -- which is high on an
+  /// `Inverse` chip. So in that case the channel stays enabled and is held at a full (inverted) period instead, which
+  /// keeps the pin low.
+  pub fn set_safe(&self) -> Result<(), &'static str> {
+    self.set_duty_cycle(0)?;
+
+    if self.inverted {
+      Ok(())
+    }
+
+    else {
+      self.set_enabled(false)
+    }
+  }
+
   /// Enables or disables the PWM channel. This does not invalidate the driver and can continue to be used and
   /// re-enabled after being disabled.
   pub fn set_enabled(&self, enabled: bool) -> Result<(), &'static str> {
@@ -206,40 +446,167 @@ impl Driver {
   }
 
   /// Internal helper to calculate string representations of every possible `duty_cycle` input value. These are
-  /// effectively `String` representations of percentage slices of the input `period`, with 1% granularity.
-  fn calculate_duty_cycle_map(period: u64) -> [String; 101] {
+  /// effectively `String` representations of percentage slices of the input `period`, with 1% granularity. When
+  /// `inverted`, each slice is subtracted from the `period` instead.
+  fn calculate_duty_cycle_map(period: u64, inverted: bool) -> [String; 101] {
     const EMPTY_STRING: String = String::new();
     let period_pulse: u64 = period / 100u64;
 
     let mut map: [String; 101] = [ EMPTY_STRING; 101 ];
 
     for i in 0u64..=100 {
-      map[i as usize] = (period_pulse * i).to_string()
+      let active = period_pulse * i;
+      map[i as usize] = if inverted { period - active } else { active }.to_string()
     }
 
     map
   }
 }
 
+impl Output for Driver {
+  fn set_duty_cycle(&self, duty_cycle: u8) -> Result<(), &'static str> {
+    Driver::set_duty_cycle(self, duty_cycle)
+  }
+
+  fn set_safe(&self) -> Result<(), &'static str> {
+    Driver::set_safe(self)
+  }
+}
+
 /// Simple `Drop` implementation that shuts down the `Driver` if it can. `Drop` cannot `Err`, so this is a best-attempt
-/// sort of thing.
+/// sort of thing. The channel's claim is released (and unexported, if configured) after this runs.
 impl Drop for Driver {
   fn drop(&mut self) {
-    self.set_duty_cycle(0).ok();
-    self.set_enabled(false).ok();
+    self.set_safe().ok();
   }
 }
 
-/// Initializes the PWM system on a given `chip` and `channel` to operate at the given `frequency`. To start, this will
-/// operate at `Normal` `Polarity` and will start at a `duty_cycle` of `0` regardless of frequency setting.
-pub fn init(chip: u8, channel: u8, frequency: u16) -> Result<Driver, &'static str> {
-  let driver: Driver = Driver::new(chip, channel, frequency);
-  driver.check_available_channels()?;
-  driver.ensure_export_channel()?;
-  driver.set_polarity(Polarity::Normal)?;
-  driver.set_frequency()?;
-  driver.set_duty_cycle(0)?;
-  driver.set_enabled(true)?;
+/// Initializes the PWM system on a given `chip` and `channel` to operate at the given `frequency`. This is shorthand for
+/// opening the `PwmChip` and asking it for a `Driver`, for when you already know exactly which chip you want.
+pub fn init(chip: u8, channel: u8, frequency: u16, polarity_mode: PolarityMode) -> Result<Driver, &'static str> {
+  PwmChip::open(chip)?.driver(channel, frequency, polarity_mode)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Builds a throwaway directory that looks enough like `/sys/class/pwm` to fool a `PwmChip`. Each chip gets an `npwm`,
+  /// a `device` symlink, and every one of its channels pre-exported (since there's no kernel here to do it for us).
+  fn fake_sysfs(name: &str, chips: &[(u8, u8, &str)]) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("windmill-pwm-{}-{name}", std::process::id()));
+    std::fs::remove_dir_all(&root).ok();
+
+    for (index, channels, device) in chips {
+      let chip = root.join(format!("pwmchip{index}"));
+      std::fs::create_dir_all(&chip).unwrap();
+      std::fs::write(chip.join("npwm"), format!("{channels}\n")).unwrap();
+      std::fs::write(chip.join("export"), "").unwrap();
+      std::fs::write(chip.join("unexport"), "").unwrap();
+      std::os::unix::fs::symlink(format!("../../../devices/platform/{device}"), chip.join("device")).unwrap();
+
+      for channel in 0..*channels {
+        let channel = chip.join(format!("pwm{channel}"));
+        std::fs::create_dir_all(&channel).unwrap();
+        std::fs::write(channel.join("enable"), "0").unwrap();
+        std::fs::write(channel.join("polarity"), "inverse").unwrap();
+      }
+    }
+
+    root
+  }
+
+  #[test]
+  fn enumerates_chips_in_order_with_device_names() {
+    let root = fake_sysfs("enumerate", &[(4, 1, "r_pwm"), (0, 2, "300a000.pwm")]);
+    let chips = PwmChip::enumerate_in(&root).unwrap();
+
+    assert_eq!(vec![0, 4], chips.iter().map(PwmChip::index).collect::<Vec<_>>());
+    assert_eq!(2, chips[0].channel_count());
+    assert_eq!(Some("300a000.pwm"), chips[0].device());
+    assert_eq!(Some("r_pwm"), chips[1].device());
+  }
+
+  #[test]
+  fn rejects_channel_equal_to_channel_count() {
+    let root = fake_sysfs("off-by-one", &[(0, 1, "300a000.pwm")]);
+    let chip = PwmChip::open_in(&root, 0).unwrap();
+
+    assert!(chip.driver(0, 20000, PolarityMode::Hardware).is_ok());
+    assert!(chip.driver(1, 20000, PolarityMode::Hardware).is_err());
+  }
+
+  #[test]
+  fn does_not_double_claim_channels() {
+    let root = fake_sysfs("claims", &[(0, 2, "300a000.pwm")]);
+    let chip = PwmChip::open_in(&root, 0).unwrap();
+
+    let driver = chip.driver(0, 20000, PolarityMode::Hardware).unwrap();
+    assert!(chip.driver(0, 20000, PolarityMode::Hardware).is_err());
+    assert!(chip.driver(1, 20000, PolarityMode::Hardware).is_ok());
+
+    drop(driver);
+    assert!(chip.driver(0, 20000, PolarityMode::Hardware).is_ok());
+  }
+
+  #[test]
+  fn claims_hold_across_separate_opens_of_a_chip() {
+    let root = fake_sysfs("reopen", &[(0, 1, "300a000.pwm")]);
 
-  Ok(driver)
+    let driver = PwmChip::open_in(&root, 0).unwrap().driver(0, 20000, PolarityMode::Hardware).unwrap();
+    assert!(PwmChip::open_in(&root, 0).unwrap().driver(0, 20000, PolarityMode::Hardware).is_err());
+
+    drop(driver);
+    assert!(PwmChip::open_in(&root, 0).unwrap().driver(0, 20000, PolarityMode::Hardware).is_ok());
+  }
+
+  #[test]
+  fn unexports_on_drop_when_asked() {
+    let root = fake_sysfs("unexport", &[(0, 2, "300a000.pwm")]);
+    let chip = PwmChip::open_in(&root, 0).unwrap().with_unexport_on_drop(true);
+
+    drop(chip.driver(1, 20000, PolarityMode::Hardware).unwrap());
+    assert_eq!("1", std::fs::read_to_string(root.join("pwmchip0/unexport")).unwrap());
+  }
+
+  #[test]
+  fn logical_inversion_writes_period_minus_duty() {
+    let root = fake_sysfs("logical", &[(0, 1, "300a000.pwm")]);
+    let chip = PwmChip::open_in(&root, 0).unwrap();
+    let driver = chip.driver(0, 20000, PolarityMode::Logical).unwrap();
+    let duty_cycle = root.join("pwmchip0/pwm0/duty_cycle");
+
+    assert!(driver.is_logically_inverted());
+    assert_eq!("inverse", std::fs::read_to_string(root.join("pwmchip0/pwm0/polarity")).unwrap());
+    assert_eq!("50000", std::fs::read_to_string(&duty_cycle).unwrap());
+
+    driver.set_duty_cycle(25).unwrap();
+    assert_eq!("37500", std::fs::read_to_string(&duty_cycle).unwrap());
+  }
+
+  #[test]
+  fn logical_inversion_stays_enabled_when_dropped() {
+    let root = fake_sysfs("logical-drop", &[(0, 1, "300a000.pwm")]);
+    let chip = PwmChip::open_in(&root, 0).unwrap();
+
+    let driver = chip.driver(0, 20000, PolarityMode::Logical).unwrap();
+    driver.set_duty_cycle(80).unwrap();
+    drop(driver);
+
+    assert_eq!("1", std::fs::read_to_string(root.join("pwmchip0/pwm0/enable")).unwrap());
+    assert_eq!("50000", std::fs::read_to_string(root.join("pwmchip0/pwm0/duty_cycle")).unwrap());
+  }
+
+  #[test]
+  fn auto_prefers_hardware_polarity() {
+    let root = fake_sysfs("auto", &[(0, 1, "300a000.pwm")]);
+    let chip = PwmChip::open_in(&root, 0).unwrap();
+    let driver = chip.driver(0, 20000, PolarityMode::Auto).unwrap();
+
+    assert!(!driver.is_logically_inverted());
+    assert_eq!(Some(Polarity::Normal), driver.polarity());
+    drop(driver);
+
+    assert_eq!("0", std::fs::read_to_string(root.join("pwmchip0/pwm0/enable")).unwrap());
+  }
 }
@@ -0,0 +1,50 @@
+use crate::pwm::Output;
+use crate::wiringpi;
+
+/// The number of steps in a software PWM cycle. WiringPi's software PWM ticks in 100µs increments, so a range of `100`
+/// gives us a 10ms period (100Hz) and lines up one-to-one with the percentage duty cycles everything else speaks.
+const RANGE: i32 = 100;
+
+/// `SoftDriver` generates a PWM signal on any old GPIO pin by having WiringPi toggle it from a dedicated, high priority
+/// thread. This is a fallback for boards (or pins) without a hardware PWM overlay, and it is very much a fallback:
+///
+///   - The frequency is fixed at 100Hz, way down in the audible range for a motor controller.
+///   - Pulse widths are only as accurate as the scheduler is at waking that thread up, so expect jitter, especially
+///     when the board is busy.
+///   - It costs a fair bit of CPU just to keep the pin wiggling.
+///
+/// None of that really matters for a fan or an LED on a small, low power prop. I wouldn't put a twelve foot windmill on
+/// it though.
+pub struct SoftDriver {
+  /// The WiringPi pin number being driven.
+  pin: i32
+}
+
+impl SoftDriver {
+  /// Starts driving software PWM on the given WiringPi `pin`, beginning at a duty cycle of `0`. WiringPi must already
+  /// have been initialized.
+  pub fn new(pin: i32) -> Result<Self, &'static str> {
+    wiringpi::soft_pwm_create(pin, 0, RANGE)?;
+
+    Ok(SoftDriver { pin })
+  }
+}
+
+impl Output for SoftDriver {
+  fn set_duty_cycle(&self, duty_cycle: u8) -> Result<(), &'static str> {
+    wiringpi::soft_pwm_write(self.pin, std::cmp::min(duty_cycle as i32, RANGE));
+    Ok(())
+  }
+
+  /// A software PWM pin is just a GPIO pin we're toggling ourselves, so it's always active-high and `0` means low.
+  fn set_safe(&self) -> Result<(), &'static str> {
+    self.set_duty_cycle(0)
+  }
+}
+
+/// Stops the PWM thread, which also leaves the pin low.
+impl Drop for SoftDriver {
+  fn drop(&mut self) {
+    wiringpi::soft_pwm_stop(self.pin);
+  }
+}
@@ -0,0 +1,334 @@
+use std::time::Duration;
+
+/// The full range of speeds a `Windmill` can be asked for. Ramp rates are all expressed relative to this, so "ten
+/// seconds to full speed" means ten seconds to go from `0` to this.
+const FULL_SPEED: f64 = u8::MAX as f64;
+
+/// The most the target can move in one step and still be the same fade. Smoothing and effects nudge the target by
+/// about this much a tick; anything bigger is the console asking for somewhere new.
+const FADE_JITTER: f64 = 5.0;
+
+/// Describes how quickly the windmill is allowed to change speed, in real (physical) time rather than in loop cycles.
+/// Speeding up and slowing down are described separately, since a big heavy rotor is generally happy to coast down a
+/// lot faster than it's happy to be shoved up to speed.
+///
+/// Optionally, the profile can be an S-curve. Rather than slamming straight into its full rate of acceleration (which
+/// is the "jerk" you feel in the blades and the mounting), the rate itself is eased in and out over `s_curve`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RampProfile {
+  /// How long it takes to go from stopped to full speed.
+  pub acceleration: Duration,
+
+  /// How long it takes to go from full speed to stopped.
+  pub deceleration: Duration,
+
+  /// How long it takes for the rate of change to build up to (or fall away from) its maximum. `None` means the rate
+  /// changes instantly, which gives a plain linear ramp.
+  pub s_curve: Option<Duration>
+}
+
+impl RampProfile {
+  /// Maximum change in speed per second while speeding up.
+  fn acceleration_rate(&self) -> f64 {
+    Self::rate(self.acceleration)
+  }
+
+  /// Maximum change in speed per second while slowing down.
+  fn deceleration_rate(&self) -> f64 {
+    Self::rate(self.deceleration)
+  }
+
+  /// Converts a "time to full speed" into a change in speed per second. A zero duration means "as fast as you like".
+  fn rate(time_to_full_speed: Duration) -> f64 {
+    Self::rate_over(FULL_SPEED, time_to_full_speed)
+  }
+
+  /// The change in speed per second that covers `distance` in `time`. A zero duration means "as fast as you like".
+  fn rate_over(distance: f64, time: Duration) -> f64 {
+    match time.as_secs_f64() {
+      seconds if seconds > 0.0 => distance / seconds,
+      _ => f64::INFINITY
+    }
+  }
+}
+
+/// A speed change that the console has asked to take a particular amount of time, rather than happen as fast as the
+/// profile allows. The target is allowed to move a little while the fade is under way (smoothing and effects nudge it
+/// every tick), but the deadline doesn't: the rate is worked out afresh each step from however much time is left. A
+/// bigger move is a new cue, and gets a whole new fade.
+#[derive(Copy, Clone, Debug)]
+struct Fade {
+  /// Where the fade is headed.
+  target: f64,
+
+  /// How long the whole fade was asked to take.
+  duration: Duration,
+
+  /// How much of that is left.
+  remaining: Duration
+}
+
+impl Fade {
+  /// The rate that covers `distance` in the time that's left, in units per second.
+  fn rate(&self, distance: f64) -> f64 {
+    RampProfile::rate_over(distance, self.remaining)
+  }
+}
+
+/// `Ramp` walks a speed towards a target according to a `RampProfile`, given however much time has actually passed since
+/// it was last asked. Because it's driven by elapsed time, it doesn't care how often it's stepped or how long the rest
+/// of the control loop takes: ten steps of 10ms land in the same place as one step of 100ms.
+///
+/// Speeds are tracked with more precision than a `Windmill` can hold, so slow ramps still make progress even when each
+/// individual step is worth a fraction of a unit.
+#[derive(Copy, Clone, Debug)]
+pub struct Ramp {
+  /// The rules to ramp by.
+  profile: RampProfile,
+
+  /// Where we currently are, in the same units as a `Windmill` speed.
+  speed: f64,
+
+  /// How quickly the speed is currently changing, in units per second. Only meaningful for S-curves; linear ramps jump
+  /// straight to their full rate.
+  rate: f64,
+
+  /// How long speed changes should take, if the console has asked for a particular time.
+  fade_time: Option<Duration>,
+
+  /// The timed speed change in progress, if there is one.
+  fade: Option<Fade>
+}
+
+impl Ramp {
+  /// Creates a new `Ramp` that starts out stopped.
+  pub fn new(profile: RampProfile) -> Self {
+    Ramp {
+      profile,
+      speed: 0.0,
+      rate: 0.0,
+      fade_time: None,
+      fade: None
+    }
+  }
+
+  /// The profile this `Ramp` follows.
+  pub fn profile(&self) -> RampProfile {
+    self.profile
+  }
+
+  /// Puts the `Ramp` back at a standstill. This should happen whenever the windmill is stopped out from under the ramp,
+  /// e.g. by the brake.
+  pub fn reset(&mut self) {
+    self.speed = 0.0;
+    self.rate = 0.0;
+    self.fade = None;
+  }
+
+  /// Asks for speed changes to take `fade_time` from here on, rather than going as fast as the profile allows. The fade
+  /// still can't go any faster than the profile does, so a short fade just means "as fast as you can". Setting the
+  /// same time again leaves a fade in progress alone; setting a different one starts over from wherever we are.
+  pub fn set_fade_time(&mut self, fade_time: Option<Duration>) {
+    if fade_time != self.fade_time {
+      self.fade_time = fade_time;
+      self.fade = None;
+    }
+  }
+
+  /// How far through the current fade we are, from `0.0` to `1.0`, if there is one.
+  pub fn fade_progress(&self) -> Option<f64> {
+    self.fade.map(|fade| {
+      if fade.target == self.speed || fade.duration.is_zero() {
+        1.0
+      }
+
+      else {
+        1.0 - fade.remaining.as_secs_f64() / fade.duration.as_secs_f64()
+      }
+    })
+  }
+
+  /// Moves towards `target` by however far the profile (and any fade) allows in `elapsed` time, and returns where we
+  /// ended up. The ramp never overshoots the target.
+  pub fn step(&mut self, target: u8, elapsed: Duration) -> u8 {
+    let target = target as f64;
+    let seconds = elapsed.as_secs_f64();
+    let error = target - self.speed;
+
+    // A new target means a new fade, timed from right now. Unless there's a fade still under way and the target has
+    // only been nudged, in which case it just heads for the new target instead, still due to finish when it was always
+    // going to.
+    if let Some(fade_time) = self.fade_time {
+      let nudged = self.fade.is_some_and(|fade| (target - fade.target).abs() <= FADE_JITTER);
+
+      match &mut self.fade {
+        Some(fade) if nudged && fade.target != self.speed && !fade.remaining.is_zero() => fade.target = target,
+        Some(fade) if fade.target == target => {},
+        _ => self.fade = Some(Fade { target, duration: fade_time, remaining: fade_time })
+      }
+    }
+
+    let fade_rate = self.fade.map_or(f64::INFINITY, |fade| fade.rate(error.abs()));
+
+    if let Some(fade) = &mut self.fade {
+      fade.remaining = fade.remaining.saturating_sub(elapsed);
+    }
+
+    if error == 0.0 || seconds <= 0.0 {
+      self.rate = if error == 0.0 { 0.0 } else { self.rate };
+      return self.current();
+    }
+
+    let max_rate = if error > 0.0 { self.profile.acceleration_rate() } else { self.profile.deceleration_rate() };
+    let max_rate = f64::min(max_rate, fade_rate);
+
+    let rate = match self.profile.s_curve.map(|s_curve| s_curve.as_secs_f64()) {
+      Some(s_curve) if s_curve > 0.0 && max_rate.is_finite() => {
+        // The rate is allowed to change by `jerk` per second. To land on the target without overshooting, the rate we
+        // aim for also has to shrink as we get close, so that there's enough room left to ease the rate back to zero.
+        let jerk = max_rate / s_curve;
+        let desired = error.signum() * f64::min(max_rate, (2.0 * jerk * error.abs()).sqrt());
+        let change = (desired - self.rate).clamp(-jerk * seconds, jerk * seconds);
+
+        self.rate + change
+      },
+
+      _ => error.signum() * max_rate
+    };
+
+    let next = self.speed + rate * seconds;
+
+    // Reached (or would have passed) the target: settle there.
+    if (error > 0.0 && next >= target) || (error < 0.0 && next <= target) || !next.is_finite() {
+      self.speed = target;
+      self.rate = 0.0;
+    }
+
+    else {
+      self.speed = next;
+      self.rate = rate;
+    }
+
+    self.current()
+  }
+
+  /// The current speed, rounded to something a `Windmill` can hold.
+  pub fn current(&self) -> u8 {
+    self.speed.round().clamp(0.0, FULL_SPEED) as u8
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn linear(acceleration: u64, deceleration: u64) -> RampProfile {
+    RampProfile {
+      acceleration: Duration::from_secs(acceleration),
+      deceleration: Duration::from_secs(deceleration),
+      s_curve: None
+    }
+  }
+
+  #[test]
+  fn ramps_by_elapsed_time_not_step_count() {
+    let mut coarse = Ramp::new(linear(1, 1));
+    let mut fine = Ramp::new(linear(1, 1));
+
+    coarse.step(255, Duration::from_millis(100));
+
+    for _ in 0..10 {
+      fine.step(255, Duration::from_millis(10));
+    }
+
+    assert!((coarse.speed - fine.speed).abs() < 1e-9);
+    assert_eq!(26, coarse.current());
+  }
+
+  #[test]
+  fn accelerates_and_decelerates_at_separate_rates() {
+    let mut ramp = Ramp::new(linear(10, 2));
+
+    assert_eq!(26, ramp.step(255, Duration::from_secs(1)));
+    assert_eq!(255, ramp.step(255, Duration::from_secs(9)));
+    assert_eq!(128, ramp.step(0, Duration::from_secs(1)));
+  }
+
+  #[test]
+  fn never_overshoots_the_target() {
+    let mut ramp = Ramp::new(linear(1, 1));
+
+    assert_eq!(100, ramp.step(100, Duration::from_secs(5)));
+    assert_eq!(90, ramp.step(90, Duration::from_secs(5)));
+  }
+
+  #[test]
+  fn s_curve_eases_in_and_settles() {
+    let profile = RampProfile { s_curve: Some(Duration::from_secs(1)), ..linear(2, 2) };
+    let mut s_curve = Ramp::new(profile);
+    let mut linear = Ramp::new(linear(2, 2));
+
+    // Easing in means the S-curve starts out well behind a linear ramp.
+    s_curve.step(255, Duration::from_millis(500));
+    linear.step(255, Duration::from_millis(500));
+    assert!(s_curve.current() < linear.current());
+
+    let mut previous = s_curve.current();
+
+    for _ in 0..1000 {
+      let next = s_curve.step(255, Duration::from_millis(10));
+      assert!(next >= previous);
+      previous = next;
+    }
+
+    assert_eq!(255, s_curve.current());
+  }
+
+  #[test]
+  fn fades_take_as_long_as_asked_but_no_faster_than_the_profile() {
+    let mut ramp = Ramp::new(linear(1, 1));
+    ramp.set_fade_time(Some(Duration::from_secs(8)));
+
+    assert_eq!(77, ramp.step(153, Duration::from_secs(4)));
+    assert_eq!(Some(0.5), ramp.fade_progress().map(|progress| (progress * 100.0).round() / 100.0));
+    assert_eq!(153, ramp.step(153, Duration::from_secs(4)));
+    assert_eq!(Some(1.0), ramp.fade_progress());
+
+    // A fade shorter than the profile allows is held to the profile.
+    ramp.set_fade_time(Some(Duration::from_millis(100)));
+    assert_eq!(128, ramp.step(0, Duration::from_millis(100)));
+  }
+
+  #[test]
+  fn fades_keep_their_deadline_when_the_target_moves() {
+    let mut ramp = Ramp::new(linear(1, 1));
+    ramp.set_fade_time(Some(Duration::from_secs(4)));
+
+    // A smoothed fader creeps up on where it's going, so the target moves a little every tick.
+    let mut target = 0.0f64;
+
+    for tick in 1..=400 {
+      target += (200.0 - target) * 0.02;
+      ramp.step(target.round() as u8, Duration::from_millis(10));
+
+      if tick == 200 {
+        assert!(ramp.fade_progress().is_some_and(|progress| (progress - 0.5).abs() < 0.01));
+      }
+    }
+
+    assert_eq!(target.round() as u8, ramp.current());
+    assert_eq!(Some(1.0), ramp.fade_progress());
+  }
+
+  #[test]
+  fn a_new_cue_mid_fade_gets_a_fade_of_its_own() {
+    let mut ramp = Ramp::new(linear(1, 1));
+    ramp.set_fade_time(Some(Duration::from_secs(2)));
+
+    assert_eq!(100, ramp.step(200, Duration::from_secs(1)));
+
+    // Heading back down with a second of the first fade left takes the whole two seconds again, rather than the one.
+    assert_eq!(50, ramp.step(0, Duration::from_secs(1)));
+    assert_eq!(0, ramp.step(0, Duration::from_secs(1)));
+  }
+}
@@ -0,0 +1,232 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::{Duration, Instant};
+use crate::conditioning::Conditioner;
+use crate::fixture::Cue;
+
+/// Every recording starts with this, so that replaying some other file fails straight away rather than halfway through
+/// a rehearsal. The last byte is the version of the format.
+const MAGIC: &[u8] = b"WMDX\x01";
+
+/// One DMX frame, as it arrived at the OLA bridge.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+  /// When the frame arrived, measured from the first frame in the recording.
+  pub at: Duration,
+
+  /// The universe the frame was for.
+  pub universe: u32,
+
+  /// The priority it was sent at.
+  pub priority: u8,
+
+  /// The (one-indexed) channels the windmill cares about and their values. Everything else in the universe belongs to
+  /// other fixtures and isn't worth the space.
+  pub channels: Vec<(u32, u8)>
+}
+
+impl Frame {
+  /// The value of a (one-indexed) DMX channel in this frame. Channels that weren't recorded read as zero, same as a
+  /// console that isn't sending them.
+  pub fn value(&self, channel: u32) -> u8 {
+    self.channels.iter().find(|(recorded, _)| *recorded == channel).map_or(0, |(_, value)| *value)
+  }
+
+  /// Appends the frame to `bytes`. Each frame is laid out (little endian) as the microseconds since the first frame
+  /// (`u64`), the universe (`u32`), the priority (`u8`), how many channels follow (`u16`), and then each channel's
+  /// number (`u16`) and value (`u8`). For a typical personality, that's a couple of dozen bytes a frame.
+  fn write(&self, bytes: &mut Vec<u8>) {
+    bytes.extend_from_slice(&(self.at.as_micros() as u64).to_le_bytes());
+    bytes.extend_from_slice(&self.universe.to_le_bytes());
+    bytes.push(self.priority);
+    bytes.extend_from_slice(&(self.channels.len() as u16).to_le_bytes());
+
+    for (channel, value) in &self.channels {
+      bytes.extend_from_slice(&(*channel as u16).to_le_bytes());
+      bytes.push(*value);
+    }
+  }
+
+  /// Reads the next frame from the front of `bytes`, moving `bytes` along past it.
+  fn read(bytes: &mut &[u8]) -> Result<Frame, &'static str> {
+    let mut take = |length: usize| -> Result<&[u8], &'static str> {
+      let (taken, rest) = bytes.split_at_checked(length).ok_or("the recording ends partway through a frame")?;
+      *bytes = rest;
+      Ok(taken)
+    };
+
+    let at = Duration::from_micros(u64::from_le_bytes(take(8)?.try_into().unwrap_or_default()));
+    let universe = u32::from_le_bytes(take(4)?.try_into().unwrap_or_default());
+    let priority = take(1)?[0];
+    let count = u16::from_le_bytes(take(2)?.try_into().unwrap_or_default());
+
+    let channels = (0..count)
+      .map(|_| take(3).map(|channel| (u16::from_le_bytes([channel[0], channel[1]]) as u32, channel[2])))
+      .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Frame { at, universe, priority, channels })
+  }
+}
+
+/// `Recorder` keeps every frame the OLA bridge receives, so that when something weird happens in a show there's a
+/// record of exactly what the console sent, and when. Frames are written out as they arrive (rather than buffered up)
+/// so that a recording survives whatever it was that went weird.
+pub struct Recorder {
+  /// Where frames are written.
+  file: BufWriter<File>,
+
+  /// The channels worth keeping.
+  channels: Vec<u32>,
+
+  /// When the first frame arrived.
+  started: Option<Instant>
+}
+
+impl Recorder {
+  /// Starts a new recording at `path` (replacing whatever was there), keeping only `channels`.
+  pub fn create(path: &Path, channels: Vec<u32>) -> Result<Self, &'static str> {
+    let mut file = BufWriter::new(File::create(path).map_err(|_| "failed to create the recording")?);
+    file.write_all(MAGIC).and_then(|_| file.flush()).map_err(|_| "failed to write to the recording")?;
+
+    Ok(Recorder { file, channels, started: None })
+  }
+
+  /// Records a frame for `universe` at `priority` that arrived at `now`, where `channel` looks up the value of a
+  /// (one-indexed) DMX channel.
+  pub fn record(
+    &mut self,
+    universe: u32,
+    priority: u8,
+    channel: impl Fn(u32) -> u8,
+    now: Instant
+  ) -> Result<(), &'static str> {
+    let started = *self.started.get_or_insert(now);
+
+    let frame = Frame {
+      at: now.saturating_duration_since(started),
+      universe,
+      priority,
+      channels: self.channels.iter().map(|number| (*number, channel(*number))).collect()
+    };
+
+    let mut bytes = Vec::new();
+    frame.write(&mut bytes);
+
+    self.file
+      .write_all(&bytes)
+      .and_then(|_| self.file.flush())
+      .map_err(|_| "failed to write to the recording")
+  }
+}
+
+/// Reads back every frame in the recording at `path`.
+pub fn load(path: &Path) -> Result<Vec<Frame>, &'static str> {
+  let bytes = std::fs::read(path).map_err(|_| "failed to read the recording")?;
+  parse(&bytes)
+}
+
+/// Reads every frame out of a recording's `bytes`.
+fn parse(bytes: &[u8]) -> Result<Vec<Frame>, &'static str> {
+  let mut bytes = bytes.strip_prefix(MAGIC).ok_or("that doesn't look like a windmill recording")?;
+  let mut frames = Vec::new();
+
+  while !bytes.is_empty() {
+    frames.push(Frame::read(&mut bytes)?);
+  }
+
+  Ok(frames)
+}
+
+/// Feeds recorded `frames` back through `conditioner` and on to the windmill over `sender`, exactly as if they were
+/// arriving from the OLA bridge, only `speed` times as fast. Only the frames are sped up: the windmill still ramps and
+/// cools down in real time, so a sped up replay is good for getting to the interesting part, but not for judging how
+/// the windmill behaved once there.
+///
+/// With `report`, what each frame was decoded into is printed as it goes, which is handy for working out what exactly a
+/// console was asking for.
+pub async fn replay(
+  frames: Vec<Frame>,
+  speed: f64,
+  mut conditioner: Conditioner,
+  sender: UnboundedSender<Cue>,
+  report: bool
+) -> Result<(), &'static str> {
+  let started = Instant::now();
+  println!("Replaying {} frames at {speed}x.", frames.len());
+
+  for frame in frames {
+    tokio::time::sleep_until(started + frame.at.div_f64(speed)).await;
+
+    let cue = conditioner.decode(|channel| frame.value(channel), Instant::now());
+
+    if report {
+      let channels = frame.channels.iter().map(|(channel, value)| format!("{channel}={value}")).collect::<Vec<_>>();
+      println!("{:>9.3}s [{}] -> {}", frame.at.as_secs_f64(), channels.join(" "), cue.windmill);
+    }
+    sender.send(cue).map_err(|_| "replay lost connection to the windmill")?;
+  }
+
+  println!("Replay finished.");
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::conditioning::ConditioningProfile;
+  use crate::fixture::{Personality, Windmill};
+
+  #[test]
+  fn recordings_read_back_what_was_written() {
+    let path = std::env::temp_dir().join(format!("windmill-recording-{}", std::process::id()));
+    let now = Instant::now();
+    let mut recorder = Recorder::create(&path, vec![1, 2, 300]).unwrap();
+
+    recorder.record(5, 100, |channel| channel as u8, now).unwrap();
+    recorder.record(5, 90, |_| 7, now + Duration::from_millis(25)).unwrap();
+    drop(recorder);
+
+    let frames = load(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+
+    assert_eq!(2, frames.len());
+    let first = Frame { at: Duration::ZERO, universe: 5, priority: 100, channels: vec![(1, 1), (2, 2), (300, 44)] };
+    assert_eq!(first, frames[0]);
+    assert_eq!(Duration::from_millis(25), frames[1].at);
+    assert_eq!(7, frames[1].value(300));
+    assert_eq!(0, frames[1].value(4));
+  }
+
+  #[test]
+  fn rejects_broken_recordings() {
+    assert!(parse(b"not a recording").is_err());
+    assert_eq!(Ok(Vec::new()), parse(MAGIC));
+
+    let mut bytes = Vec::from(MAGIC);
+    Frame { at: Duration::ZERO, universe: 1, priority: 100, channels: vec![(1, 255)] }.write(&mut bytes);
+    assert!(parse(&bytes).is_ok());
+    assert!(parse(&bytes[..bytes.len() - 1]).is_err());
+  }
+
+  #[tokio::test]
+  async fn replays_through_the_conditioner() {
+    let personality = Personality::speed_direction(1, 2);
+
+    let frames = vec![
+      Frame { at: Duration::ZERO, universe: 1, priority: 100, channels: vec![(1, 0), (2, 0)] },
+      Frame { at: Duration::from_millis(40), universe: 1, priority: 100, channels: vec![(1, 200), (2, 255)] }
+    ];
+
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let conditioner = Conditioner::new(personality, ConditioningProfile::default());
+    let started = Instant::now();
+
+    replay(frames, 2.0, conditioner, sender, false).await.unwrap();
+
+    assert_eq!(Some(Windmill::Off), receiver.recv().await.map(|cue| cue.windmill));
+    assert_eq!(Some(Windmill::Reverse(200)), receiver.recv().await.map(|cue| cue.windmill));
+    assert!(started.elapsed() >= Duration::from_millis(20));
+  }
+}
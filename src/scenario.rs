@@ -0,0 +1,511 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::{Duration, Instant};
+use crate::conditioning::Conditioner;
+use crate::fixture::{Cue, Windmill};
+
+/// How often a scenario checks up on the windmill.
+const TICK: Duration = Duration::from_millis(10);
+
+/// How often the scenario resends its DMX frame, like a console refreshing its output.
+const REFRESH: Duration = Duration::from_millis(25);
+
+/// Where a scenario runs.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Target {
+  /// Against the physics model, same as `--simulate`.
+  Simulated,
+
+  /// On the real windmill, supervised: nothing moves until someone standing next to it says so, the motor is never
+  /// driven past `maximum_duty`, and the first failed expectation stops everything.
+  Hardware { maximum_duty: u8 }
+}
+
+/// What can be seen of the windmill at any given moment, which is what a scenario's expectations are checked against.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Observation {
+  /// Where the control loop thinks the windmill is.
+  pub state: Windmill,
+
+  /// The duty cycle last written to the PWM output, in percent.
+  pub duty_cycle: u8,
+
+  /// Whether the brake relay is on.
+  pub brake: bool,
+
+  /// Whether the direction pin says reverse.
+  pub reverse: bool,
+
+  /// Whether the safety relay is passing PWM through to the motor controller.
+  pub powered: bool,
+
+  /// How fast the rotor is turning (reverse is negative), if there's anything to tell.
+  pub rpm: Option<f64>
+}
+
+/// Which states a `state=` expectation accepts.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StateMatch {
+  /// Off, and not cooling down.
+  Off,
+
+  /// Cooling down, however long is left.
+  Cooldown,
+
+  /// Turning forwards at one of these speeds.
+  Forward(RangeInclusive<u8>),
+
+  /// Turning in reverse at one of these speeds.
+  Reverse(RangeInclusive<u8>)
+}
+
+impl StateMatch {
+  /// Whether `state` is one of the accepted states.
+  fn matches(&self, state: Windmill) -> bool {
+    match (self, state) {
+      (StateMatch::Off, Windmill::Off) | (StateMatch::Cooldown, Windmill::Cooldown(_)) => true,
+      (StateMatch::Forward(speeds), Windmill::Forward(speed))
+      | (StateMatch::Reverse(speeds), Windmill::Reverse(speed)) => speeds.contains(&speed),
+      _ => false
+    }
+  }
+}
+
+/// Written as `off`, `cooldown`, `forward` or `reverse`, where the last two can be narrowed down to a speed
+/// (`forward:200`) or a range of them (`forward:200..255`).
+impl FromStr for StateMatch {
+  type Err = &'static str;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    let (direction, speeds) = match value.split_once(':') {
+      Some((direction, speeds)) => (direction, range(speeds).map_err(|_| "state speeds should be 0 to 255")?),
+      None => (value, 0..=u8::MAX)
+    };
+
+    match direction {
+      "off" if speeds == (0..=u8::MAX) => Ok(StateMatch::Off),
+      "cooldown" if speeds == (0..=u8::MAX) => Ok(StateMatch::Cooldown),
+      "forward" => Ok(StateMatch::Forward(speeds)),
+      "reverse" => Ok(StateMatch::Reverse(speeds)),
+      _ => Err("states should be off, cooldown, forward[:<speeds>] or reverse[:<speeds>]")
+    }
+  }
+}
+
+impl Display for StateMatch {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      StateMatch::Off => write!(f, "off"),
+      StateMatch::Cooldown => write!(f, "cooldown"),
+      StateMatch::Forward(speeds) => write!(f, "forward:{}", Range(speeds)),
+      StateMatch::Reverse(speeds) => write!(f, "reverse:{}", Range(speeds))
+    }
+  }
+}
+
+/// What the windmill should look like at some point in a scenario. Anything left out isn't checked.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Expectation {
+  /// The states the control loop may be in (`state=`).
+  pub state: Option<StateMatch>,
+
+  /// The duty cycles, in percent, the PWM output may be at (`duty=`).
+  pub duty_cycle: Option<RangeInclusive<u8>>,
+
+  /// Whether the brake relay should be on (`brake=`).
+  pub brake: Option<bool>,
+
+  /// Whether the direction pin should say reverse (`direction=`).
+  pub reverse: Option<bool>,
+
+  /// Whether the safety relay should be passing PWM through (`power=`).
+  pub powered: Option<bool>,
+
+  /// The speeds the rotor may be turning at, in rpm with reverse negative (`rpm=`).
+  pub rpm: Option<RangeInclusive<f64>>
+}
+
+impl Expectation {
+  /// Everything about `seen` that isn't as expected, described for the report. Empty means it all checked out.
+  pub fn mismatches(&self, seen: &Observation) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    let on = |on: bool| if on { "on" } else { "off" };
+    let direction = |reverse: bool| if reverse { "reverse" } else { "forward" };
+
+    if let Some(state) = self.state.as_ref().filter(|state| !state.matches(seen.state)) {
+      let seen = match seen.state {
+        Windmill::Cooldown(remaining) => format!("cooldown ({:.2}s left)", remaining.as_secs_f64()),
+        windmill => windmill.to_string()
+      };
+
+      mismatches.push(format!("expected state={state}, saw {seen}"));
+    }
+
+    if let Some(duty_cycle) = self.duty_cycle.as_ref().filter(|duty_cycle| !duty_cycle.contains(&seen.duty_cycle)) {
+      mismatches.push(format!("expected duty={}, saw {}", Range(duty_cycle), seen.duty_cycle));
+    }
+
+    if let Some(brake) = self.brake.filter(|brake| *brake != seen.brake) {
+      mismatches.push(format!("expected brake={}, saw {}", on(brake), on(seen.brake)));
+    }
+
+    if let Some(reverse) = self.reverse.filter(|reverse| *reverse != seen.reverse) {
+      mismatches.push(format!("expected direction={}, saw {}", direction(reverse), direction(seen.reverse)));
+    }
+
+    if let Some(powered) = self.powered.filter(|powered| *powered != seen.powered) {
+      mismatches.push(format!("expected power={}, saw {}", on(powered), on(seen.powered)));
+    }
+
+    match (&self.rpm, seen.rpm) {
+      (Some(rpm), Some(seen)) if !rpm.contains(&seen) =>
+        mismatches.push(format!("expected rpm={}, saw {seen:.1}", Range(rpm))),
+      (Some(_), None) => mismatches.push("expected an rpm, but there's no sensor or simulation to ask".to_string()),
+      _ => {}
+    }
+
+    mismatches
+  }
+}
+
+/// Something a scenario does at a particular time.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+  /// Sets these (one-indexed) DMX channels to these values. Every other channel stays as it was (starting at zero).
+  Send(Vec<(u32, u8)>),
+
+  /// Checks the windmill looks as expected, giving it up until `within` later to get there.
+  Expect { expectation: Expectation, within: Duration }
+}
+
+/// One line of a scenario.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+  /// How far into the scenario this happens.
+  pub at: Duration,
+
+  /// The line of the file it came from, for the report.
+  pub line: usize,
+
+  /// What happens.
+  pub action: Action
+}
+
+/// A `Scenario` is a scripted acceptance test for the windmill: DMX to send at particular times, and what the windmill
+/// should be doing in response. It's the same checks that get done by hand on the rig before every production, written
+/// down so they get done the same way every time, and so they can be tried out against the simulation first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scenario {
+  /// Every step, in order.
+  pub steps: Vec<Step>
+}
+
+impl Scenario {
+  /// Loads a scenario file.
+  pub fn load(path: &Path) -> Result<Self, &'static str> {
+    std::fs::read_to_string(path).map_err(|_| "failed to read the scenario")?.parse()
+  }
+
+  /// How long the scenario takes if every expectation needs all the time it's allowed.
+  pub fn duration(&self) -> Duration {
+    self.steps
+      .iter()
+      .map(|step| match &step.action {
+        Action::Send(_) => step.at,
+        Action::Expect { within, .. } => step.at + *within
+      })
+      .max()
+      .unwrap_or_default()
+  }
+}
+
+/// Parses a scenario file. Each line is a step, written as `<seconds> send <channel>=<value>...` or `<seconds> expect
+/// <check>=<value>... [within=<seconds>]`. The checks are:
+///
+///   - `state=`: `off`, `cooldown`, `forward` or `reverse`, optionally with the speed (`forward:200..255`).
+///   - `duty=`: the PWM duty cycle, in percent.
+///   - `brake=` and `power=`: `on` or `off`, for the brake and safety relays.
+///   - `direction=`: `forward` or `reverse`.
+///   - `rpm=`: the rotor speed, with reverse negative. Only works with a sensor or the simulation.
+///
+/// Any number can be a range (`duty=45..55`) to allow for some tolerance. An expectation is checked at its time, unless
+/// it has `within`, in which case it passes as soon as it's met and only fails if it still isn't that long after. Blank
+/// lines and lines starting with `#` are ignored. For example:
+///
+/// ```text
+/// # Full speed forward, then stop.
+/// 0   send 10=255 11=0
+/// 0   expect brake=off direction=forward within=0.1
+/// 3   expect state=forward:255 duty=100 rpm=30..60
+/// 3   send 10=0
+/// 3   expect state=cooldown brake=on duty=0 within=0.1
+/// 4.5 expect state=off rpm=0
+/// ```
+impl FromStr for Scenario {
+  type Err = &'static str;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    let mut steps = Vec::new();
+
+    for (index, line) in value.lines().enumerate() {
+      let line = line.trim();
+
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+
+      let mut words = line.split_whitespace();
+      let at = words.next().unwrap_or_default().parse::<f64>().map_err(|_| "steps start with a time in seconds")?;
+      let at = Duration::try_from_secs_f64(at).map_err(|_| "step times can't be negative")?;
+
+      let action = match words.next() {
+        Some("send") => Action::Send(words.map(channel_value).collect::<Result<_, _>>()?),
+        Some("expect") => expect(words)?,
+        _ => return Err("steps should either send or expect")
+      };
+
+      steps.push(Step { at, line: index + 1, action });
+    }
+
+    if steps.windows(2).any(|pair| pair[0].at > pair[1].at) {
+      return Err("scenario steps should be in order");
+    }
+
+    Ok(Scenario { steps })
+  }
+}
+
+/// Parses a `<channel>=<value>` pair.
+fn channel_value(word: &str) -> Result<(u32, u8), &'static str> {
+  let (channel, value) = word.split_once('=').ok_or("sends should be <channel>=<value>")?;
+  let channel = channel.parse::<u32>().ok().filter(|channel| (1..=512).contains(channel));
+
+  Ok((channel.ok_or("channels should be 1 to 512")?, value.parse().map_err(|_| "DMX values should be 0 to 255")?))
+}
+
+/// Parses the checks of an `expect` step.
+fn expect<'a>(words: impl Iterator<Item = &'a str>) -> Result<Action, &'static str> {
+  let mut expectation = Expectation::default();
+  let mut within = Duration::ZERO;
+
+  let on = |value: &str| match value {
+    "on" => Ok(true),
+    "off" => Ok(false),
+    _ => Err("brake and power should be on or off")
+  };
+
+  for word in words {
+    match word.split_once('=') {
+      Some(("state", state)) => expectation.state = Some(state.parse()?),
+      Some(("duty", duty)) => expectation.duty_cycle = Some(range(duty).map_err(|_| "duty cycles should be 0 to 255")?),
+      Some(("brake", brake)) => expectation.brake = Some(on(brake)?),
+      Some(("power", power)) => expectation.powered = Some(on(power)?),
+      Some(("direction", "forward")) => expectation.reverse = Some(false),
+      Some(("direction", "reverse")) => expectation.reverse = Some(true),
+      Some(("rpm", rpm)) => expectation.rpm = Some(range(rpm).map_err(|_| "rpm should be a number")?),
+      Some(("within", seconds)) => {
+        within = seconds.parse::<f64>()
+          .ok()
+          .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+          .ok_or("within should be a number of seconds")?;
+      },
+      _ => return Err("expectations should be state, duty, brake, power, direction, rpm or within")
+    }
+  }
+
+  if expectation == Expectation::default() {
+    return Err("an expectation needs something to check");
+  }
+
+  Ok(Action::Expect { expectation, within })
+}
+
+/// Parses either a single value or an inclusive range of them, written `<low>..<high>`.
+fn range<T: FromStr + PartialOrd + Copy>(value: &str) -> Result<RangeInclusive<T>, ()> {
+  let (low, high) = value.split_once("..").unwrap_or((value, value));
+  let (low, high) = (low.parse().map_err(|_| ())?, high.parse().map_err(|_| ())?);
+
+  if low > high {
+    return Err(());
+  }
+
+  Ok(low..=high)
+}
+
+/// Writes a range back out the way it's parsed.
+struct Range<'a, T>(&'a RangeInclusive<T>);
+
+impl<T: Display + PartialEq> Display for Range<'_, T> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self.0.start() == self.0.end() {
+      true => write!(f, "{}", self.0.start()),
+      false => write!(f, "{}..{}", self.0.start(), self.0.end())
+    }
+  }
+}
+
+/// An expectation that's waiting to be met.
+struct Pending {
+  line: usize,
+  at: Duration,
+  deadline: Duration,
+  expectation: Expectation
+}
+
+/// Runs `scenario`, feeding its DMX through `conditioner` and on to the windmill over `sender` as if it was a console,
+/// and checking its expectations against whatever `observe` sees. Each expectation is reported as it passes or fails,
+/// with a summary at the end. With `stop_on_failure`, the first failure ends the scenario then and there.
+///
+/// It's an error if any expectation failed, which is what makes `windmill scenario` exit with a failure.
+pub async fn run(
+  scenario: Scenario,
+  mut conditioner: Conditioner,
+  sender: UnboundedSender<Cue>,
+  observe: impl Fn() -> Observation,
+  stop_on_failure: bool
+) -> Result<(), &'static str> {
+  let started = Instant::now();
+  let mut steps = scenario.steps.into_iter().peekable();
+  let mut frame = BTreeMap::new();
+  let mut pending = Vec::new();
+  let mut sent: Option<Instant> = None;
+  let (mut passed, mut failed) = (0, 0);
+
+  loop {
+    let now = Instant::now();
+    let elapsed = now.saturating_duration_since(started);
+    let mut changed = false;
+
+    while let Some(step) = steps.next_if(|step| step.at <= elapsed) {
+      match step.action {
+        Action::Send(values) => {
+          frame.extend(values);
+          changed = true;
+        },
+
+        Action::Expect { expectation, within } =>
+          pending.push(Pending { line: step.line, at: step.at, deadline: step.at + within, expectation })
+      }
+    }
+
+    if changed || sent.is_none_or(|sent| now.saturating_duration_since(sent) >= REFRESH) {
+      let cue = conditioner.decode(|channel| frame.get(&channel).copied().unwrap_or_default(), now);
+      sender.send(cue).map_err(|_| "the scenario lost connection to the windmill")?;
+      sent = Some(now);
+    }
+
+    let seen = observe();
+
+    pending.retain(|expect| {
+      let mismatches = expect.expectation.mismatches(&seen);
+
+      if mismatches.is_empty() {
+        println!("PASS line {} at {:.3}s (met at {:.3}s)", expect.line, expect.at.as_secs_f64(), elapsed.as_secs_f64());
+        passed += 1;
+        return false;
+      }
+
+      if elapsed >= expect.deadline {
+        println!("FAIL line {} at {:.3}s: {}", expect.line, expect.at.as_secs_f64(), mismatches.join(", "));
+        failed += 1;
+        return false;
+      }
+
+      true
+    });
+
+    if (failed > 0 && stop_on_failure) || (steps.peek().is_none() && pending.is_empty()) {
+      break;
+    }
+
+    tokio::time::sleep(TICK).await;
+  }
+
+  let skipped = steps.filter(|step| matches!(step.action, Action::Expect { .. })).count() + pending.len();
+  println!("{passed} passed, {failed} failed, {skipped} not checked.");
+
+  match failed {
+    0 => Ok(()),
+    _ => Err("the scenario failed")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+  use crate::conditioning::ConditioningProfile;
+  use crate::fixture::Personality;
+
+  fn observation(state: Windmill, duty_cycle: u8) -> Observation {
+    Observation { state, duty_cycle, brake: false, reverse: false, powered: true, rpm: Some(40.0) }
+  }
+
+  #[test]
+  fn parses_sends_and_expectations() {
+    let scenario = "
+      # a comment
+      0 send 10=255 11=0
+      1.5 expect state=forward:200..255 duty=95..100 brake=off direction=forward power=on rpm=-1..60 within=0.5
+    ".parse::<Scenario>().unwrap();
+
+    assert_eq!(2, scenario.steps.len());
+    assert_eq!(Action::Send(vec![(10, 255), (11, 0)]), scenario.steps[0].action);
+    assert_eq!(4, scenario.steps[1].line);
+    assert_eq!(Duration::from_secs(2), scenario.duration());
+
+    let Action::Expect { expectation, within } = &scenario.steps[1].action else { panic!("not an expectation") };
+    assert_eq!(Duration::from_millis(500), *within);
+    assert_eq!(Some(StateMatch::Forward(200..=255)), expectation.state);
+    assert_eq!(Some(-1.0..=60.0), expectation.rpm);
+
+    for broken in ["1 send 0=1", "1 send 1=256", "1 expect", "1 expect duty=9..1", "1 expect state=off:3", "x send"] {
+      assert!(broken.parse::<Scenario>().is_err(), "{broken}");
+    }
+
+    assert!("2 send 1=1\n1 send 1=1".parse::<Scenario>().is_err());
+  }
+
+  #[test]
+  fn describes_what_didnt_match() {
+    let expectation = Expectation { state: Some(StateMatch::Cooldown), duty_cycle: Some(0..=0), ..Default::default() };
+
+    assert_eq!(Vec::<String>::new(), expectation.mismatches(&observation(Windmill::Cooldown(Duration::ZERO), 0)));
+    assert_eq!(
+      vec!["expected state=cooldown, saw forward:10".to_string(), "expected duty=0, saw 4".to_string()],
+      expectation.mismatches(&observation(Windmill::Forward(10), 4))
+    );
+
+    let rpm = Expectation { rpm: Some(0.0..=0.0), ..Default::default() };
+    assert_eq!(1, rpm.mismatches(&Observation { rpm: None, ..observation(Windmill::Off, 0) }).len());
+  }
+
+  #[tokio::test]
+  async fn waits_within_tolerance_then_reports() {
+    let personality = Personality::speed_direction(1, 2);
+
+    // Stands in for the windmill, following the last cue it was sent.
+    let seen = Arc::new(Mutex::new(Windmill::Off));
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<Cue>();
+    let follower = seen.clone();
+
+    tokio::spawn(async move {
+      while let Some(cue) = receiver.recv().await {
+        *follower.lock().unwrap() = cue.windmill;
+      }
+    });
+
+    let conditioner = || Conditioner::new(personality, ConditioningProfile::default());
+    let observe = || observation(*seen.lock().unwrap(), 0);
+
+    let passing = "0 send 1=200\n0 expect state=forward:200 within=0.2".parse().unwrap();
+    assert_eq!(Ok(()), run(passing, conditioner(), sender.clone(), observe, false).await);
+
+    let failing = "0 send 1=0\n0.01 expect state=forward within=0.03\n0.05 expect state=off".parse().unwrap();
+    assert!(run(failing, conditioner(), sender.clone(), observe, true).await.is_err());
+  }
+}
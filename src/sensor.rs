@@ -0,0 +1,102 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::wiringpi;
+
+/// Anything slower than this is considered stopped. Sensors are noisy and rotors wobble, so "exactly zero" is not a
+/// useful line to draw.
+const MOTION_THRESHOLD_RPM: f64 = 1.0;
+
+/// How often the pulse sensor looks at its pin. A twelve foot windmill is not going to outrun a millisecond.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Something that can tell us how fast the windmill is actually turning, as opposed to how fast we've asked it to turn.
+/// The whole program works fine without one of these, but when there is one, it gets to overrule our guesses.
+pub trait SpeedSensor: Send + Sync {
+  /// The most recently measured speed of the rotor, in revolutions per minute.
+  fn rpm(&self) -> f64;
+
+  /// Whether the rotor is turning at all.
+  fn is_moving(&self) -> bool {
+    self.rpm() >= MOTION_THRESHOLD_RPM
+  }
+}
+
+/// `PulseSensor` measures rotor speed from a hall effect sensor (or an optical interrupter, or a reed switch, or whatever
+/// was in the parts bin) that pulses a GPIO pin high some number of times per revolution. The pin is polled from a
+/// dedicated thread and the speed is worked out from the time between the last two rising edges.
+///
+/// Between pulses, the rotor can't be turning any faster than one pulse in however long it's been waiting for the
+/// next, so the reading falls away with the wait rather than holding on to the last measurement. That's only to keep
+/// the display honest, though. Waiting for the reading to fall all the way to nothing would take a whole minute with
+/// one pulse per revolution, so instead the rotor is called stopped once it's gone `stopped_after` without a pulse.
+pub struct PulseSensor {
+  /// The last measured speed, stored as the bits of an `f64` so it can be shared without a lock.
+  rpm: Arc<AtomicU64>
+}
+
+impl PulseSensor {
+  /// Starts watching the given WiringPi `pin`, which pulses `pulses_per_revolution` times for every turn of the rotor,
+  /// and calling the rotor stopped once there's been no pulse for `stopped_after`. That wants to be a good few times
+  /// longer than the slowest the rotor ever really turns. WiringPi must already have been initialized.
+  pub fn new(pin: i32, pulses_per_revolution: u8, stopped_after: Duration) -> Result<Self, &'static str> {
+    if pulses_per_revolution == 0 {
+      return Err("the speed sensor needs at least one pulse per revolution");
+    }
+
+    wiringpi::pin_mode(pin, wiringpi::PIN_MODE_INPUT);
+
+    let rpm = Arc::new(AtomicU64::new(0f64.to_bits()));
+    let measured = rpm.clone();
+
+    std::thread::Builder::new()
+      .name(String::from("speed-sensor"))
+      .spawn(move || {
+        let mut previous_level = wiringpi::digital_read(pin);
+        let mut previous_pulse: Option<Instant> = None;
+
+        loop {
+          let level = wiringpi::digital_read(pin);
+          let now = Instant::now();
+
+          let pulsed = level == wiringpi::DIGITAL_HIGH && previous_level == wiringpi::DIGITAL_LOW;
+
+          if let Some(previous) = previous_pulse {
+            let fastest = pulse_rpm(now - previous, pulses_per_revolution);
+            let last = f64::from_bits(measured.load(Ordering::Relaxed));
+
+            // A pulse is a fresh measurement. Otherwise, the wait so far is the most it can be turning at.
+            if pulsed || fastest < last {
+              measured.store(fastest.to_bits(), Ordering::Relaxed);
+            }
+          }
+
+          if pulsed {
+            previous_pulse = Some(now);
+          }
+
+          else if previous_pulse.is_some_and(|previous| now - previous >= stopped_after) {
+            measured.store(0f64.to_bits(), Ordering::Relaxed);
+            previous_pulse = None;
+          }
+
+          previous_level = level;
+          std::thread::sleep(POLL_INTERVAL);
+        }
+      })
+      .map_err(|_| "failed to start the speed sensor thread")?;
+
+    Ok(PulseSensor { rpm })
+  }
+}
+
+/// How fast the rotor is turning if it took `interval` to go from one pulse to the next.
+fn pulse_rpm(interval: Duration, pulses_per_revolution: u8) -> f64 {
+  60.0 / (interval.as_secs_f64() * pulses_per_revolution as f64)
+}
+
+impl SpeedSensor for PulseSensor {
+  fn rpm(&self) -> f64 {
+    f64::from_bits(self.rpm.load(Ordering::Relaxed))
+  }
+}
@@ -0,0 +1,441 @@
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::time::{Duration, Instant};
+use crate::fixture::{Cue, CueSource, Windmill};
+use crate::playback::Timeline;
+
+/// How often the chase works out where it's up to, in the same spirit as playback's tick.
+const TICK: Duration = Duration::from_millis(25);
+
+/// The header every Art-Net packet starts with.
+const ART_NET_ID: &[u8] = b"Art-Net\0";
+
+/// The Art-Net opcode for ArtTimeCode, as it appears on the wire (little endian).
+const OP_TIME_CODE: [u8; 2] = [0x00, 0x97];
+
+/// The frame rates timecode can run at.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FrameRate {
+  /// 24 frames per second.
+  Film,
+
+  /// 25 frames per second.
+  Ebu,
+
+  /// 29.97 frames per second, dropping frame labels to keep up with the clock.
+  DropFrame,
+
+  /// 30 frames per second.
+  Smpte
+}
+
+impl FrameRate {
+  /// Decodes the frame rate from the two bit code that both ArtTimeCode and MTC use.
+  fn from_code(code: u8) -> FrameRate {
+    match code & 0b11 {
+      0 => FrameRate::Film,
+      1 => FrameRate::Ebu,
+      2 => FrameRate::DropFrame,
+      _ => FrameRate::Smpte
+    }
+  }
+}
+
+/// A single timecode value.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Timecode {
+  pub hours: u8,
+  pub minutes: u8,
+  pub seconds: u8,
+  pub frames: u8,
+  pub rate: FrameRate
+}
+
+impl Timecode {
+  /// How far into the show this timecode is, in real time.
+  pub fn position(&self) -> Duration {
+    let whole_seconds = self.hours as u64 * 3600 + self.minutes as u64 * 60 + self.seconds as u64;
+
+    let seconds = match self.rate {
+      FrameRate::Film => whole_seconds as f64 + self.frames as f64 / 24.0,
+      FrameRate::Ebu => whole_seconds as f64 + self.frames as f64 / 25.0,
+      FrameRate::Smpte => whole_seconds as f64 + self.frames as f64 / 30.0,
+
+      // Drop frame labels skip the first two frames of every minute, except every tenth minute. Take those back out to
+      // get the actual number of frames that have gone by.
+      FrameRate::DropFrame => {
+        let minutes = self.hours as u64 * 60 + self.minutes as u64;
+        let frames = whole_seconds * 30 + self.frames as u64 - 2 * (minutes - minutes / 10);
+        frames as f64 / 29.97
+      }
+    };
+
+    Duration::from_secs_f64(seconds)
+  }
+
+  /// The timecode two frames on, carrying into the seconds, minutes and hours as needed (and wrapping at midnight, like
+  /// timecode does). Drop frame labels that don't exist are skipped over.
+  fn plus_two_frames(self) -> Timecode {
+    let rate = match self.rate {
+      FrameRate::Film => 24,
+      FrameRate::Ebu => 25,
+      FrameRate::DropFrame | FrameRate::Smpte => 30
+    };
+
+    let mut timecode = Timecode { frames: self.frames + 2, ..self };
+
+    if timecode.frames >= rate {
+      timecode.frames -= rate;
+      timecode.seconds += 1;
+    }
+
+    if timecode.seconds >= 60 {
+      timecode.seconds = 0;
+      timecode.minutes += 1;
+    }
+
+    if timecode.minutes >= 60 {
+      timecode.minutes = 0;
+      timecode.hours = (timecode.hours + 1) % 24;
+    }
+
+    // Frames 0 and 1 don't exist at the start of a drop frame minute, unless it's a tenth minute.
+    let dropped = timecode.seconds == 0 && timecode.frames < 2 && !timecode.minutes.is_multiple_of(10);
+
+    if timecode.rate == FrameRate::DropFrame && dropped {
+      timecode.frames += 2;
+    }
+
+    timecode
+  }
+
+  /// Parses an ArtTimeCode packet, ignoring anything that isn't one.
+  pub fn from_art_net(packet: &[u8]) -> Option<Timecode> {
+    if packet.len() < 19 || !packet.starts_with(ART_NET_ID) || packet[8..10] != OP_TIME_CODE {
+      return None;
+    }
+
+    Some(Timecode {
+      frames: packet[14],
+      seconds: packet[15],
+      minutes: packet[16],
+      hours: packet[17],
+      rate: FrameRate::from_code(packet[18])
+    })
+  }
+}
+
+/// `MtcDecoder` pieces MIDI timecode back together from a raw MIDI byte stream. Running timecode arrives as quarter
+/// frame messages, each carrying a nibble of the full value, so it takes eight of them (two frames) to know where we
+/// are. Jumps arrive as a full frame SysEx message instead.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MtcDecoder {
+  /// The nibbles received so far, indexed by piece number.
+  pieces: [u8; 8],
+
+  /// Which pieces have been received since the last complete timecode.
+  received: u8,
+
+  /// The message currently being read, if we're partway through one.
+  message: [u8; 10],
+
+  /// How much of `message` has been read.
+  length: usize
+}
+
+impl MtcDecoder {
+  /// Feeds in the next byte from the MIDI stream, and returns a timecode if it completed one.
+  pub fn push(&mut self, byte: u8) -> Option<Timecode> {
+    // A status byte starts a new message, apart from the one that ends a SysEx message (and real time messages, which
+    // can turn up anywhere and don't interrupt anything).
+    if byte & 0x80 != 0 && byte != 0xf7 && byte < 0xf8 {
+      self.length = 0;
+    }
+
+    if self.length < self.message.len() {
+      self.message[self.length] = byte;
+      self.length += 1;
+    }
+
+    match &self.message[..self.length] {
+      [0xf1, data] => {
+        let data = *data;
+        self.length = 0;
+        self.quarter_frame(data)
+      },
+
+      [0xf0, 0x7f, _, 0x01, 0x01, hours, minutes, seconds, frames, 0xf7] => {
+        let timecode = Timecode {
+          hours: hours & 0x1f,
+          minutes: *minutes,
+          seconds: *seconds,
+          frames: *frames,
+          rate: FrameRate::from_code(hours >> 5)
+        };
+
+        self.length = 0;
+        self.received = 0;
+        Some(timecode)
+      },
+
+      _ => None
+    }
+  }
+
+  /// Handles one quarter frame message.
+  fn quarter_frame(&mut self, data: u8) -> Option<Timecode> {
+    let piece = (data >> 4) as usize & 0b111;
+    self.pieces[piece] = data & 0x0f;
+    self.received |= 1 << piece;
+
+    if piece != 7 || self.received != 0xff {
+      return None;
+    }
+
+    self.received = 0;
+    let pair = |low: usize| self.pieces[low] | self.pieces[low + 1] << 4;
+
+    // The full value describes the frame when the first piece was sent, which by now is two frames ago.
+    let timecode = Timecode {
+      frames: pair(0),
+      seconds: pair(2),
+      minutes: pair(4),
+      hours: pair(6) & 0x1f,
+      rate: FrameRate::from_code(pair(6) >> 5)
+    };
+
+    Some(timecode.plus_two_frames())
+  }
+}
+
+/// `Chase` follows timecode through a `Timeline`, treating each keyframe as a cue that fires at its time. Because the
+/// position comes straight from the timecode, jumps (in either direction) just land wherever they land, and a source
+/// that pauses leaves the windmill holding. If timecode goes missing altogether, the chase freewheels along on its own
+/// clock for a little while, which rides out a dropped packet or a glitchy cable, and then holds wherever it got to.
+pub struct Chase {
+  /// The cue list.
+  timeline: Timeline,
+
+  /// How long to carry on without timecode.
+  freewheel: Duration,
+
+  /// The last timecode position, and when it arrived.
+  last: Option<(Duration, Instant)>
+}
+
+impl Chase {
+  /// Creates a new `Chase` through `timeline`, freewheeling for `freewheel` when timecode goes missing.
+  pub fn new(timeline: Timeline, freewheel: Duration) -> Self {
+    Chase { timeline, freewheel, last: None }
+  }
+
+  /// Records a timecode that arrived at `now`.
+  pub fn receive(&mut self, timecode: Timecode, now: Instant) {
+    self.last = Some((timecode.position(), now));
+  }
+
+  /// Where the timeline is up to at `now`, if any timecode has been seen yet.
+  pub fn position(&self, now: Instant) -> Option<Duration> {
+    self.last.map(|(position, received)| position + now.saturating_duration_since(received).min(self.freewheel))
+  }
+
+  /// Whether timecode has gone missing for longer than the freewheel window.
+  pub fn is_lost(&self, now: Instant) -> bool {
+    self.last.is_some_and(|(_, received)| now.saturating_duration_since(received) > self.freewheel)
+  }
+
+  /// Where the windmill should be at `now`. Until timecode turns up, that's off.
+  pub fn cue(&self, now: Instant) -> Cue {
+    match self.position(now) {
+      Some(position) => self.timeline.sample(position),
+      None => Cue { source: CueSource::Playback, ..Cue::from(Windmill::Off) }
+    }
+  }
+}
+
+/// Where timecode comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimecodeSource {
+  /// ArtTimeCode packets arriving at this address.
+  ArtNet(SocketAddr),
+
+  /// MIDI timecode read from a raw MIDI device (e.g. `/dev/snd/midiC1D0`).
+  Mtc(PathBuf)
+}
+
+/// Binds a UDP socket on `address` that other programs can bind too. ArtTimeCode is broadcast to Art-Net's port, which
+/// is the very port OLA's Art-Net plugin is already sitting on for DMX. OLA asks to share it, so as long as we ask as
+/// well, both of us get a copy of every broadcast.
+fn shared_socket(address: SocketAddr) -> std::io::Result<UdpSocket> {
+  let socket = Socket::new(Domain::for_address(address), Type::DGRAM, Some(Protocol::UDP))?;
+  socket.set_reuse_address(true)?;
+  socket.set_reuse_port(true)?;
+  socket.set_broadcast(true)?;
+  socket.set_nonblocking(true)?;
+  socket.bind(&address.into())?;
+
+  UdpSocket::from_std(socket.into())
+}
+
+/// Chases timecode from `source`, feeding cues to the windmill over `sender` (the same channel `ola::start` feeds)
+/// until the windmill stops listening or the source fails.
+pub async fn run(source: TimecodeSource, mut chase: Chase, sender: UnboundedSender<Cue>) -> Result<(), &'static str> {
+  let (timecode_tx, mut timecode_rx) = mpsc::unbounded_channel::<Timecode>();
+
+  match source {
+    TimecodeSource::ArtNet(address) => {
+      let socket = shared_socket(address).map_err(|_| "failed to bind the art-net timecode socket")?;
+
+      tokio::spawn(async move {
+        let mut packet = [0u8; 1024];
+
+        while let Ok(length) = socket.recv(&mut packet).await {
+          if let Some(timecode) = Timecode::from_art_net(&packet[..length]) {
+            if timecode_tx.send(timecode).is_err() {
+              break;
+            }
+          }
+        }
+      });
+    },
+
+    // Raw MIDI devices block, so they get a thread of their own.
+    TimecodeSource::Mtc(path) => {
+      let mut device = std::fs::File::open(path).map_err(|_| "failed to open the midi timecode device")?;
+
+      std::thread::Builder::new()
+        .name(String::from("midi-timecode"))
+        .spawn(move || {
+          let mut decoder = MtcDecoder::default();
+          let mut byte = [0u8; 1];
+
+          while let Ok(1) = device.read(&mut byte) {
+            if let Some(timecode) = decoder.push(byte[0]) {
+              if timecode_tx.send(timecode).is_err() {
+                break;
+              }
+            }
+          }
+        })
+        .map_err(|_| "failed to start the midi timecode thread")?;
+    }
+  }
+
+  let mut last_cue = None;
+  let mut was_lost = false;
+
+  loop {
+    select! {
+      _ = tokio::time::sleep(TICK) => {},
+      timecode = timecode_rx.recv() => match timecode {
+        Some(timecode) => chase.receive(timecode, Instant::now()),
+        None => return Err("timecode source stopped")
+      }
+    }
+
+    let now = Instant::now();
+
+    if chase.is_lost(now) != was_lost {
+      was_lost = !was_lost;
+      println!("{}", if was_lost { "Lost timecode, holding." } else { "Timecode is back." });
+    }
+
+    // Only send cues that are actually different, so that a held position doesn't crowd out the console.
+    let cue = chase.cue(now);
+
+    if last_cue != Some(cue) {
+      sender.send(cue).map_err(|_| "timecode chase lost connection to the windmill")?;
+      last_cue = Some(cue);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reads_art_timecode() {
+    let mut packet = Vec::from(ART_NET_ID);
+    packet.extend_from_slice(&[0x00, 0x97, 0, 14, 0, 0, 12, 30, 1, 0, 1]);
+
+    let timecode = Timecode::from_art_net(&packet).unwrap();
+    assert_eq!(Timecode { hours: 0, minutes: 1, seconds: 30, frames: 12, rate: FrameRate::Ebu }, timecode);
+    assert_eq!(Duration::from_secs_f64(90.48), timecode.position());
+
+    packet[8] = 0x00;
+    packet[9] = 0x50;
+    assert_eq!(None, Timecode::from_art_net(&packet));
+  }
+
+  #[test]
+  fn drop_frame_keeps_up_with_the_clock() {
+    let timecode = Timecode { hours: 1, minutes: 0, seconds: 0, frames: 0, rate: FrameRate::DropFrame };
+    assert!((timecode.position().as_secs_f64() - 3600.0).abs() < 0.01);
+  }
+
+  #[test]
+  fn reads_midi_timecode() {
+    let mut decoder = MtcDecoder::default();
+
+    // 01:02:03:04 at 25fps, one quarter frame at a time, with a clock tick thrown in the middle for good measure.
+    let pieces = [0x04, 0x10, 0x23, 0x30, 0x42, 0x50, 0x61, 0x72];
+    let mut decoded = None;
+
+    for (index, piece) in pieces.iter().enumerate() {
+      if index == 3 {
+        assert_eq!(None, decoder.push(0xf8));
+      }
+
+      assert_eq!(None, decoded);
+      decoded = decoder.push(0xf1).or(decoder.push(*piece));
+    }
+
+    // The pieces started going out two frames ago, so by the time the last one arrives it's two frames later.
+    assert_eq!(Some(Timecode { hours: 1, minutes: 2, seconds: 3, frames: 6, rate: FrameRate::Ebu }), decoded);
+
+    let full_frame = [0xf0, 0x7f, 0x7f, 0x01, 0x01, 0x61, 0x02, 0x03, 0x04, 0xf7];
+    let decoded = full_frame.iter().filter_map(|byte| decoder.push(*byte)).last();
+    assert_eq!(Some(Timecode { hours: 1, minutes: 2, seconds: 3, frames: 4, rate: FrameRate::Smpte }), decoded);
+  }
+
+  #[test]
+  fn two_frames_on_carries_over() {
+    let at = |hours, minutes, seconds, frames, rate| Timecode { hours, minutes, seconds, frames, rate };
+
+    assert_eq!(at(0, 0, 1, 0, FrameRate::Film), at(0, 0, 0, 22, FrameRate::Film).plus_two_frames());
+    assert_eq!(at(0, 0, 0, 1, FrameRate::Ebu), at(23, 59, 59, 24, FrameRate::Ebu).plus_two_frames());
+    assert_eq!(at(0, 1, 0, 2, FrameRate::DropFrame), at(0, 0, 59, 28, FrameRate::DropFrame).plus_two_frames());
+    assert_eq!(at(0, 10, 0, 0, FrameRate::DropFrame), at(0, 9, 59, 28, FrameRate::DropFrame).plus_two_frames());
+  }
+
+  #[test]
+  fn chase_follows_jumps_and_freewheels_then_holds() {
+    let timeline = "0 off\n10 forward:100\n20 forward:100".parse::<Timeline>().unwrap();
+    let mut chase = Chase::new(timeline, Duration::from_secs(2));
+    let now = Instant::now();
+    let at = |seconds: u8| Timecode { hours: 0, minutes: 0, seconds, frames: 0, rate: FrameRate::Ebu };
+
+    assert_eq!(Windmill::Off, chase.cue(now).windmill);
+
+    chase.receive(at(5), now);
+    assert_eq!(Windmill::Forward(50), chase.cue(now).windmill);
+
+    // Jumping backwards just goes there.
+    chase.receive(at(2), now);
+    assert_eq!(Windmill::Forward(20), chase.cue(now).windmill);
+
+    // No timecode for a second: freewheel along.
+    assert_eq!(Windmill::Forward(30), chase.cue(now + Duration::from_secs(1)).windmill);
+    assert!(!chase.is_lost(now + Duration::from_secs(1)));
+
+    // No timecode for ages: hold at the end of the freewheel window.
+    assert_eq!(Windmill::Forward(40), chase.cue(now + Duration::from_secs(60)).windmill);
+    assert!(chase.is_lost(now + Duration::from_secs(60)));
+  }
+}
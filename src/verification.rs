@@ -0,0 +1,364 @@
+use crate::motion::{CooldownCurve, CooldownModel, MotionProfile};
+use crate::ramp::RampProfile;
+use super::*;
+
+/// Where the relays (and the PWM duty cycle) are, as far as the commands carried out so far say.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Relays {
+  brake: bool,
+  reverse: bool,
+  powered: bool,
+  duty_cycle: u8
+}
+
+/// `Checker` follows a trace of the state machine (every state it went to, when, and every command it gave on the way)
+/// and complains the moment anything unsafe happens:
+///
+///   - The direction never changes unless the brake is on.
+///   - The duty cycle is always zero while the brake is on.
+///   - Changing direction always goes through a cool down.
+///   - A cool down never ends early, and is never shorter than the cool down model asks for, given however the windmill
+///     stopped and how fast it was going at the time.
+///   - Speed never changes faster than the ramp allows, and always starts from zero.
+///   - The relays always agree with the state: driving the right way with the brake off while moving, braked while off,
+///     and braked or cut off at the safety relay while cooling down.
+#[derive(Clone, Debug)]
+struct Checker {
+  /// How the windmill is allowed to move.
+  profile: MotionProfile,
+
+  /// Where the relays are.
+  relays: Relays,
+
+  /// The state as of the last step, and when that was.
+  state: Windmill,
+  at: Instant,
+
+  /// Which way the windmill last turned (`true` for reverse), and whether it's been through a cool down since.
+  turned: Option<bool>,
+  cooled_down: bool,
+
+  /// The earliest the current cool down is allowed to end.
+  cooldown_until: Option<Instant>
+}
+
+impl Checker {
+  /// Starts checking from a freshly started windmill at `now`, with its relays set up just like the windmill task does.
+  /// Until then, they're wherever the pins leave them at power on: braked, forward, and cut off at the safety relay.
+  fn new(profile: MotionProfile, now: Instant) -> Self {
+    let mut checker = Checker {
+      profile,
+      relays: Relays { brake: true, reverse: false, powered: false, duty_cycle: 0 },
+      state: Windmill::Off,
+      at: now,
+      turned: None,
+      cooled_down: false,
+      cooldown_until: None
+    };
+
+    checker.check(&STARTUP, Windmill::Off, now).unwrap();
+    checker
+  }
+
+  /// Checks the next step of the trace: the state machine gave `commands` and went to `state` at `now`.
+  fn check(&mut self, commands: &[OutputCommand], state: Windmill, now: Instant) -> Result<(), String> {
+    for command in commands {
+      match *command {
+        OutputCommand::Brake(brake) => self.relays.brake = brake,
+        OutputCommand::Safety(safety) => self.relays.powered = safety,
+        OutputCommand::Direction(direction) => {
+          let reverse = direction == MotorDirection::Reverse;
+
+          if reverse != self.relays.reverse && !self.relays.brake {
+            return Err(format!("changed direction to {direction:?} with the brake off"));
+          }
+
+          self.relays.reverse = reverse;
+        },
+        OutputCommand::DutyCycle(duty_cycle) => self.relays.duty_cycle = duty_cycle,
+        OutputCommand::SafeOutput => self.relays.duty_cycle = 0
+      }
+
+      if self.relays.brake && self.relays.duty_cycle != 0 {
+        return Err(format!("had the brake on at {}% duty", self.relays.duty_cycle));
+      }
+    }
+
+    let ramp = self.profile.ramp;
+    let elapsed = now.saturating_duration_since(self.at);
+
+    match (self.state, state) {
+      (Windmill::Cooldown(_), Windmill::Cooldown(_)) => {},
+
+      (Windmill::Cooldown(_), next) => match self.cooldown_until.take() {
+        Some(until) if now < until => return Err(format!("went to {next:?} {:?} early", until - now)),
+        _ => {}
+      },
+
+      (previous, Windmill::Cooldown(length)) => {
+        let (model, how) = match !self.relays.powered {
+          true => (self.profile.coast_cooldown, "coast"),
+          false => (self.profile.brake_cooldown, "brake")
+        };
+
+        // A ramp down only brakes once it's got all the way to zero, so it gets the shortest cool down there is.
+        let speed = match previous {
+          Windmill::Forward(speed) | Windmill::Reverse(speed) => speed,
+          Windmill::Off | Windmill::Cooldown(_) => 0
+        };
+
+        let required = model.duration(speed);
+
+        if length < required {
+          return Err(format!("a {how} cool down of {length:?} from {speed} is shorter than the {required:?} needed"));
+        }
+
+        self.cooldown_until = Some(now + length);
+        self.cooled_down = true;
+      },
+
+      (Windmill::Off, Windmill::Forward(speed) | Windmill::Reverse(speed)) if speed != 0 =>
+        return Err(format!("started at {speed} rather than from zero")),
+
+      (Windmill::Forward(from), Windmill::Forward(to)) | (Windmill::Reverse(from), Windmill::Reverse(to)) => {
+        let time = if to > from { ramp.acceleration } else { ramp.deceleration };
+        let limit = (u8::MAX as f64 * elapsed.as_secs_f64() / time.as_secs_f64()).ceil() + 1.0;
+
+        if (to as f64 - from as f64).abs() > limit {
+          return Err(format!("ramped from {from} to {to} in {elapsed:?}, but only {limit} is allowed"));
+        }
+      },
+
+      (previous @ (Windmill::Forward(_) | Windmill::Reverse(_)), next @ (Windmill::Forward(_) | Windmill::Reverse(_)))
+      | (previous @ (Windmill::Forward(_) | Windmill::Reverse(_)), next @ Windmill::Off) =>
+        return Err(format!("went straight from {previous:?} to {next:?} without a cool down")),
+
+      _ => {}
+    }
+
+    if let Windmill::Forward(_) | Windmill::Reverse(_) = state {
+      let reverse = matches!(state, Windmill::Reverse(_));
+
+      if self.turned.is_some_and(|turned| turned != reverse) && !self.cooled_down {
+        return Err(format!("changed direction to {state:?} without a cool down"));
+      }
+
+      self.turned = Some(reverse);
+      self.cooled_down = false;
+    }
+
+    let relays = self.relays;
+    let agrees = match state {
+      Windmill::Forward(_) => !relays.brake && relays.powered && !relays.reverse,
+      Windmill::Reverse(_) => !relays.brake && relays.powered && relays.reverse,
+      Windmill::Off => relays.brake && relays.powered,
+      Windmill::Cooldown(_) => relays.brake || !relays.powered
+    };
+
+    if !agrees {
+      return Err(format!("the relays {relays:?} don't agree with {state:?}"));
+    }
+
+    self.state = state;
+    self.at = now;
+    Ok(())
+  }
+}
+
+/// Something the operator asks for: where the windmill should head, how it should stop if it has to, and how long
+/// they leave it before asking for something else.
+#[derive(Copy, Clone, Debug)]
+struct Input {
+  desired: Windmill,
+  stop_mode: StopMode,
+  hold: Duration
+}
+
+/// The state machine, and the `Checker` following along.
+#[derive(Clone)]
+struct World {
+  motion: Motion,
+  checker: Checker,
+  now: Instant,
+
+  /// The duty cycle the control loop last wrote.
+  duty_cycle: u8
+}
+
+impl World {
+  fn new(profile: MotionProfile) -> Self {
+    let now = Instant::now();
+    World { motion: Motion::new(profile, None, now), checker: Checker::new(profile, now), now, duty_cycle: 0 }
+  }
+
+  /// Holds `input` for as long as it asks, evaluating the state machine every tick and writing the duty cycle for
+  /// whatever speed it's at, just like the control loop does.
+  fn run(&mut self, input: Input) -> Result<(), String> {
+    let until = self.now + input.hold;
+    self.motion.set_stop_mode(input.stop_mode);
+
+    while self.now < until {
+      self.now += TICK;
+      let (state, mut commands) = state_change_evaluator(self.checker.state, input.desired, &mut self.motion, self.now);
+
+      let duty_cycle = match state {
+        Windmill::Forward(speed) | Windmill::Reverse(speed) =>
+          output::DutyLimits::default().duty_cycle(&curve::ResponseCurve::Linear, speed),
+        Windmill::Off | Windmill::Cooldown(_) => 0
+      };
+
+      reconcile_duty_cycle(&mut commands, &mut self.duty_cycle, duty_cycle);
+      self.checker.check(&commands, state, self.now)?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Ramps that take a second to full speed (one of them an S-curve), and cool downs that take one to three seconds. And
+/// what the windmill actually ships with, since that's the one that has to be right.
+fn profiles() -> [MotionProfile; 3] {
+  let linear = MotionProfile {
+    ramp: RampProfile { acceleration: Duration::from_secs(1), deceleration: Duration::from_millis(700), s_curve: None },
+    kick_start: None,
+    brake_cooldown: CooldownModel {
+      minimum: Duration::from_millis(500),
+      full_speed: Duration::from_millis(1500),
+      curve: CooldownCurve::Linear
+    },
+    coast_cooldown: CooldownModel {
+      minimum: Duration::from_secs(2),
+      full_speed: Duration::from_secs(3),
+      curve: CooldownCurve::Linear
+    }
+  };
+
+  let s_curve = MotionProfile {
+    ramp: RampProfile { s_curve: Some(Duration::from_millis(300)), ..linear.ramp },
+    ..linear
+  };
+
+  [linear, s_curve, cli::Args::parse_from(["windmill"]).motion_profile().unwrap()]
+}
+
+const DESIRED: [Windmill; 5] =
+  [Windmill::Off, Windmill::Forward(255), Windmill::Forward(40), Windmill::Reverse(255), Windmill::Reverse(40)];
+
+const STOP_MODES: [StopMode; 3] = [StopMode::HardBrake, StopMode::RampDown, StopMode::Coast];
+
+/// Tries every sequence of `depth` inputs from `world`, failing with the sequence that led to the first broken rule.
+fn explore(world: &World, inputs: &[Input], depth: usize, trace: &mut Vec<Input>) {
+  if depth == 0 {
+    return;
+  }
+
+  for input in inputs {
+    let mut next = world.clone();
+    trace.push(*input);
+
+    if let Err(why) = next.run(*input) {
+      panic!("{why}, after {trace:#?}");
+    }
+
+    explore(&next, inputs, depth - 1, trace);
+    trace.pop();
+  }
+}
+
+/// Every sequence of three operator inputs, for every stop mode and profile. Holds are picked to land in the interesting
+/// places: a single tick, partway through a ramp or a cool down, and long enough for either to finish.
+#[test]
+fn every_short_sequence_is_safe() {
+  for profile in profiles() {
+    for stop_mode in STOP_MODES {
+      let inputs = DESIRED
+        .iter()
+        .flat_map(|desired| [10, 400, 2500].map(|millis| (*desired, Duration::from_millis(millis))))
+        .map(|(desired, hold)| Input { desired, stop_mode, hold })
+        .collect::<Vec<_>>();
+
+      explore(&World::new(profile), &inputs, 3, &mut Vec::new());
+    }
+  }
+}
+
+/// Long random sequences, with the stop mode changing as it goes (the console can pick one per cue), any speed, and
+/// holds anywhere from a tick to a few seconds. Each sequence ends by turning the windmill off and waiting, so every
+/// cool down gets to finish. The seeds are fixed, so a failure always comes back.
+#[test]
+fn long_random_sequences_are_safe() {
+  for seed in 1..=100u64 {
+    let mut random = seed.wrapping_mul(0x9e3779b97f4a7c15);
+    let mut next = |bound: u64| {
+      random ^= random << 13;
+      random ^= random >> 7;
+      random ^= random << 17;
+      random % bound
+    };
+
+    let profile = profiles()[next(profiles().len() as u64) as usize];
+    let mut world = World::new(profile);
+    let mut trace = Vec::new();
+
+    for step in 0..40 {
+      let input = match step {
+        39 => Input {
+          desired: Windmill::Off,
+          stop_mode: StopMode::HardBrake,
+          hold: profile.coast_cooldown.full_speed.max(profile.ramp.deceleration) + Duration::from_secs(1)
+        },
+        _ => Input {
+          desired: match next(3) {
+            0 => Windmill::Off,
+            1 => Windmill::Forward(next(256) as u8),
+            _ => Windmill::Reverse(next(256) as u8)
+          },
+          stop_mode: STOP_MODES[next(3) as usize],
+          hold: TICK * (1 + next(300) as u32)
+        }
+      };
+
+      trace.push(input);
+
+      if let Err(why) = world.run(input) {
+        panic!("seed {seed}: {why}, after {trace:#?}");
+      }
+    }
+
+    assert_eq!(Windmill::Off, world.checker.state, "seed {seed} never came to a stop");
+  }
+}
+
+/// The checker is only worth anything if it actually notices when something is wrong.
+#[test]
+fn catches_unsafe_traces() {
+  let profile = profiles()[0];
+  let start = Instant::now();
+  let mut checker = Checker::new(profile, start);
+  let tick = |ticks: u32| start + TICK * ticks;
+
+  // Releasing the brake and going forward is fine, but a sudden jump in speed isn't.
+  checker.check(&[OutputCommand::Brake(false)], Windmill::Forward(0), tick(1)).unwrap();
+  checker.check(&[OutputCommand::DutyCycle(1)], Windmill::Forward(3), tick(2)).unwrap();
+  assert!(checker.clone().check(&[], Windmill::Forward(100), tick(3)).is_err());
+
+  // Nor is changing direction with the brake off, or without a cool down.
+  let reverse = [OutputCommand::Direction(MotorDirection::Reverse)];
+  assert!(checker.clone().check(&reverse, Windmill::Forward(3), tick(3)).is_err());
+  assert!(checker.clone().check(&[], Windmill::Reverse(0), tick(3)).is_err());
+
+  // Or putting the brake on before the motor has stopped being driven.
+  let braked = [OutputCommand::DutyCycle(0), OutputCommand::Brake(true)];
+  let cooldown = Windmill::Cooldown(profile.brake_cooldown.full_speed);
+  assert!(checker.clone().check(&braked, cooldown, tick(3)).is_ok());
+  assert!(checker.clone().check(&[OutputCommand::Brake(true), OutputCommand::DutyCycle(0)], cooldown, tick(3)).is_err());
+
+  // Or braking from speed with a cool down only long enough for stopping from a crawl.
+  assert!(checker.clone().check(&braked, Windmill::Cooldown(profile.brake_cooldown.minimum), tick(3)).is_err());
+
+  // Or leaving a cool down early, or going without the safety relay once it's over.
+  checker.check(&braked, Windmill::Cooldown(Duration::from_secs(1)), tick(3)).unwrap();
+  assert!(checker.clone().check(&[], Windmill::Off, tick(50)).is_err());
+  assert!(checker.clone().check(&[OutputCommand::Safety(false)], Windmill::Off, tick(103)).is_err());
+  checker.check(&[], Windmill::Off, tick(103)).unwrap();
+}
@@ -1,4 +1,4 @@
-pub use ffi::{pin_mode, digital_write};
+pub use ffi::{pin_mode, digital_read, digital_write, soft_pwm_write, soft_pwm_stop};
 
 #[cxx::bridge]
 mod ffi {
@@ -11,9 +11,25 @@ mod ffi {
     #[cxx_name = "pinMode"]
     fn pin_mode(pin: i32, mode: i32);
 
+    #[cxx_name = "digitalRead"]
+    fn digital_read(pin: i32) -> i32;
+
     #[cxx_name = "digitalWrite"]
     fn digital_write(pin: i32, value: i32);
   }
+
+  unsafe extern "C++" {
+    include!("softPwm.h");
+
+    #[cxx_name = "softPwmCreate"]
+    fn soft_pwm_create(pin: i32, value: i32, range: i32) -> i32;
+
+    #[cxx_name = "softPwmWrite"]
+    fn soft_pwm_write(pin: i32, value: i32);
+
+    #[cxx_name = "softPwmStop"]
+    fn soft_pwm_stop(pin: i32);
+  }
 }
 
 /// WiringPi magic number that sets a pin to INPUT (read) mode.
@@ -39,3 +55,13 @@ pub fn init() -> Result<(), &'static str> {
 
   Ok(())
 }
+
+/// Starts WiringPi's software PWM on the given `pin`, with duty cycles ranging from `0` to `range`. Under the hood this
+/// spins up a thread that toggles the pin, so it's only as good as the scheduler is at waking it up on time.
+pub fn soft_pwm_create(pin: i32, value: i32, range: i32) -> Result<(), &'static str> {
+  if 0 != ffi::soft_pwm_create(pin, value, range) {
+    return Err("Could not start software pwm");
+  }
+
+  Ok(())
+}
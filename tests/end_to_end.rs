@@ -0,0 +1,349 @@
+//! End-to-end tests of the whole windmill: the real binary, running against simulated hardware, listening for a fake
+//! console sending Art-Net over loopback. Everything the windmill does to its (simulated) hardware is traced to stdout,
+//! and that timeline is what gets checked. Nothing here needs a network beyond loopback, or `olad`, or a windmill.
+//!
+//! That does mean the OLA bridge itself is not tested here: `--listen` stands in for it, so `ola::start`, the patching
+//! in `ensure_patches_exist` and the C++ client behind them only ever run against a real `olad`. Changes there need
+//! trying on the rig (or anywhere with `olad` running).
+
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, UdpSocket};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The universe the fake console and the windmill agree on.
+const UNIVERSE: u16 = 1;
+
+/// How often the fake console sends a frame, like a real one refreshing its output.
+const REFRESH: Duration = Duration::from_millis(25);
+
+/// One change to the simulated hardware, as traced by the windmill.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Sample {
+  /// Seconds since the simulation started.
+  at: f64,
+  duty_cycle: u8,
+  reverse: bool,
+  brake: bool,
+  powered: bool,
+  rpm: f64
+}
+
+impl Sample {
+  /// Reads a sample out of a line like `[simulated] 1.234s duty=40 forward brake=off power=on rpm=12.3`. Any other line
+  /// is `None`.
+  fn parse(line: &str) -> Option<Sample> {
+    let mut fields = line.strip_prefix("[simulated] ")?.split(' ');
+
+    let at = fields.next()?.strip_suffix('s')?.parse().ok()?;
+    let duty_cycle = fields.next()?.strip_prefix("duty=")?.parse().ok()?;
+    let reverse = fields.next()? == "reverse";
+    let brake = fields.next()? == "brake=on";
+    let powered = fields.next()? == "power=on";
+    let rpm = fields.next()?.strip_prefix("rpm=")?.parse().ok()?;
+
+    Some(Sample { at, duty_cycle, reverse, brake, powered, rpm })
+  }
+}
+
+/// A running windmill and the fake console plugged into it.
+struct Harness {
+  /// The windmill itself.
+  windmill: Child,
+
+  /// Where the fake console sends from.
+  console: UdpSocket,
+
+  /// Everything the windmill has printed, stdout and stderr alike.
+  output: Arc<Mutex<Vec<String>>>,
+
+  /// The threads reading the windmill's stdout and stderr, which finish once it exits and they've read everything.
+  readers: Vec<JoinHandle<()>>
+}
+
+impl Harness {
+  /// Starts the windmill on simulated hardware with quick ramps and cool downs (and a rotor light enough to stop within
+  /// them), listening on a free loopback port, and waits until it's ready for the console.
+  ///
+  /// Finding a free port means binding to port 0 and letting go again, so something else could grab the port before the
+  /// windmill does. If that happens, the windmill can't listen and exits, and it's tried again on another port.
+  fn start() -> Self {
+    for _ in 0..5 {
+      let address = UdpSocket::bind("127.0.0.1:0").and_then(|socket| socket.local_addr()).unwrap();
+
+      if let Some(harness) = Self::start_on(address) {
+        return harness;
+      }
+    }
+
+    panic!("the windmill couldn't find a port to listen on");
+  }
+
+  /// Starts the windmill listening on `address`, or `None` if it exited because it couldn't.
+  fn start_on(address: SocketAddr) -> Option<Self> {
+    let mut windmill = Command::new(env!("CARGO_BIN_EXE_windmill"))
+      .args(["--simulate", "--simulate-trace", "--listen", &address.to_string(), "--universe", &UNIVERSE.to_string()])
+      .args(["--speed-channel", "1", "--direction-channel", "2"])
+      .args(["--acceleration-time", "0.5", "--deceleration-time", "0.5"])
+      .args(["--brake-cooldown-minimum", "1", "--brake-cooldown-full-speed", "1"])
+      .args(["--rotor-inertia", "2", "--brake-damping", "15"])
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()
+      .unwrap();
+
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let (ready_tx, ready_rx) = mpsc::channel();
+
+    let stdout = BufReader::new(windmill.stdout.take().unwrap());
+    let stderr = BufReader::new(windmill.stderr.take().unwrap());
+    let mut readers = Vec::new();
+
+    for lines in [Box::new(stdout.lines()) as Box<dyn Iterator<Item = _> + Send>, Box::new(stderr.lines())] {
+      let output = output.clone();
+      let ready_tx = ready_tx.clone();
+
+      readers.push(thread::spawn(move || {
+        for line in lines.map_while(Result::ok) {
+          if line.starts_with("Listening for") {
+            ready_tx.send(()).ok();
+          }
+
+          output.lock().unwrap().push(line);
+        }
+      }));
+    }
+
+    let console = UdpSocket::bind("127.0.0.1:0").unwrap();
+    console.connect(address).unwrap();
+
+    let mut harness = Harness { windmill, console, output, readers };
+    let until = Instant::now() + Duration::from_secs(10);
+
+    while ready_rx.recv_timeout(Duration::from_millis(50)).is_err() {
+      if harness.windmill.try_wait().unwrap().is_some() {
+        harness.join_readers();
+        let output = harness.output.lock().unwrap();
+
+        assert!(output.iter().any(|line| line.contains("failed to listen")), "the windmill exited: {output:#?}");
+        return None;
+      }
+
+      if Instant::now() > until {
+        harness.windmill.kill().ok();
+        panic!("the windmill never started listening: {:#?}", harness.output.lock().unwrap());
+      }
+    }
+
+    Some(harness)
+  }
+
+  /// Has the console hold `speed` and `direction` for `duration`, sending a frame every `REFRESH`.
+  fn hold(&self, speed: u8, direction: u8, duration: Duration) {
+    let until = Instant::now() + duration;
+
+    while Instant::now() < until {
+      self.console.send(&art_dmx(UNIVERSE, &[speed, direction])).unwrap();
+      thread::sleep(REFRESH);
+    }
+  }
+
+  /// Every change the windmill has made to the simulated hardware so far.
+  fn samples(&self) -> Vec<Sample> {
+    self.output.lock().unwrap().iter().filter_map(|line| Sample::parse(line)).collect()
+  }
+
+  /// Whether the windmill has printed a line containing `text`.
+  fn printed(&self, text: &str) -> bool {
+    self.output.lock().unwrap().iter().any(|line| line.contains(text))
+  }
+
+  /// Sends the windmill a SIGTERM and waits for it to exit, and for everything it printed to be read.
+  fn terminate(&mut self) -> ExitStatus {
+    let pid = self.windmill.id().to_string();
+    assert!(Command::new("kill").args(["-TERM", &pid]).status().unwrap().success());
+
+    let until = Instant::now() + Duration::from_secs(10);
+
+    loop {
+      if let Some(status) = self.windmill.try_wait().unwrap() {
+        self.join_readers();
+        return status;
+      }
+
+      assert!(Instant::now() < until, "the windmill didn't exit after SIGTERM");
+      thread::sleep(Duration::from_millis(10));
+    }
+  }
+
+  /// Waits for the output to be read to the end. Only worth doing once the windmill has exited, since that's when its
+  /// stdout and stderr close.
+  fn join_readers(&mut self) {
+    for reader in self.readers.drain(..) {
+      reader.join().unwrap();
+    }
+  }
+}
+
+impl Drop for Harness {
+  fn drop(&mut self) {
+    self.windmill.kill().ok();
+    self.windmill.wait().ok();
+  }
+}
+
+/// An ArtDmx packet for `universe`, with `data` starting at channel one.
+fn art_dmx(universe: u16, data: &[u8]) -> Vec<u8> {
+  let mut packet = Vec::from(&b"Art-Net\0"[..]);
+  packet.extend_from_slice(&[0x00, 0x50, 0, 14, 0, 0]);
+  packet.extend_from_slice(&universe.to_le_bytes());
+  packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+  packet.extend_from_slice(data);
+  packet
+}
+
+const FORWARD: u8 = 0;
+const REVERSE: u8 = 255;
+
+#[test]
+fn starts_by_releasing_the_brake_then_ramping_up() {
+  let harness = Harness::start();
+  harness.hold(255, FORWARD, Duration::from_millis(1500));
+
+  let samples = harness.samples();
+  let first_driven = samples.iter().position(|sample| sample.duty_cycle > 0).expect("never drove the motor");
+
+  assert!(!samples[first_driven].brake && samples[first_driven].powered && !samples[first_driven].reverse);
+  assert!(samples.windows(2).all(|pair| pair[0].duty_cycle <= pair[1].duty_cycle), "{samples:#?}");
+
+  // The ramp goes by the clock, so however the windmill was scheduled, full speed can't come any sooner than half a
+  // second (give or take a loop) after it got going.
+  let full_speed = samples.iter().find(|sample| sample.duty_cycle == 100).expect("never got to full speed");
+  assert!(full_speed.at - samples[first_driven].at > 0.45, "jumped straight to full speed: {samples:#?}");
+
+  let last = samples.last().unwrap();
+  assert_eq!(100, last.duty_cycle);
+  assert!(last.rpm > 1.0);
+}
+
+#[test]
+fn ramps_between_speeds() {
+  let harness = Harness::start();
+  harness.hold(255, FORWARD, Duration::from_millis(1200));
+  let before = harness.samples().len();
+
+  harness.hold(128, FORWARD, Duration::from_millis(1200));
+  let samples = harness.samples();
+  let after = &samples[before..];
+
+  assert!(after.iter().any(|sample| (51..100).contains(&sample.duty_cycle)), "didn't ramp: {after:#?}");
+  assert!(after.iter().all(|sample| !sample.brake && !sample.reverse));
+  assert_eq!(50, samples.last().unwrap().duty_cycle);
+}
+
+#[test]
+fn brakes_to_a_standstill_before_reversing() {
+  let harness = Harness::start();
+  harness.hold(255, FORWARD, Duration::from_millis(1500));
+  harness.hold(255, REVERSE, Duration::from_millis(3000));
+
+  let samples = harness.samples();
+  let reversal = samples.iter().position(|sample| sample.reverse).expect("never reversed");
+
+  assert!(samples[..reversal].iter().any(|sample| sample.brake && sample.duty_cycle == 0), "{samples:#?}");
+  assert!(samples[reversal].rpm.abs() < 1.0, "reversed at {} rpm", samples[reversal].rpm);
+  assert!(!harness.printed("still turning"));
+
+  let last = samples.last().unwrap();
+  assert!(last.reverse && !last.brake && last.powered);
+  assert_eq!(100, last.duty_cycle);
+  assert!(last.rpm < -1.0);
+}
+
+#[test]
+fn holds_the_last_look_when_the_console_goes_quiet() {
+  let mut harness = Harness::start();
+  harness.hold(200, FORWARD, Duration::from_millis(1500));
+  let before = harness.samples();
+
+  thread::sleep(Duration::from_millis(1500));
+
+  assert_eq!(before, harness.samples());
+  assert_eq!(78, before.last().unwrap().duty_cycle);
+  assert!(harness.windmill.try_wait().unwrap().is_none());
+}
+
+#[test]
+fn stops_hard_on_sigterm() {
+  let mut harness = Harness::start();
+  harness.hold(255, FORWARD, Duration::from_millis(1500));
+
+  let status = harness.terminate();
+  assert!(status.success());
+
+  let samples = harness.samples();
+  let last = samples.last().unwrap();
+  assert!(last.brake && !last.powered);
+  assert_eq!(0, last.duty_cycle);
+  assert!(harness.printed("I'll get you my pretty!"));
+}
+
+/// Runs a scenario from `scenarios/` in the simulation, with `args` ahead of it, and checks every expectation passed.
+fn assert_scenario_passes(args: &[&str], scenario: &str) {
+  let scenario = format!("{}/scenarios/{scenario}", env!("CARGO_MANIFEST_DIR"));
+  let output = Command::new(env!("CARGO_BIN_EXE_windmill")).args(args).args(["scenario", &scenario]).output().unwrap();
+  let stdout = String::from_utf8_lossy(&output.stdout);
+
+  assert!(output.status.success(), "{stdout}");
+  assert!(stdout.contains("0 failed, 0 not checked."), "{stdout}");
+}
+
+#[test]
+fn the_preflight_scenario_passes_in_simulation() {
+  assert_scenario_passes(&[], "preflight.scenario");
+}
+
+/// The rig can't run this one here, but the simulation can, held to the same duty cycle cap as `--hardware` would be.
+#[test]
+fn the_hardware_preflight_scenario_fits_under_the_hardware_cap() {
+  assert_scenario_passes(&["--maximum-duty", "25"], "hardware-preflight.scenario");
+}
+
+#[test]
+fn replays_wind_down_the_ordinary_way_when_they_run_out() {
+  // A second and a half of full speed forward, as a recording made with `--record` would have it.
+  let mut recording = Vec::from(&b"WMDX\x01"[..]);
+
+  for frame in 0..60u64 {
+    recording.extend_from_slice(&(frame * REFRESH.as_micros() as u64).to_le_bytes());
+    recording.extend_from_slice(&(UNIVERSE as u32).to_le_bytes());
+    recording.extend_from_slice(&[100, 2, 0, 1, 0, 255, 2, 0, FORWARD]);
+  }
+
+  let path = std::env::temp_dir().join(format!("windmill-end-to-end-{}.wmdx", std::process::id()));
+  std::fs::write(&path, recording).unwrap();
+
+  let output = Command::new(env!("CARGO_BIN_EXE_windmill"))
+    .args(["--simulate", "--simulate-trace", "--replay", path.to_str().unwrap()])
+    .args(["--speed-channel", "1", "--direction-channel", "2", "--stop-mode", "ramp-down"])
+    .args(["--acceleration-time", "0.5", "--deceleration-time", "0.5"])
+    .args(["--brake-cooldown-minimum", "1", "--brake-cooldown-full-speed", "1"])
+    .output()
+    .unwrap();
+
+  std::fs::remove_file(&path).ok();
+
+  let stdout = String::from_utf8_lossy(&output.stdout);
+  let samples = stdout.lines().filter_map(Sample::parse).collect::<Vec<_>>();
+  let full_speed = samples.iter().position(|sample| sample.duty_cycle == 100).expect("never got to full speed");
+  let braked = samples.iter().position(|sample| sample.brake && sample.at > 0.0).expect("never braked");
+
+  assert!(output.status.success(), "{stdout}");
+  assert!(stdout.contains("Wound down."), "{stdout}");
+
+  // Running out of frames ramps down before braking, rather than hard braking at full duty.
+  assert!(samples[full_speed..braked].iter().any(|sample| (1..100).contains(&sample.duty_cycle)), "{samples:#?}");
+  assert_eq!(0, samples[braked].duty_cycle);
+}